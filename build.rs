@@ -0,0 +1,57 @@
+// Builds the SQLite the library runs on from the amalgamation in `sqlite3/` (3.33.0, the version
+// rusqlite's `modern_sqlite` bindings are written against). It's built here, rather than through
+// rusqlite's `bundled` feature, so the options the VFS and the `session` feature depend on come
+// with the crate instead of having to be set in the environment of whatever builds it.
+use std::env;
+
+/// What rusqlite's `bundled` feature builds SQLite with.
+const DEFAULTS: &[&str] = &[
+    "SQLITE_CORE",
+    "SQLITE_DEFAULT_FOREIGN_KEYS=1",
+    "SQLITE_ENABLE_API_ARMOR",
+    "SQLITE_ENABLE_COLUMN_METADATA",
+    "SQLITE_ENABLE_DBSTAT_VTAB",
+    "SQLITE_ENABLE_FTS3",
+    "SQLITE_ENABLE_FTS3_PARENTHESIS",
+    "SQLITE_ENABLE_FTS5",
+    "SQLITE_ENABLE_JSON1",
+    "SQLITE_ENABLE_LOAD_EXTENSION=1",
+    "SQLITE_ENABLE_MEMORY_MANAGEMENT",
+    "SQLITE_ENABLE_RTREE",
+    "SQLITE_ENABLE_STAT2",
+    "SQLITE_ENABLE_STAT4",
+    "SQLITE_SOUNDEX",
+    "SQLITE_THREADSAFE=1",
+    "SQLITE_USE_URI",
+    "HAVE_USLEEP=1",
+    "HAVE_ISNAN",
+    "_POSIX_THREAD_SAFE_FUNCTIONS",
+];
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=sqlite3/sqlite3.c");
+
+    let mut build = cc::Build::new();
+    build.file("sqlite3/sqlite3.c").warnings(false);
+    for define in DEFAULTS {
+        let mut define = define.splitn(2, '=');
+        build.define(define.next().unwrap(), define.next());
+    }
+    // Lets SQLite write a transaction to the Hypercore VFS as one batch instead of journaling it
+    // (see `SQLITE_IOCAP_BATCH_ATOMIC`).
+    build.define("SQLITE_ENABLE_BATCH_ATOMIC_WRITE", None);
+    // The session extension `session` records and applies changesets with, and the
+    // serialization that databases kept as changesets are materialized in memory with.
+    if env::var_os("CARGO_FEATURE_SESSION").is_some() {
+        build.define("SQLITE_ENABLE_SESSION", None);
+        build.define("SQLITE_ENABLE_PREUPDATE_HOOK", None);
+        build.define("SQLITE_ENABLE_DESERIALIZE", None);
+    }
+    if env::var("CARGO_CFG_TARGET_OS").map_or(true, |os| os != "windows") {
+        build.define("HAVE_LOCALTIME_R", None);
+    }
+    // Named after the library libsqlite3-sys links, so that it's found here too when the system
+    // doesn't have one; it's linked into this crate first either way.
+    build.compile("sqlite3");
+}
@@ -0,0 +1,102 @@
+// The entry point SQLite looks for when this library is loaded as a run-time extension, with
+// `.load` in the shell or `load_extension()` in SQL.
+//
+// FIXME: rusqlite doesn't route calls through `sqlite3_api_routines` yet, so the `hyper_log` table
+// and `hyper_*()` functions (which are added through it) are only there when the SQLite bundled
+// into this library loads it. Any other SQLite gets the VFS and its pragmas, which only need the
+// few functions `vfs::api` routes.
+use crate::vfs::{api, control};
+use crate::{register_functions, register_log_table, Instance, Vfs, VfsOptions};
+use rusqlite::ffi as sqlite3;
+use rusqlite::Connection;
+use std::cell::RefCell;
+use std::os::raw;
+use std::rc::Rc;
+
+/// The name the VFS is registered under when loaded as an extension.
+pub const VFS_NAME: &str = "hypercore";
+
+/// Registers the VFS with the SQLite that handed over `api`, and the SQL that goes with it if
+/// that's the bundled one.
+unsafe fn register(api: *const sqlite3::sqlite3_api_routines) -> anyhow::Result<()> {
+    api::route_through(api)?;
+    let hyper_vfs = Rc::new(RefCell::new(Vfs::connect(VfsOptions::default())?));
+    let inst = Instance::new(VFS_NAME, hyper_vfs)?;
+    Instance::register(inst, false)?;
+    if !is_bundled(api) {
+        return Ok(());
+    }
+
+    // SAFETY: SQLite calls entry points with the signature of `connect`, whatever it's given as.
+    let entry_point = std::mem::transmute::<
+        unsafe extern "C" fn(
+            *mut sqlite3::sqlite3,
+            *mut *mut raw::c_char,
+            *const sqlite3::sqlite3_api_routines,
+        ) -> raw::c_int,
+        unsafe extern "C" fn(),
+    >(connect);
+    match sqlite3::sqlite3_auto_extension(Some(entry_point)) {
+        sqlite3::SQLITE_OK => Ok(()),
+        code => Err(anyhow::Error::new(sqlite3::Error::new(code))),
+    }
+}
+
+/// Whether `api` is the table of the SQLite bundled into this library (or missing, as it is when
+/// the library's linked in), the only one rusqlite can add SQL to for now.
+unsafe fn is_bundled(api: *const sqlite3::sqlite3_api_routines) -> bool {
+    // Every SQLite fills in the table with its own functions, starting with
+    // `sqlite3_aggregate_context`.
+    api.is_null() || *(api as *const *const ()) == sqlite3::sqlite3_aggregate_context as *const ()
+}
+
+/// Adds the `hyper_log` table and the `hyper_*()` functions to every connection opened after the
+/// extension is loaded.
+unsafe extern "C" fn connect(
+    db: *mut sqlite3::sqlite3,
+    _error_message: *mut *mut raw::c_char,
+    _api: *const sqlite3::sqlite3_api_routines,
+) -> raw::c_int {
+    let registered = Connection::from_handle(db)
+        .map_err(anyhow::Error::new)
+        .and_then(|conn| {
+            register_log_table(&conn)?;
+            register_functions(&conn)
+        });
+    match registered {
+        Ok(()) => sqlite3::SQLITE_OK,
+        Err(error) => {
+            log::error!("Could not add the Hypercore SQL to a connection: {}", error);
+            sqlite3::SQLITE_ERROR
+        }
+    }
+}
+
+/// Registers the Hypercore VFS (with its default options) as `hypercore`. When it's loaded by the
+/// SQLite bundled into this library, the `hyper_log` table and `hyper_*()` functions are added to
+/// connections opened from then on too.
+///
+/// # Safety
+///
+/// This is only meant to be called by SQLite when loading the extension.
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_sqlitehypercore_init(
+    _db: *mut sqlite3::sqlite3,
+    error_message: *mut *mut raw::c_char,
+    api: *const sqlite3::sqlite3_api_routines,
+) -> raw::c_int {
+    match register(api) {
+        // The VFS has to outlive every connection using it, so the library can't be unloaded.
+        Ok(()) => sqlite3::SQLITE_OK_LOAD_PERMANENTLY,
+        Err(error) => {
+            let message = format!("Could not load the Hypercore VFS: {:#}", error);
+            log::error!("{}", message);
+            if !error_message.is_null() {
+                if let Ok(message) = control::allocate(&message) {
+                    *error_message = message;
+                }
+            }
+            sqlite3::SQLITE_ERROR
+        }
+    }
+}
@@ -5,3 +5,536 @@
 // primitives (like locking and the like - if any).
 //
 // [1]: https://github.com/RangerMauve/co-hyperdrive
+use durability::FeedReplica;
+use futures::lock::Mutex;
+use hypercore::{Hypercore, HypercoreBuilder, PartialKeypair, RequestBlock, RequestUpgrade};
+use peer::{FeedPeer, Replicator};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+mod block;
+#[cfg(feature = "session")]
+pub(crate) mod changeset;
+#[cfg(feature = "encryption")]
+mod cipher;
+mod codec;
+mod durability;
+#[cfg(feature = "session")]
+mod multiwriter;
+mod peer;
+mod runtime;
+#[cfg(feature = "session")]
+mod session;
+
+pub use block::{Block, BlockKind};
+#[cfg(feature = "session")]
+pub use changeset::{ChangesetFeed, ConflictHandler, LastWriterWins};
+#[cfg(feature = "encryption")]
+pub use cipher::{Cipher, EncryptionKey, KEY_LENGTH};
+pub use codec::{Compression, CompressionStats};
+pub use durability::{Durability, Replica};
+#[cfg(feature = "session")]
+pub use multiwriter::MultiWriter;
+pub use peer::{Peer, Replication};
+pub use runtime::{Runtime, Timeouts};
+#[cfg(feature = "session")]
+pub use session::{ChangesetItem, ConflictAction, ConflictType};
+
+/// Where the Hypercores backing each file are kept.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Storage {
+    /// Every Hypercore lives in memory and goes away with the VFS.
+    #[cfg(feature = "memory")]
+    InMemory,
+    /// Every file gets a directory of Hypercore data under this one.
+    #[cfg(feature = "disk")]
+    Disk(PathBuf),
+}
+
+impl Default for Storage {
+    /// Keeps files in memory or, when the `memory` feature is disabled, in a directory under the
+    /// system's temporary one that's only used by this process. Either way they don't outlast
+    /// it for long; files meant to stay have to be given a `Storage::Disk` of their own.
+    fn default() -> Self {
+        #[cfg(feature = "memory")]
+        return Self::InMemory;
+        #[cfg(not(feature = "memory"))]
+        return Self::Disk(
+            std::env::temp_dir().join(format!("sqlite-hypercore-{}", std::process::id())),
+        );
+    }
+}
+
+impl Storage {
+    /// The directory holding the Hypercore for `name`, if it's kept on disk.
+    #[cfg_attr(not(feature = "disk"), allow(unused_variables))]
+    pub fn directory_of(&self, name: &str) -> Option<PathBuf> {
+        match self {
+            #[cfg(feature = "memory")]
+            Self::InMemory => None,
+            #[cfg(feature = "disk")]
+            Self::Disk(root) => Some(root.join(directory_name(name))),
+        }
+    }
+
+    /// Where a plain file for `name` is kept in place of a Hypercore, if it's on disk.
+    pub fn file_of(&self, name: &str) -> Option<PathBuf> {
+        self.directory_of(name)
+    }
+}
+
+/// Turns a file name (which can be a `hyper://` URL) into something safe to use as a directory.
+#[cfg(feature = "disk")]
+fn directory_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Encodes bytes as lowercase hexadecimal digits, like the keys in `hyper://` URLs.
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Decodes a string of hexadecimal digits, like the ones used for keys in `hyper://` URLs.
+pub fn decode_hex(value: &str) -> anyhow::Result<Vec<u8>> {
+    if !value.len().is_multiple_of(2) {
+        return Err(anyhow::anyhow!("{:?} has an odd number of digits", value));
+    }
+
+    (0..value.len())
+        .step_by(2)
+        .map(|index| {
+            value
+                .get(index..index + 2)
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or_else(|| anyhow::anyhow!("{:?} is not hexadecimal", value))
+        })
+        .collect()
+}
+
+/// How many blocks the verifier of a feed checks before it's rebuilt, since Hypercore keeps a copy
+/// of every block it's been proven.
+const VERIFIED_BLOCKS: u64 = 64;
+
+/// The Hypercore a feed checks proofs with, along with the number of blocks it holds.
+struct Verifier {
+    core: Hypercore,
+    blocks: u64,
+}
+
+/// An append-only log of blocks; a blocking wrapper over the `Hypercore` of a single file.
+pub struct Feed {
+    core: Arc<Mutex<Hypercore>>,
+    runtime: Runtime,
+    directory: Option<PathBuf>,
+    /// The number of blocks in the Hypercore. It's set by the operations changing it as they
+    /// finish, so ones that outlast the wait on them (see `Timeouts::io`) aren't missed.
+    length: Arc<AtomicU64>,
+    key: [u8; 32],
+    writable: bool,
+    /// A Hypercore that only knows the feed's public key, like a peer would; the tree and blocks
+    /// are checked by replaying proofs of them into it. It's built on first use, and rebuilt once
+    /// it's kept a copy of `VERIFIED_BLOCKS` of the blocks it checked.
+    verifier: Arc<Mutex<Option<Verifier>>>,
+    /// Fetches what's missing from peers, once `replicate` is called.
+    replicator: Option<Arc<Replicator>>,
+}
+
+impl Feed {
+    /// Opens the Hypercore for `name`, creating it if it doesn't exist yet.
+    pub fn open(runtime: &Runtime, storage: &Storage, name: &str) -> anyhow::Result<Self> {
+        Self::open_with(runtime, storage, name, None)
+    }
+
+    /// Opens the Hypercore for `name` as a copy of the feed with the public key `key`, which can
+    /// only get blocks through `replicate`.
+    pub fn open_replica(
+        runtime: &Runtime,
+        storage: &Storage,
+        name: &str,
+        key: [u8; 32],
+    ) -> anyhow::Result<Self> {
+        let feed = Self::open_with(runtime, storage, name, Some(key))?;
+        if feed.key != key {
+            return Err(anyhow::anyhow!(
+                "{:?} already holds the feed {}",
+                name,
+                encode_hex(&feed.key)
+            ));
+        }
+        Ok(feed)
+    }
+
+    fn open_with(
+        runtime: &Runtime,
+        storage: &Storage,
+        name: &str,
+        key: Option<[u8; 32]>,
+    ) -> anyhow::Result<Self> {
+        let directory = storage.directory_of(name);
+        let core = Self::build(runtime, directory.clone(), key)?;
+
+        Ok(Self {
+            length: Arc::new(AtomicU64::new(core.info().length)),
+            key: core.key_pair().public.to_bytes(),
+            writable: core.info().writeable,
+            core: Arc::new(Mutex::new(core)),
+            runtime: runtime.clone(),
+            directory,
+            verifier: Arc::default(),
+            replicator: None,
+        })
+    }
+
+    fn build(
+        runtime: &Runtime,
+        directory: Option<PathBuf>,
+        key: Option<[u8; 32]>,
+    ) -> anyhow::Result<Hypercore> {
+        let key_pair = match key {
+            Some(key) => Some(PartialKeypair {
+                public: hypercore::VerifyingKey::from_bytes(&key)?,
+                secret: None,
+            }),
+            None => None,
+        };
+
+        runtime.run(async {}, |()| async move {
+            let core = match directory {
+                None => {
+                    let storage = hypercore::Storage::new_memory().await?;
+                    let builder = HypercoreBuilder::new(storage);
+                    match key_pair {
+                        Some(key_pair) => builder.key_pair(key_pair),
+                        None => builder,
+                    }
+                    .build()
+                    .await
+                }
+                Some(directory) => {
+                    let exists = directory.join("oplog").exists();
+                    std::fs::create_dir_all(&directory)?;
+                    let storage = hypercore::Storage::new_disk(&directory, false).await?;
+                    let builder = HypercoreBuilder::new(storage).open(exists);
+                    match key_pair {
+                        Some(key_pair) if !exists => builder.key_pair(key_pair),
+                        _ => builder,
+                    }
+                    .build()
+                    .await
+                }
+            }?;
+            Ok(core)
+        })
+    }
+
+    /// Reopens a Hypercore kept on disk to pick up what other processes appended to it, and
+    /// catches up with the feed's peers.
+    pub fn reload(&mut self) -> anyhow::Result<()> {
+        if self.directory.is_some() {
+            // Replicas have nothing to sign with, so they're recreated with the key they follow.
+            let key = Some(self.key).filter(|_| !self.writable);
+            let core = Self::build(&self.runtime, self.directory.clone(), key)?;
+            self.key = core.key_pair().public.to_bytes();
+            self.writable = core.info().writeable;
+            // It's swapped in place so peers serving the feed see what's been reloaded.
+            self.runtime.run(
+                Arc::clone(&self.core).lock_owned(),
+                move |mut current| async move {
+                    *current = core;
+                    Ok(())
+                },
+            )?;
+        }
+        self.update()
+    }
+
+    /// Fetches blocks of the feed from `peers` from now on, as `replication` says, waiting up to
+    /// `timeout` on each request; it starts by catching up with them.
+    pub fn replicate(
+        &mut self,
+        peers: Vec<Arc<dyn Peer>>,
+        replication: Replication,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<()> {
+        self.replicator = Some(Arc::new(Replicator {
+            key: self.key,
+            peers,
+            replication,
+            timeout,
+        }));
+        self.update()
+    }
+
+    /// How many peers blocks are fetched from.
+    pub fn peers(&self) -> usize {
+        self.replicator
+            .as_ref()
+            .map_or(0, |replicator| replicator.peers.len())
+    }
+
+    /// Something for other feeds to fetch this one's blocks from.
+    pub fn peer(&self) -> Arc<dyn Peer> {
+        Arc::new(FeedPeer {
+            key: self.key,
+            core: Arc::clone(&self.core),
+        })
+    }
+
+    /// Something for the feed this one replicates to wait on, if it does replicate one.
+    pub fn replica(&self) -> Option<Arc<dyn Replica>> {
+        let replicator = Arc::clone(self.replicator.as_ref()?);
+        Some(Arc::new(FeedReplica {
+            core: Arc::clone(&self.core),
+            replicator,
+        }))
+    }
+
+    /// Makes sure what's been appended so far is on the local disk, if that's where the
+    /// Hypercore is kept.
+    pub fn flush(&self) -> anyhow::Result<()> {
+        let directory = match &self.directory {
+            Some(directory) => directory,
+            None => return Ok(()),
+        };
+        // Everything's written by the time the Hypercore returns from an append, so nothing's
+        // held up by syncing outside of it.
+        for name in &["oplog", "tree", "bitfield", "data"] {
+            let path = directory.join(name);
+            if path.exists() {
+                std::fs::File::open(path)?.sync_all()?;
+            }
+        }
+        #[cfg(unix)]
+        std::fs::File::open(directory)?.sync_all()?;
+        Ok(())
+    }
+
+    /// Waits until `quorum` of `replicas` hold every block of the feed, giving each of them up to
+    /// `timeout`.
+    pub fn acknowledge(
+        &self,
+        replicas: Vec<Arc<dyn Replica>>,
+        quorum: usize,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<()> {
+        let (key, length) = (self.key, self.len());
+        self.runtime.run(async {}, move |()| {
+            durability::acknowledged(replicas, key, length, quorum, timeout)
+        })
+    }
+
+    fn update(&mut self) -> anyhow::Result<()> {
+        let replicator = self.replicator.clone();
+        let length = Arc::clone(&self.length);
+        self.runtime.run(
+            Arc::clone(&self.core).lock_owned(),
+            move |mut core| async move {
+                if let Some(replicator) = replicator {
+                    replicator.update(&mut core).await?;
+                }
+                length.store(core.info().length, Ordering::SeqCst);
+                Ok(())
+            },
+        )
+    }
+
+    /// Checks if there's a Hypercore for `name` in the provided storage.
+    pub fn exists(storage: &Storage, name: &str) -> bool {
+        storage
+            .directory_of(name)
+            .map(|directory| directory.join("oplog").exists())
+            .unwrap_or(false)
+    }
+
+    /// Removes the data of the Hypercore for `name` from the provided storage.
+    pub fn remove(storage: &Storage, name: &str) -> anyhow::Result<()> {
+        match storage.directory_of(name) {
+            Some(directory) if directory.exists() => Ok(std::fs::remove_dir_all(directory)?),
+            _ => Ok(()),
+        }
+    }
+
+    /// The number of blocks in this feed.
+    pub fn len(&self) -> u64 {
+        self.length.load(Ordering::SeqCst)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The public key identifying this feed.
+    pub fn key(&self) -> [u8; 32] {
+        self.key
+    }
+
+    /// Whether blocks can be appended, which takes the feed's secret key.
+    pub fn is_writable(&self) -> bool {
+        self.writable
+    }
+
+    /// Appends a block to the feed and returns its sequence number.
+    pub fn append(&mut self, data: &[u8]) -> anyhow::Result<u64> {
+        let data = data.to_vec();
+        let length = Arc::clone(&self.length);
+        let outcome =
+            self.runtime
+                .run(Arc::clone(&self.core).lock_owned(), |mut core| async move {
+                    let outcome = core.append(&data).await?;
+                    length.store(outcome.length, Ordering::SeqCst);
+                    Ok(outcome)
+                })?;
+        Ok(outcome.length - 1)
+    }
+
+    /// Appends several blocks in one go and returns the sequence number of the first.
+    pub fn append_batch(&mut self, data: &[Vec<u8>]) -> anyhow::Result<u64> {
+        let data = data.to_vec();
+        let count = data.len() as u64;
+        let length = Arc::clone(&self.length);
+        let outcome =
+            self.runtime
+                .run(Arc::clone(&self.core).lock_owned(), |mut core| async move {
+                    let outcome = core.append_batch(&data).await?;
+                    length.store(outcome.length, Ordering::SeqCst);
+                    Ok(outcome)
+                })?;
+        Ok(outcome.length - count)
+    }
+
+    /// Frees the local copies of the blocks from `start` up to (but not including) `end`; they
+    /// read as missing afterwards.
+    pub fn clear(&mut self, start: u64, end: u64) -> anyhow::Result<()> {
+        self.runtime.run(
+            Arc::clone(&self.core).lock_owned(),
+            move |mut core| async move { Ok(core.clear(start, end).await?) },
+        )
+    }
+
+    /// Checks the block at `seq`, as it's stored locally, against the feed's signed Merkle tree.
+    ///
+    /// Returns `None` if the block isn't stored locally.
+    pub fn verify(&mut self, seq: u64) -> anyhow::Result<Option<bool>> {
+        Ok(self.prove(Some(seq))?.map(|(_, verified)| verified))
+    }
+
+    /// Checks the roots of the feed's Merkle tree against the signature over them.
+    pub fn verify_tree(&mut self) -> anyhow::Result<bool> {
+        self.verifier = Arc::default();
+        Ok(self.prove(None)?.is_none_or(|(_, verified)| verified))
+    }
+
+    /// Fetches the block at `seq` like `get` (from peers, if it isn't stored locally), along with
+    /// whether it checks out against the feed's signed Merkle tree; the bytes can't be relied on
+    /// (and may be missing) when it doesn't.
+    pub fn get_verified(&mut self, seq: u64) -> anyhow::Result<Option<(Vec<u8>, bool)>> {
+        if let Some(replicator) = self.replicator.clone() {
+            let found = self.runtime.run(
+                Arc::clone(&self.core).lock_owned(),
+                move |mut core| async move { replicator.fetch_around(&mut core, seq).await },
+            )?;
+            if !found {
+                return Ok(None);
+            }
+        }
+
+        Ok(self
+            .prove(Some(seq))?
+            .map(|(value, verified)| (value.unwrap_or_default(), verified)))
+    }
+
+    /// Replays proofs of the tree grown since the last check and of the block at `seq` (if any)
+    /// into the verifier, like a peer receiving them would.
+    ///
+    /// Returns what was proven along with whether it checked out, or `None` if the block isn't
+    /// stored locally.
+    fn prove(&mut self, seq: Option<u64>) -> anyhow::Result<Option<(Option<Vec<u8>>, bool)>> {
+        let key_pair = PartialKeypair {
+            public: hypercore::VerifyingKey::from_bytes(&self.key)?,
+            secret: None,
+        };
+        let length = self.len();
+        let core = Arc::clone(&self.core);
+        let verifier = Arc::clone(&self.verifier);
+
+        self.runtime.run(
+            async move { (core.lock_owned().await, verifier.lock_owned().await) },
+            move |(mut core, mut verifier)| async move {
+                let checker = match verifier.as_mut() {
+                    Some(checker) => checker,
+                    None => {
+                        let storage = hypercore::Storage::new_memory().await?;
+                        let core = HypercoreBuilder::new(storage)
+                            .key_pair(key_pair)
+                            .build()
+                            .await?;
+                        verifier.insert(Verifier { core, blocks: 0 })
+                    }
+                };
+
+                // NOTE: Hypercore panics when applying a proof of a block along with a proof of
+                // the tree it's in, unless the block falls under the tree's first root, so the two
+                // are proven separately.
+                let known = checker.core.info().length;
+                if length > known {
+                    let upgrade = RequestUpgrade {
+                        start: known,
+                        length: length - known,
+                    };
+                    let proof = core.create_proof(None, None, None, Some(upgrade)).await?;
+                    let verified = match proof {
+                        Some(proof) => {
+                            matches!(checker.core.verify_and_apply_proof(&proof).await, Ok(true))
+                        }
+                        None => false,
+                    };
+                    if !verified {
+                        *verifier = None;
+                        return Ok(Some((None, false)));
+                    }
+                }
+
+                let index = match seq {
+                    Some(index) => index,
+                    None => return Ok(Some((None, true))),
+                };
+                if !core.has(index) {
+                    return Ok(None);
+                }
+                let nodes = checker.core.missing_nodes(index).await?;
+                let proof = match core
+                    .create_proof(Some(RequestBlock { index, nodes }), None, None, None)
+                    .await?
+                {
+                    Some(proof) => proof,
+                    None => return Ok(None),
+                };
+                let result = checker.core.verify_and_apply_proof(&proof).await;
+                let verified = matches!(result, Ok(true));
+                checker.blocks += 1;
+                if checker.blocks >= VERIFIED_BLOCKS {
+                    *verifier = None;
+                }
+                Ok(Some((proof.block.map(|block| block.value), verified)))
+            },
+        )
+    }
+
+    /// Fetches the block at `seq`, if it's been stored locally.
+    pub fn get(&mut self, seq: u64) -> anyhow::Result<Option<Vec<u8>>> {
+        self.runtime.run(
+            Arc::clone(&self.core).lock_owned(),
+            move |mut core| async move { Ok(core.get(seq).await?) },
+        )
+    }
+}
+
+#[cfg(test)]
+mod test;
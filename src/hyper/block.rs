@@ -0,0 +1,156 @@
+use std::convert::TryInto;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The length of the fixed header that prefixes every block.
+pub const HEADER_LENGTH: usize = 27;
+
+/// Set in `Block::flags` when the payload has been sealed by a `Cipher`.
+pub const FLAG_ENCRYPTED: u8 = 0b0000_0001;
+
+/// What a block appended to a file's Hypercore represents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockKind {
+    /// The contents of a single page of the file.
+    Page = 1,
+    /// The file was truncated to `Block::file_size` bytes.
+    Truncate = 2,
+    /// A SQLite changeset recorded from a transaction, along with what it was made on top of.
+    #[cfg(feature = "session")]
+    Changeset = 3,
+    /// What a committed transaction touched, recorded after its pages; see `vfs::hyper::Audit`.
+    Transaction = 4,
+    /// Where the latest block of each page is among the blocks before it, for replicas that
+    /// haven't fetched them.
+    Index = 5,
+}
+
+impl BlockKind {
+    /// How the kind is spelled in SQL, like in the `hyper_log` table.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Page => "page",
+            Self::Truncate => "truncate",
+            #[cfg(feature = "session")]
+            Self::Changeset => "changeset",
+            Self::Transaction => "transaction",
+            Self::Index => "index",
+        }
+    }
+
+    fn from_byte(byte: u8) -> anyhow::Result<Self> {
+        match byte {
+            1 => Ok(Self::Page),
+            2 => Ok(Self::Truncate),
+            #[cfg(feature = "session")]
+            3 => Ok(Self::Changeset),
+            4 => Ok(Self::Transaction),
+            5 => Ok(Self::Index),
+            other => Err(anyhow::anyhow!("Unknown block kind {}", other)),
+        }
+    }
+}
+
+/// A single entry in the Hypercore backing a file.
+///
+/// Every block records the size of the file after it was applied, so the latest block alone is
+/// enough to know how long the file is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub kind: BlockKind,
+    pub flags: u8,
+    /// Identifies how the payload was compressed; see `Compression::codec`.
+    pub codec: u8,
+    pub page_no: u64,
+    pub file_size: u64,
+    /// Milliseconds since the UNIX epoch at which the block was created.
+    pub timestamp: u64,
+    pub payload: Vec<u8>,
+}
+
+impl Block {
+    pub fn page(page_no: u64, file_size: u64, payload: Vec<u8>) -> Self {
+        Self::new(BlockKind::Page, page_no, file_size, payload)
+    }
+
+    pub fn truncate(file_size: u64) -> Self {
+        Self::new(BlockKind::Truncate, 0, file_size, Vec::default())
+    }
+
+    #[cfg(feature = "session")]
+    pub fn changeset(payload: Vec<u8>) -> Self {
+        Self::new(BlockKind::Changeset, 0, 0, payload)
+    }
+
+    pub fn transaction(file_size: u64, payload: Vec<u8>) -> Self {
+        Self::new(BlockKind::Transaction, 0, file_size, payload)
+    }
+
+    pub fn index(file_size: u64, payload: Vec<u8>) -> Self {
+        Self::new(BlockKind::Index, 0, file_size, payload)
+    }
+
+    fn new(kind: BlockKind, page_no: u64, file_size: u64, payload: Vec<u8>) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or_default();
+
+        Self {
+            kind,
+            flags: 0,
+            codec: 0,
+            page_no,
+            file_size,
+            timestamp,
+            payload,
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.flags & FLAG_ENCRYPTED != 0
+    }
+
+    /// The encoded header of this block; it's also what gets authenticated alongside encrypted
+    /// payloads.
+    pub fn header(&self) -> [u8; HEADER_LENGTH] {
+        let mut header = [0; HEADER_LENGTH];
+        header[0] = self.kind as u8;
+        header[1] = self.flags;
+        header[2] = self.codec;
+        header[3..11].copy_from_slice(&self.page_no.to_le_bytes());
+        header[11..19].copy_from_slice(&self.file_size.to_le_bytes());
+        header[19..27].copy_from_slice(&self.timestamp.to_le_bytes());
+        header
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LENGTH + self.payload.len());
+        bytes.extend_from_slice(&self.header());
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> anyhow::Result<Self> {
+        if bytes.len() < HEADER_LENGTH {
+            return Err(anyhow::anyhow!(
+                "A block needs at least {} bytes; got {}",
+                HEADER_LENGTH,
+                bytes.len()
+            ));
+        }
+
+        let read_u64 = |range: std::ops::Range<usize>| {
+            u64::from_le_bytes(bytes[range].try_into().expect("eight bytes"))
+        };
+
+        Ok(Self {
+            kind: BlockKind::from_byte(bytes[0])?,
+            flags: bytes[1],
+            codec: bytes[2],
+            page_no: read_u64(3..11),
+            file_size: read_u64(11..19),
+            timestamp: read_u64(19..27),
+            payload: bytes[HEADER_LENGTH..].to_vec(),
+        })
+    }
+}
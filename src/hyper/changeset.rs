@@ -0,0 +1,274 @@
+// Helpers over SQLite's session extension (https://sqlite.org/sessionintro.html) for recording
+// what a transaction changed and applying it elsewhere. Changesets only carry row changes of
+// tables with a PRIMARY KEY; schema changes have to be made on every database by other means.
+use super::session::{self, ChangesetItem, ConflictAction, ConflictType, Session};
+use super::{Block, BlockKind, Feed, Peer, Replication, Runtime, Storage, Timeouts};
+use rusqlite::Connection;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::sync::Arc;
+
+/// Decides what happens when a changeset being applied doesn't fit the database.
+///
+/// See https://sqlite.org/session/sqlite3changeset_apply.html for what each conflict means and
+/// which actions are allowed for it.
+pub trait ConflictHandler: Send + Sync + 'static {
+    fn on_conflict(&self, conflict: ConflictType, item: ChangesetItem) -> ConflictAction;
+}
+
+/// Lets the change applied last win: conflicting rows are replaced and changes to rows that are
+/// gone (or that break constraints) are dropped.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LastWriterWins;
+
+impl ConflictHandler for LastWriterWins {
+    fn on_conflict(&self, conflict: ConflictType, _item: ChangesetItem) -> ConflictAction {
+        match conflict {
+            ConflictType::Data | ConflictType::Conflict => ConflictAction::Replace,
+            _ => ConflictAction::Omit,
+        }
+    }
+}
+
+/// A changeset as it's stored in a feed.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Entry {
+    /// How many entries of each writer had been applied when this one was recorded.
+    pub clock: BTreeMap<String, u64>,
+    pub changeset: Vec<u8>,
+}
+
+impl Entry {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(self.clock.len() as u16).to_le_bytes());
+        for (writer, length) in &self.clock {
+            bytes.extend_from_slice(&(writer.len() as u16).to_le_bytes());
+            bytes.extend_from_slice(writer.as_bytes());
+            bytes.extend_from_slice(&length.to_le_bytes());
+        }
+        bytes.extend_from_slice(&self.changeset);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> anyhow::Result<Self> {
+        let mut rest = bytes;
+        let read_u16 = |bytes: &[u8]| u16::from_le_bytes(bytes.try_into().expect("two bytes"));
+
+        let mut clock = BTreeMap::new();
+        for _ in 0..read_u16(take(&mut rest, 2)?) {
+            let name_length = read_u16(take(&mut rest, 2)?) as usize;
+            let writer = String::from_utf8(take(&mut rest, name_length)?.to_vec())?;
+            let length = u64::from_le_bytes(take(&mut rest, 8)?.try_into().expect("eight bytes"));
+            clock.insert(writer, length);
+        }
+
+        Ok(Self {
+            clock,
+            changeset: rest.to_vec(),
+        })
+    }
+}
+
+fn take<'a>(rest: &mut &'a [u8], length: usize) -> anyhow::Result<&'a [u8]> {
+    if rest.len() < length {
+        return Err(anyhow::anyhow!("The changeset entry is truncated"));
+    }
+    let (taken, remaining) = rest.split_at(length);
+    *rest = remaining;
+    Ok(taken)
+}
+
+/// A feed storing the changesets of committed transactions, rather than the pages of a database.
+///
+/// It's recorded from and replayed into any connection; databases opened through the `Vfs` are
+/// kept as changesets with `StorageMode::Changesets` instead.
+///
+/// Each block holds what a single transaction changed, which is usually much smaller than the
+/// pages it touched and can be read back with SQLite's `sqlite3changeset_start()`.
+pub struct ChangesetFeed {
+    feed: Feed,
+}
+
+impl ChangesetFeed {
+    /// Opens the feed for `name`, creating it if it doesn't exist yet.
+    pub fn open(storage: &Storage, name: &str) -> anyhow::Result<Self> {
+        let runtime = Runtime::start(Timeouts::default())?;
+        Ok(Self::new(Feed::open(&runtime, storage, name)?))
+    }
+
+    pub(super) fn new(feed: Feed) -> Self {
+        Self { feed }
+    }
+
+    /// The number of transactions in this feed.
+    pub fn len(&self) -> u64 {
+        self.feed.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.feed.is_empty()
+    }
+
+    /// The public key identifying this feed.
+    pub fn key(&self) -> [u8; 32] {
+        self.feed.key()
+    }
+
+    /// The changeset of the transaction at `seq`.
+    pub fn changeset(&mut self, seq: u64) -> anyhow::Result<Vec<u8>> {
+        Ok(self.entry(seq)?.changeset)
+    }
+
+    /// Runs `change` against `conn` in a transaction and appends what it changed to the feed once
+    /// it's committed; the transaction is undone if the append fails. `conn` can't be in a
+    /// transaction already.
+    pub fn record<T>(
+        &mut self,
+        conn: &Connection,
+        change: impl FnOnce(&Connection) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        record(conn, change, |changeset| {
+            self.append(&Entry {
+                clock: BTreeMap::default(),
+                changeset,
+            })
+            .map(|_| ())
+        })
+    }
+
+    /// Applies every transaction in the feed to `conn`, which needs to have the same tables as
+    /// the database they were recorded from and none of their rows.
+    pub fn replay_into(&mut self, conn: &Connection) -> anyhow::Result<()> {
+        replay(
+            conn,
+            (0..self.len()).map(|seq| Ok(self.entry(seq)?.changeset)),
+        )
+    }
+
+    pub(super) fn reload(&mut self) -> anyhow::Result<()> {
+        self.feed.reload()
+    }
+
+    pub(super) fn replicate(&mut self, peer: Arc<dyn Peer>) -> anyhow::Result<()> {
+        self.feed
+            .replicate(vec![peer], Replication::Full, Timeouts::default().peer)
+    }
+
+    pub(super) fn peer(&self) -> Arc<dyn Peer> {
+        self.feed.peer()
+    }
+
+    pub(super) fn append(&mut self, entry: &Entry) -> anyhow::Result<u64> {
+        self.feed.append(&Block::changeset(entry.encode()).encode())
+    }
+
+    pub(super) fn entry(&mut self, seq: u64) -> anyhow::Result<Entry> {
+        let bytes = self
+            .feed
+            .get(seq)?
+            .ok_or_else(|| anyhow::anyhow!("Block {} isn't available", seq))?;
+        let block = Block::decode(&bytes)?;
+        if block.kind != BlockKind::Changeset {
+            return Err(anyhow::anyhow!("Block {} isn't a changeset", seq));
+        }
+        Entry::decode(&block.payload)
+    }
+}
+
+/// Runs `change` against `conn` in a transaction and, once it's committed, hands what it changed
+/// (if anything) to `store`; the transaction is undone if `store` fails, so everything stored was
+/// committed and the other way around.
+///
+/// `conn` can't be in a transaction already, since that could still be rolled back afterwards.
+pub fn record<T>(
+    conn: &Connection,
+    change: impl FnOnce(&Connection) -> anyhow::Result<T>,
+    store: impl FnOnce(Vec<u8>) -> anyhow::Result<()>,
+) -> anyhow::Result<T> {
+    if !conn.is_autocommit() {
+        return Err(anyhow::anyhow!(
+            "Changesets can't be recorded inside of a transaction"
+        ));
+    }
+
+    conn.execute_batch("SAVEPOINT changeset_record")?;
+    let (value, changeset) = match capture(conn, change) {
+        Ok(captured) => captured,
+        Err(error) => {
+            conn.execute_batch("ROLLBACK TO changeset_record; RELEASE changeset_record")?;
+            return Err(error);
+        }
+    };
+    // Outside of a transaction, releasing the savepoint commits it.
+    if let Err(error) = conn.execute_batch("RELEASE changeset_record") {
+        if !conn.is_autocommit() {
+            conn.execute_batch("ROLLBACK")?;
+        }
+        return Err(error.into());
+    }
+
+    if changeset.is_empty() {
+        return Ok(value);
+    }
+    let undo = session::invert(&changeset)?;
+    match store(changeset) {
+        Ok(()) => Ok(value),
+        Err(error) => match apply_exactly(conn, &undo) {
+            Ok(()) => Err(error),
+            Err(undoing) => Err(undoing.context(format!(
+                "Could not undo a transaction that couldn't be stored ({:#})",
+                error
+            ))),
+        },
+    }
+}
+
+/// Runs `change` against `conn` and records the changeset of what it did.
+pub fn capture<T>(
+    conn: &Connection,
+    change: impl FnOnce(&Connection) -> anyhow::Result<T>,
+) -> anyhow::Result<(T, Vec<u8>)> {
+    let session = Session::new(conn)?;
+    let value = change(conn)?;
+    Ok((value, session.changeset()?))
+}
+
+/// Applies `changeset` to `conn`, returning the changeset that undoes what it actually did
+/// (which differs from its inverse when conflicts were resolved).
+pub fn apply(
+    conn: &Connection,
+    changeset: &[u8],
+    handler: Arc<dyn ConflictHandler>,
+) -> anyhow::Result<Vec<u8>> {
+    let ((), effect) = capture(conn, |conn| {
+        session::apply(conn, changeset, &|conflict, item| {
+            handler.on_conflict(conflict, item)
+        })
+    })?;
+    session::invert(&effect)
+}
+
+/// Applies every changeset in `changesets` to `conn` in order, or none of them if one of them
+/// doesn't fit.
+pub fn replay(
+    conn: &Connection,
+    mut changesets: impl Iterator<Item = anyhow::Result<Vec<u8>>>,
+) -> anyhow::Result<()> {
+    conn.execute_batch("SAVEPOINT changeset_replay")?;
+    let result = changesets.try_for_each(|changeset| apply_exactly(conn, &changeset?));
+
+    match result {
+        Ok(()) => conn.execute_batch("RELEASE changeset_replay")?,
+        Err(_) => conn.execute_batch("ROLLBACK TO changeset_replay; RELEASE changeset_replay")?,
+    }
+    result
+}
+
+/// Applies a changeset that has to fit the database exactly, like the ones returned by `apply`.
+pub fn apply_exactly(conn: &Connection, changeset: &[u8]) -> anyhow::Result<()> {
+    session::apply(conn, changeset, &|_conflict, _item| ConflictAction::Abort)
+}
+
+#[cfg(test)]
+mod test;
@@ -0,0 +1,70 @@
+use super::*;
+
+const SCHEMA: &str = "CREATE TABLE notes(id INTEGER PRIMARY KEY, body TEXT);";
+
+#[test]
+fn replays_recorded_transactions() -> anyhow::Result<()> {
+    let source = Connection::open_in_memory()?;
+    source.execute_batch(SCHEMA)?;
+    let mut feed = ChangesetFeed::open(&Storage::default(), "notes.changes")?;
+
+    feed.record(&source, |conn| {
+        conn.execute_batch("INSERT INTO notes VALUES (1, 'draft'), (2, 'kept');")?;
+        Ok(())
+    })?;
+    feed.record(&source, |conn| {
+        conn.execute_batch("UPDATE notes SET body = 'final' WHERE id = 1;")?;
+        Ok(())
+    })?;
+    // Transactions that change nothing aren't stored.
+    feed.record(&source, |_conn| Ok(()))?;
+    assert_eq!(feed.len(), 2);
+
+    let replica = Connection::open_in_memory()?;
+    replica.execute_batch(SCHEMA)?;
+    feed.replay_into(&replica)?;
+
+    let bodies = replica
+        .prepare("SELECT body FROM notes ORDER BY id")?
+        .query_map(rusqlite::NO_PARAMS, |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    assert_eq!(bodies, vec!["final", "kept"]);
+    Ok(())
+}
+
+#[test]
+fn only_stores_what_was_committed() -> anyhow::Result<()> {
+    let conn = Connection::open_in_memory()?;
+    conn.execute_batch(SCHEMA)?;
+    let mut feed = ChangesetFeed::open(&Storage::default(), "committed.changes")?;
+    let count = |conn: &Connection| -> rusqlite::Result<i64> {
+        conn.query_row("SELECT count(*) FROM notes", rusqlite::NO_PARAMS, |row| {
+            row.get(0)
+        })
+    };
+
+    // The outer transaction could still be rolled back once the changeset's stored.
+    conn.execute_batch("BEGIN")?;
+    assert!(feed
+        .record(&conn, |conn| {
+            conn.execute_batch("INSERT INTO notes VALUES (1, 'draft');")?;
+            Ok(())
+        })
+        .is_err());
+    conn.execute_batch("ROLLBACK")?;
+    assert!(feed.is_empty());
+
+    // A transaction whose changeset can't be stored is taken back.
+    let stored = record(
+        &conn,
+        |conn| {
+            conn.execute_batch("INSERT INTO notes VALUES (1, 'lost');")?;
+            Ok(())
+        },
+        |_changeset| Err(anyhow::anyhow!("The feed is gone")),
+    );
+    assert!(stored.is_err());
+    assert!(conn.is_autocommit());
+    assert_eq!(count(&conn)?, 0);
+    Ok(())
+}
@@ -0,0 +1,110 @@
+use super::block::{Block, FLAG_ENCRYPTED};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use std::fmt;
+
+/// The length (in bytes) of an `EncryptionKey`.
+pub const KEY_LENGTH: usize = 32;
+
+const NONCE_LENGTH: usize = 24;
+
+/// A secret key used to encrypt the pages of a file before they're appended to its Hypercore.
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey([u8; KEY_LENGTH]);
+
+impl EncryptionKey {
+    pub fn new(bytes: [u8; KEY_LENGTH]) -> Self {
+        Self(bytes)
+    }
+
+    /// Parses a key from its hexadecimal form, as used by the `key=` URI parameter.
+    pub fn from_hex(value: &str) -> anyhow::Result<Self> {
+        let bytes = super::decode_hex(value)?;
+
+        if bytes.len() == KEY_LENGTH {
+            let mut key = [0; KEY_LENGTH];
+            key.copy_from_slice(&bytes);
+            Ok(Self(key))
+        } else {
+            Err(anyhow::anyhow!(
+                "An encryption key has to be {} bytes long; got {}",
+                KEY_LENGTH,
+                bytes.len()
+            ))
+        }
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+/// Seals and opens the payloads of blocks with XChaCha20-Poly1305.
+///
+/// The block's header (which carries its kind and page number) is authenticated along with the
+/// payload, so a sealed page can't be replayed as another page.
+#[derive(Clone)]
+pub struct Cipher {
+    aead: XChaCha20Poly1305,
+}
+
+impl Cipher {
+    pub fn new(key: &EncryptionKey) -> Self {
+        Self {
+            aead: XChaCha20Poly1305::new(&key.0.into()),
+        }
+    }
+
+    pub fn seal(&self, block: &mut Block) -> anyhow::Result<()> {
+        block.flags |= FLAG_ENCRYPTED;
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .aead
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &block.payload,
+                    aad: &block.header(),
+                },
+            )
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt page {}", block.page_no))?;
+
+        let mut payload = Vec::with_capacity(NONCE_LENGTH + ciphertext.len());
+        payload.extend_from_slice(&nonce);
+        payload.extend_from_slice(&ciphertext);
+        block.payload = payload;
+        Ok(())
+    }
+
+    pub fn open(&self, block: &mut Block) -> anyhow::Result<()> {
+        if !block.is_encrypted() {
+            return Err(anyhow::anyhow!(
+                "Page {} was stored without encryption",
+                block.page_no
+            ));
+        } else if block.payload.len() < NONCE_LENGTH {
+            return Err(anyhow::anyhow!(
+                "The sealed payload of page {} is truncated",
+                block.page_no
+            ));
+        }
+
+        let (nonce, ciphertext) = block.payload.split_at(NONCE_LENGTH);
+        let plaintext = self
+            .aead
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &block.header(),
+                },
+            )
+            .map_err(|_| anyhow::anyhow!("Failed to authenticate page {}", block.page_no))?;
+
+        block.flags &= !FLAG_ENCRYPTED;
+        block.payload = plaintext;
+        Ok(())
+    }
+}
@@ -0,0 +1,87 @@
+/// How the payload of page blocks gets compressed before it's appended.
+///
+/// The codec used is recorded in each block's header, so a feed can mix blocks written with
+/// different settings; only the codecs enabled through cargo features can be read back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    /// Zstandard at the provided compression level.
+    #[cfg(feature = "compression-zstd")]
+    Zstd(i32),
+    #[cfg(feature = "compression-lz4")]
+    Lz4,
+}
+
+const CODEC_NONE: u8 = 0;
+const CODEC_ZSTD: u8 = 1;
+const CODEC_LZ4: u8 = 2;
+
+impl Compression {
+    /// The byte identifying this codec in a block's header.
+    pub fn codec(&self) -> u8 {
+        match self {
+            Self::None => CODEC_NONE,
+            #[cfg(feature = "compression-zstd")]
+            Self::Zstd(_) => CODEC_ZSTD,
+            #[cfg(feature = "compression-lz4")]
+            Self::Lz4 => CODEC_LZ4,
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        match self {
+            Self::None => Ok(data.to_vec()),
+            #[cfg(feature = "compression-zstd")]
+            Self::Zstd(level) => Ok(zstd::bulk::compress(data, *level)?),
+            #[cfg(feature = "compression-lz4")]
+            Self::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+        }
+    }
+
+    /// Reverses `compress` for a payload written with the codec identified by `codec`.
+    pub fn decompress(codec: u8, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        match codec {
+            CODEC_NONE => Ok(data.to_vec()),
+            #[cfg(feature = "compression-zstd")]
+            CODEC_ZSTD => Ok(zstd::stream::decode_all(data)?),
+            #[cfg(feature = "compression-lz4")]
+            CODEC_LZ4 => Ok(lz4_flex::decompress_size_prepended(data)?),
+            // Only reachable for the codecs whose feature is disabled.
+            #[allow(unreachable_patterns)]
+            CODEC_ZSTD | CODEC_LZ4 => Err(anyhow::anyhow!(
+                "This block was compressed with codec {}, which this build doesn't support",
+                codec
+            )),
+            other => Err(anyhow::anyhow!("Unknown compression codec {}", other)),
+        }
+    }
+}
+
+/// How well the pages written to a file have compressed so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CompressionStats {
+    /// The number of page blocks appended.
+    pub blocks: u64,
+    /// The size of those pages before compression.
+    pub raw_bytes: u64,
+    /// The size of those pages as they were stored.
+    pub stored_bytes: u64,
+}
+
+impl CompressionStats {
+    pub fn record(&mut self, raw_bytes: usize, stored_bytes: usize) {
+        self.blocks += 1;
+        self.raw_bytes += raw_bytes as u64;
+        self.stored_bytes += stored_bytes as u64;
+    }
+
+    /// The stored size as a fraction of the raw size; lower is better.
+    pub fn ratio(&self) -> f64 {
+        if self.raw_bytes == 0 {
+            1.0
+        } else {
+            self.stored_bytes as f64 / self.raw_bytes as f64
+        }
+    }
+}
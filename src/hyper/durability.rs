@@ -0,0 +1,110 @@
+// What SQLite's `xSync` means for a Hypercore: blocks are in the feed as soon as they're appended,
+// so a sync is only about how far they've gone since, which is the local disk or other nodes
+// keeping a copy of the feed.
+//
+// NOTE: There's no networking yet; the only replicas are other feeds in the same process.
+use super::peer::{within, Replicator};
+use futures::future::BoxFuture;
+use futures::lock::Mutex;
+use futures::stream::{FuturesUnordered, StreamExt};
+use futures::FutureExt;
+use hypercore::Hypercore;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How far the blocks of a file have to go before a sync of it returns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Durability {
+    /// Appended to the Hypercore, which leaves it to the operating system when they reach the
+    /// disk.
+    Buffered,
+    /// Flushed to local storage.
+    #[default]
+    Flushed,
+    /// Flushed, and held by at least `quorum` of the replicas of the file (see
+    /// `Vfs::replicate_to`).
+    Replicated { quorum: usize },
+}
+
+/// Somewhere keeping a copy of a feed, that can say when it's caught up with it.
+pub trait Replica: fmt::Debug + Send + Sync {
+    /// Resolves to whether the replica holds the first `length` blocks of the feed with `key`,
+    /// fetching them first if it's missing any.
+    fn acknowledge(&self, key: [u8; 32], length: u64) -> BoxFuture<'static, anyhow::Result<bool>>;
+}
+
+/// A feed in this process replicating another; see `Vfs::replica`.
+pub(crate) struct FeedReplica {
+    pub(crate) core: Arc<Mutex<Hypercore>>,
+    pub(crate) replicator: Arc<Replicator>,
+}
+
+impl fmt::Debug for FeedReplica {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FeedReplica")
+            .field("key", &super::encode_hex(&self.replicator.key))
+            .finish()
+    }
+}
+
+impl Replica for FeedReplica {
+    fn acknowledge(&self, key: [u8; 32], length: u64) -> BoxFuture<'static, anyhow::Result<bool>> {
+        let core = Arc::clone(&self.core);
+        let replicator = Arc::clone(&self.replicator);
+        async move {
+            if key != replicator.key {
+                return Ok(false);
+            }
+            let mut core = core.lock().await;
+            if core.info().length < length {
+                replicator.update(&mut core).await?;
+            }
+            for seq in 0..length {
+                if !replicator.fetch(&mut core, seq).await? {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        }
+        .boxed()
+    }
+}
+
+/// Waits until `quorum` of `replicas` hold the first `length` blocks of the feed with `key`,
+/// giving each of them up to `timeout`.
+pub(crate) async fn acknowledged(
+    replicas: Vec<Arc<dyn Replica>>,
+    key: [u8; 32],
+    length: u64,
+    quorum: usize,
+    timeout: Option<Duration>,
+) -> anyhow::Result<()> {
+    let total = replicas.len();
+    let mut answers: FuturesUnordered<_> = replicas
+        .iter()
+        .map(|replica| {
+            let replica = Arc::clone(replica);
+            within(timeout, replica.acknowledge(key, length)).map(move |answer| (replica, answer))
+        })
+        .collect();
+
+    let mut acknowledged = 0;
+    while acknowledged < quorum {
+        match answers.next().await {
+            Some((_, Ok(true))) => acknowledged += 1,
+            Some((replica, Ok(false))) => log::warn!("{:?} is missing blocks", replica),
+            Some((replica, Err(error))) => log::warn!("Could not reach {:?}: {:#}", replica, error),
+            None => {
+                return Err(anyhow::anyhow!(
+                    "Only {} of the {} replicas hold the first {} blocks, short of a quorum of {}",
+                    acknowledged,
+                    total,
+                    length,
+                    quorum
+                ))
+            }
+        }
+    }
+    Ok(())
+}
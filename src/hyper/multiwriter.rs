@@ -0,0 +1,214 @@
+// A Hypercore has a single writer, so databases written to by several nodes follow the approach
+// of Autobase (https://github.com/holepunchto/autobase): every writer appends the changesets of
+// its transactions to a feed of its own, and every node linearizes the feeds it knows of into
+// the same causal order to build a local copy of the database from them.
+use super::changeset::{self, ChangesetFeed, ConflictHandler, Entry};
+use super::{encode_hex, session, Feed, Peer, Runtime, Storage, Timeouts};
+use rusqlite::Connection;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Identifies an entry by its writer (the public key of its feed, in hexadecimal) and its
+/// position in the writer's feed.
+type EntryId = (String, u64);
+
+/// An entry that's been applied to the view, along with how to take it back out.
+struct Applied {
+    id: EntryId,
+    undo: Vec<u8>,
+}
+
+/// A database written to by several nodes, each through a feed of changesets of its own.
+///
+/// Changesets don't carry schema changes, so every node has to set up the same tables (each with
+/// a PRIMARY KEY) in its view before calling `update` or `write`.
+pub struct MultiWriter {
+    view: Connection,
+    /// The local writer, as entries identify it.
+    local: String,
+    /// The name of the local writer's feed in `storage`; the feeds of other writers are stored
+    /// under names starting with it.
+    name: String,
+    storage: Storage,
+    runtime: Runtime,
+    feeds: BTreeMap<String, ChangesetFeed>,
+    applied: Vec<Applied>,
+    handler: Arc<dyn ConflictHandler>,
+}
+
+impl MultiWriter {
+    /// Materializes the database into `view`, appending the changes made through `write` to the
+    /// feed named `local` in `storage`.
+    pub fn open(
+        view: Connection,
+        storage: Storage,
+        local: &str,
+        handler: impl ConflictHandler,
+    ) -> anyhow::Result<Self> {
+        let runtime = Runtime::start(Timeouts::default())?;
+        let feed = ChangesetFeed::new(Feed::open(&runtime, &storage, local)?);
+        let writer = encode_hex(&feed.key());
+        let mut feeds = BTreeMap::new();
+        feeds.insert(writer.clone(), feed);
+
+        Ok(Self {
+            view,
+            local: writer,
+            name: local.to_string(),
+            storage,
+            runtime,
+            feeds,
+            applied: Vec::default(),
+            handler: Arc::new(handler),
+        })
+    }
+
+    /// The local copy of the database; changes made directly to it aren't shared.
+    pub fn view(&self) -> &Connection {
+        &self.view
+    }
+
+    /// The public key of the local writer's feed, which other nodes follow it by.
+    pub fn key(&self) -> [u8; 32] {
+        self.local_feed().key()
+    }
+
+    /// Something for other nodes to fetch the local writer's entries from.
+    pub fn peer(&self) -> Arc<dyn Peer> {
+        self.local_feed().peer()
+    }
+
+    /// Starts following the writer whose feed has the public key `key`, fetching its entries from
+    /// `peer`.
+    pub fn add_writer(&mut self, key: [u8; 32], peer: Arc<dyn Peer>) -> anyhow::Result<()> {
+        let writer = encode_hex(&key);
+        if !self.feeds.contains_key(&writer) {
+            let name = format!("{}+{}", self.name, writer);
+            let mut feed = ChangesetFeed::new(Feed::open_replica(
+                &self.runtime,
+                &self.storage,
+                &name,
+                key,
+            )?);
+            feed.replicate(peer)?;
+            self.feeds.insert(writer, feed);
+        }
+        Ok(())
+    }
+
+    fn local_feed(&self) -> &ChangesetFeed {
+        self.feeds
+            .get(&self.local)
+            .expect("the local writer's feed to be open")
+    }
+
+    /// Runs `change` against the view in a transaction and appends what it changed to the local
+    /// writer's feed.
+    pub fn write<T>(
+        &mut self,
+        change: impl FnOnce(&Connection) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        self.update()?;
+
+        let feed = self
+            .feeds
+            .get_mut(&self.local)
+            .expect("the local writer's feed to be open");
+        let (local, applied) = (&self.local, &mut self.applied);
+
+        changeset::record(&self.view, change, |changeset| {
+            let mut clock = BTreeMap::new();
+            for entry in applied.iter() {
+                *clock.entry(entry.id.0.clone()).or_insert(0) += 1;
+            }
+
+            let undo = session::invert(&changeset)?;
+            let seq = feed.append(&Entry { clock, changeset })?;
+            applied.push(Applied {
+                id: (local.clone(), seq),
+                undo,
+            });
+            Ok(())
+        })
+    }
+
+    /// Brings the view up to date with every writer's feed.
+    ///
+    /// Entries that were applied in an order that no longer holds (since entries made
+    /// concurrently came in) are undone and applied again in the new order.
+    pub fn update(&mut self) -> anyhow::Result<()> {
+        for (name, feed) in self.feeds.iter_mut() {
+            if *name != self.local {
+                feed.reload()?;
+            }
+        }
+
+        let order = self.linearize()?;
+        let kept = self
+            .applied
+            .iter()
+            .zip(&order)
+            .take_while(|(applied, (id, _))| applied.id == *id)
+            .count();
+
+        while self.applied.len() > kept {
+            if let Some(applied) = self.applied.pop() {
+                changeset::apply_exactly(&self.view, &applied.undo)?;
+            }
+        }
+
+        for (id, entry) in order.into_iter().skip(kept) {
+            let undo = changeset::apply(&self.view, &entry.changeset, Arc::clone(&self.handler))?;
+            self.applied.push(Applied { id, undo });
+        }
+        Ok(())
+    }
+
+    /// Orders the entries of every feed so that each comes after everything its writer had
+    /// applied when making it; among entries that are ready, the writer with the lowest key goes
+    /// first.
+    ///
+    /// Entries depending on ones that aren't available yet are left out.
+    fn linearize(&mut self) -> anyhow::Result<Vec<(EntryId, Entry)>> {
+        let mut entries = BTreeMap::new();
+        for (name, feed) in self.feeds.iter_mut() {
+            for seq in 0..feed.len() {
+                let entry = feed
+                    .entry(seq)
+                    .map_err(|error| error.context(format!("Could not read {:?}", name)))?;
+                entries.insert((name.clone(), seq), entry);
+            }
+        }
+
+        let mut lengths: BTreeMap<String, u64> = BTreeMap::new();
+        let mut order = Vec::with_capacity(entries.len());
+        loop {
+            let next = self.feeds.keys().find_map(|name| {
+                let id = (name.clone(), lengths.get(name).copied().unwrap_or(0));
+                let entry = entries.get(&id)?;
+                let ready = entry
+                    .clock
+                    .iter()
+                    .all(|(writer, length)| lengths.get(writer).copied().unwrap_or(0) >= *length);
+                if ready {
+                    Some(id)
+                } else {
+                    None
+                }
+            });
+
+            match next.and_then(|id| entries.remove(&id).map(|entry| (id, entry))) {
+                Some((id, entry)) => {
+                    *lengths.entry(id.0.clone()).or_insert(0) += 1;
+                    order.push((id, entry));
+                }
+                None => break,
+            }
+        }
+
+        Ok(order)
+    }
+}
+
+#[cfg(test)]
+mod test;
@@ -0,0 +1,67 @@
+use super::*;
+use crate::hyper::LastWriterWins;
+
+const SCHEMA: &str = "CREATE TABLE notes(id INTEGER PRIMARY KEY, body TEXT);";
+
+fn node(local: &str) -> anyhow::Result<MultiWriter> {
+    let view = Connection::open_in_memory()?;
+    view.execute_batch(SCHEMA)?;
+    MultiWriter::open(view, Storage::default(), local, LastWriterWins)
+}
+
+fn bodies(node: &MultiWriter) -> anyhow::Result<Vec<String>> {
+    let mut statement = node.view().prepare("SELECT body FROM notes ORDER BY id")?;
+    let bodies = statement
+        .query_map(rusqlite::NO_PARAMS, |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(bodies)
+}
+
+#[test]
+fn converges_on_concurrent_writes() -> anyhow::Result<()> {
+    let mut alice = node("alice")?;
+    let mut bob = node("bob")?;
+
+    // Both write the same row before they know of each other.
+    bob.write(|view| {
+        view.execute(
+            "INSERT INTO notes VALUES (1, 'from bob')",
+            rusqlite::NO_PARAMS,
+        )?;
+        Ok(())
+    })?;
+    alice.write(|view| {
+        view.execute(
+            "INSERT INTO notes VALUES (1, 'from alice')",
+            rusqlite::NO_PARAMS,
+        )?;
+        view.execute(
+            "INSERT INTO notes VALUES (2, 'only alice')",
+            rusqlite::NO_PARAMS,
+        )?;
+        Ok(())
+    })?;
+
+    alice.add_writer(bob.key(), bob.peer())?;
+    bob.add_writer(alice.key(), alice.peer())?;
+    alice.update()?;
+    bob.update()?;
+
+    // Whichever writer's entry sorts last replaces the other's conflicting insert on both nodes.
+    let (first, last) = if alice.key() < bob.key() {
+        ("from alice", "from bob")
+    } else {
+        ("from bob", "from alice")
+    };
+    assert_eq!(bodies(&alice)?, vec![last, "only alice"]);
+    assert_eq!(bodies(&bob)?, bodies(&alice)?);
+
+    // Later writes come in through the same peers, on top of what's been settled.
+    bob.write(|view| {
+        view.execute("UPDATE notes SET body = ? WHERE id = 1", &[first])?;
+        Ok(())
+    })?;
+    alice.update()?;
+    assert_eq!(bodies(&alice)?, vec![first, "only alice"]);
+    Ok(())
+}
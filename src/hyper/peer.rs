@@ -0,0 +1,202 @@
+// Feeds opened by their key (`hyper://<key>`) start out empty, and get their blocks from peers. A
+// peer only hands out proofs, which are checked against the feed's key before anything they
+// carry is stored, so it doesn't have to be trusted.
+//
+// NOTE: There's no networking yet; the only peers are other feeds in the same process.
+use futures::future::BoxFuture;
+use futures::lock::Mutex;
+use futures::FutureExt;
+use hypercore::{Hypercore, Proof, RequestBlock, RequestUpgrade};
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Somewhere to fetch the blocks of a feed from.
+pub trait Peer: fmt::Debug + Send + Sync {
+    /// How many blocks of the feed with `key` the peer has a signed tree for, if it has the feed
+    /// at all.
+    fn length(&self, key: [u8; 32]) -> BoxFuture<'static, anyhow::Result<Option<u64>>>;
+
+    /// Proves `block` and the tree grown by `upgrade` (either of which can be left out) from the
+    /// feed with `key`, or returns `None` if the peer doesn't have what was asked for.
+    fn proof(
+        &self,
+        key: [u8; 32],
+        block: Option<RequestBlock>,
+        upgrade: Option<RequestUpgrade>,
+    ) -> BoxFuture<'static, anyhow::Result<Option<Proof>>>;
+}
+
+/// How much of a feed opened by its key is fetched from peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Replication {
+    /// Every block is fetched as soon as a peer has it.
+    Full,
+    /// Blocks are fetched as reads need them, each along with up to `prefetch` of the blocks
+    /// before it. Reads find pages through the index blocks in the feed, so that's only worth it
+    /// for feeds written without them, which reads scan back through.
+    Sparse { prefetch: u64 },
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Self::Sparse { prefetch: 0 }
+    }
+}
+
+/// Serves a feed in this process to others; see `Vfs::peer`.
+pub(crate) struct FeedPeer {
+    pub(crate) key: [u8; 32],
+    pub(crate) core: Arc<Mutex<Hypercore>>,
+}
+
+impl fmt::Debug for FeedPeer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FeedPeer")
+            .field("key", &super::encode_hex(&self.key))
+            .finish()
+    }
+}
+
+impl Peer for FeedPeer {
+    fn length(&self, key: [u8; 32]) -> BoxFuture<'static, anyhow::Result<Option<u64>>> {
+        let core = Arc::clone(&self.core);
+        let known = key == self.key;
+        async move { Ok(Some(core.lock().await.info().length).filter(|_| known)) }.boxed()
+    }
+
+    fn proof(
+        &self,
+        key: [u8; 32],
+        block: Option<RequestBlock>,
+        upgrade: Option<RequestUpgrade>,
+    ) -> BoxFuture<'static, anyhow::Result<Option<Proof>>> {
+        let core = Arc::clone(&self.core);
+        let known = key == self.key;
+        async move {
+            if !known {
+                return Ok(None);
+            }
+            Ok(core
+                .lock()
+                .await
+                .create_proof(block, None, None, upgrade)
+                .await?)
+        }
+        .boxed()
+    }
+}
+
+/// Fetches what's missing of a feed from its peers.
+pub(crate) struct Replicator {
+    pub(crate) key: [u8; 32],
+    pub(crate) peers: Vec<Arc<dyn Peer>>,
+    pub(crate) replication: Replication,
+    pub(crate) timeout: Option<Duration>,
+}
+
+impl Replicator {
+    /// Grows the tree of `core` to the longest one its peers have, and fetches every block of it
+    /// with `Replication::Full`.
+    pub(crate) async fn update(&self, core: &mut Hypercore) -> anyhow::Result<()> {
+        let mut known = core.info().length;
+        let mut longest = None;
+        for peer in &self.peers {
+            match self.ask(peer.length(self.key)).await {
+                Ok(Some(length)) if length > longest.map_or(known, |(_, length)| length) => {
+                    longest = Some((peer, length))
+                }
+                Ok(_) => {}
+                Err(error) => log::warn!("Could not reach {:?}: {:#}", peer, error),
+            }
+        }
+
+        if let Some((peer, length)) = longest {
+            let upgrade = RequestUpgrade {
+                start: known,
+                length: length - known,
+            };
+            match self.ask(peer.proof(self.key, None, Some(upgrade))).await {
+                Ok(Some(proof)) if core.verify_and_apply_proof(&proof).await.unwrap_or(false) => {
+                    known = length
+                }
+                Ok(_) => log::warn!("{:?} couldn't prove the feed grew to {}", peer, length),
+                Err(error) => log::warn!("Could not reach {:?}: {:#}", peer, error),
+            }
+        }
+
+        if self.replication == Replication::Full {
+            for seq in 0..known {
+                self.fetch(core, seq).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes sure `core` has the block at `seq` (and, with `Replication::Sparse`, the ones
+    /// before it) if a peer has it, and returns whether it does.
+    pub(crate) async fn fetch_around(
+        &self,
+        core: &mut Hypercore,
+        seq: u64,
+    ) -> anyhow::Result<bool> {
+        let found = self.fetch(core, seq).await?;
+        if let Replication::Sparse { prefetch } = self.replication {
+            for before in (seq.saturating_sub(prefetch)..seq).rev() {
+                self.fetch(core, before).await?;
+            }
+        }
+        Ok(found)
+    }
+
+    pub(crate) async fn fetch(&self, core: &mut Hypercore, seq: u64) -> anyhow::Result<bool> {
+        if core.has(seq) {
+            return Ok(true);
+        }
+
+        for peer in &self.peers {
+            let nodes = core.missing_nodes(seq).await?;
+            let block = RequestBlock { index: seq, nodes };
+            match self.ask(peer.proof(self.key, Some(block), None)).await {
+                Ok(Some(proof)) if core.verify_and_apply_proof(&proof).await.unwrap_or(false) => {
+                    return Ok(true)
+                }
+                Ok(Some(_)) => log::warn!("{:?} sent a block {} that doesn't check out", peer, seq),
+                Ok(None) => {}
+                // A peer that's slow to answer is likely slow with everything else too.
+                Err(error) => return Err(error.context(format!("Could not fetch block {}", seq))),
+            }
+        }
+        Ok(false)
+    }
+
+    /// Waits on a request to a peer for up to the replicator's timeout.
+    async fn ask<T>(&self, request: BoxFuture<'static, anyhow::Result<T>>) -> anyhow::Result<T> {
+        within(self.timeout, request).await
+    }
+}
+
+/// Waits on a request to a peer for up to `timeout`.
+pub(crate) async fn within<T>(
+    timeout: Option<Duration>,
+    request: BoxFuture<'static, anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return request.await,
+    };
+
+    // Requests are waited on from the thread driving Hypercore (see `Runtime`), so its runtime's
+    // timers are there to keep time.
+    #[cfg(feature = "async-std")]
+    let answer = async_std::future::timeout(timeout, request).await.ok();
+    #[cfg(all(feature = "tokio", not(feature = "async-std")))]
+    let answer = tokio::time::timeout(timeout, request).await.ok();
+
+    answer.unwrap_or_else(|| {
+        Err(anyhow::anyhow!(
+            "Timed out after {:?} waiting on a peer",
+            timeout
+        ))
+    })
+}
@@ -0,0 +1,149 @@
+// SQLite calls into the VFS synchronously while Hypercore is async. Rather than blocking on
+// Hypercore futures in whatever thread SQLite happens to be using (which panics under Tokio and
+// can starve async-std when done from one of its tasks), every operation is sent to a thread
+// running its own executor and the caller waits for the outcome on a channel.
+use futures::channel::mpsc;
+use futures::future::BoxFuture;
+use futures::{FutureExt, StreamExt};
+use rusqlite::ffi as sqlite3;
+use std::future::Future;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{mpsc as sync_mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+const PENDING: u8 = 0;
+const STARTED: u8 = 1;
+const ABANDONED: u8 = 2;
+
+/// How long a VFS callback waits on a Hypercore operation; `None` waits for as long as it takes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// How long to wait for the Hypercore to be free before giving up with `SQLITE_BUSY`.
+    pub busy: Option<Duration>,
+    /// How long to wait for an operation that's started before giving up with `SQLITE_IOERR`.
+    pub io: Option<Duration>,
+    /// How long to wait on a peer for each block (or the length of its feed) before giving up on
+    /// it, which fails reads with `SQLITE_IOERR`; it's best kept shorter than `io`.
+    pub peer: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            busy: Some(Duration::from_secs(5)),
+            io: Some(Duration::from_secs(30)),
+            peer: Some(Duration::from_secs(10)),
+        }
+    }
+}
+
+enum Progress<T> {
+    Started,
+    Done(anyhow::Result<T>),
+}
+
+/// A handle to the thread that drives Hypercore operations.
+///
+/// The thread stops once every handle to it has been dropped and what it was running finishes.
+#[derive(Clone)]
+pub struct Runtime {
+    jobs: mpsc::UnboundedSender<BoxFuture<'static, ()>>,
+    timeouts: Timeouts,
+}
+
+impl Runtime {
+    pub fn start(timeouts: Timeouts) -> anyhow::Result<Self> {
+        let (jobs, receiver) = mpsc::unbounded::<BoxFuture<'static, ()>>();
+
+        thread::Builder::new()
+            .name("hypercore-runtime".to_string())
+            .spawn(move || {
+                let work = receiver.for_each_concurrent(None, |job| job);
+
+                #[cfg(feature = "async-std")]
+                async_std::task::block_on(work);
+
+                #[cfg(all(feature = "tokio", not(feature = "async-std")))]
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .expect("a Tokio runtime for Hypercore")
+                    .block_on(work);
+            })?;
+
+        Ok(Self { jobs, timeouts })
+    }
+
+    /// Waits for `acquire` (usually a lock over a Hypercore) and then runs `operation` with what
+    /// it resolved to, blocking the current thread until it's done.
+    ///
+    /// Timing out on `acquire` fails with `SQLITE_BUSY` and the operation never runs; timing out
+    /// on `operation` fails with a plain error, left for the caller to report as an I/O error.
+    pub fn run<A, F, O, T>(&self, acquire: A, operation: F) -> anyhow::Result<T>
+    where
+        A: Future + Send + 'static,
+        A::Output: Send,
+        F: FnOnce(A::Output) -> O + Send + 'static,
+        O: Future<Output = anyhow::Result<T>> + Send,
+        T: Send + 'static,
+    {
+        let state = Arc::new(AtomicU8::new(PENDING));
+        let (progress, outcome) = sync_mpsc::channel();
+
+        let job_state = Arc::clone(&state);
+        let job = async move {
+            let resource = acquire.await;
+            if job_state
+                .compare_exchange(PENDING, STARTED, Ordering::SeqCst, Ordering::SeqCst)
+                .is_err()
+            {
+                return;
+            }
+
+            let _ = progress.send(Progress::Started);
+            let _ = progress.send(Progress::Done(operation(resource).await));
+        };
+
+        self.jobs
+            .unbounded_send(job.boxed())
+            .map_err(|_| anyhow::anyhow!("The Hypercore runtime has stopped"))?;
+
+        match receive(&outcome, self.timeouts.busy) {
+            Some(Progress::Started) => {}
+            Some(Progress::Done(result)) => return result,
+            None if state
+                .compare_exchange(PENDING, ABANDONED, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok() =>
+            {
+                return Err(
+                    anyhow::Error::new(sqlite3::Error::new(sqlite3::SQLITE_BUSY))
+                        .context("Timed out waiting for the Hypercore to be free"),
+                );
+            }
+            // The operation started right as the wait ran out.
+            None => {}
+        }
+
+        loop {
+            match receive(&outcome, self.timeouts.io) {
+                Some(Progress::Started) => continue,
+                Some(Progress::Done(result)) => return result,
+                None => return Err(anyhow::anyhow!("Timed out waiting on the Hypercore")),
+            }
+        }
+    }
+}
+
+fn receive<T>(
+    outcome: &sync_mpsc::Receiver<Progress<T>>,
+    timeout: Option<Duration>,
+) -> Option<Progress<T>> {
+    match timeout {
+        Some(timeout) => outcome.recv_timeout(timeout).ok(),
+        None => outcome.recv().ok(),
+    }
+}
+
+#[cfg(test)]
+mod test;
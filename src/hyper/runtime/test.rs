@@ -0,0 +1,54 @@
+use super::*;
+use futures::lock::Mutex;
+
+fn start(busy: u64, io: u64) -> anyhow::Result<Runtime> {
+    Runtime::start(Timeouts {
+        busy: Some(Duration::from_millis(busy)),
+        io: Some(Duration::from_millis(io)),
+        peer: None,
+    })
+}
+
+fn extended_code(error: &anyhow::Error) -> Option<std::os::raw::c_int> {
+    error
+        .downcast_ref::<sqlite3::Error>()
+        .map(|error| error.extended_code)
+}
+
+#[test]
+fn runs_operations_to_completion() -> anyhow::Result<()> {
+    let runtime = start(1_000, 1_000)?;
+    assert_eq!(
+        runtime.run(async { 20 }, |n| async move { Ok(n + 22) })?,
+        42
+    );
+    Ok(())
+}
+
+#[test]
+fn reports_busy_when_the_resource_stays_taken() -> anyhow::Result<()> {
+    let runtime = start(50, 1_000)?;
+    let resource = Arc::new(Mutex::new(()));
+    let _held = resource.try_lock().expect("the lock to be free");
+
+    let error = runtime
+        .run(Arc::clone(&resource).lock_owned(), |_guard| async {
+            Ok(())
+        })
+        .unwrap_err();
+    assert_eq!(extended_code(&error), Some(sqlite3::SQLITE_BUSY));
+    Ok(())
+}
+
+#[test]
+fn reports_an_io_error_when_the_operation_stalls() -> anyhow::Result<()> {
+    let runtime = start(1_000, 50)?;
+
+    let error = runtime
+        .run(async {}, |()| {
+            futures::future::pending::<anyhow::Result<()>>()
+        })
+        .unwrap_err();
+    assert_eq!(extended_code(&error), None);
+    Ok(())
+}
@@ -0,0 +1,357 @@
+// Bindings to the parts of SQLite's session extension (https://sqlite.org/sessionintro.html) that
+// changesets are recorded and applied with. rusqlite's `session` feature generates its bindings
+// at build time, which takes libclang; these are written out by hand instead, against the SQLite
+// `build.rs` builds, which it builds with `SQLITE_ENABLE_SESSION` and
+// `SQLITE_ENABLE_PREUPDATE_HOOK` when this feature is on.
+use rusqlite::ffi as sqlite3;
+use rusqlite::types::Value;
+use rusqlite::Action;
+use rusqlite::Connection;
+use std::ffi::CStr;
+use std::marker::PhantomData;
+use std::os::raw;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+
+#[allow(non_camel_case_types)]
+enum sqlite3_session {}
+#[allow(non_camel_case_types)]
+enum sqlite3_changeset_iter {}
+
+type ConflictCallback =
+    unsafe extern "C" fn(*mut raw::c_void, raw::c_int, *mut sqlite3_changeset_iter) -> raw::c_int;
+
+extern "C" {
+    fn sqlite3session_create(
+        db: *mut sqlite3::sqlite3,
+        database: *const raw::c_char,
+        session: *mut *mut sqlite3_session,
+    ) -> raw::c_int;
+    fn sqlite3session_delete(session: *mut sqlite3_session);
+    fn sqlite3session_attach(
+        session: *mut sqlite3_session,
+        table: *const raw::c_char,
+    ) -> raw::c_int;
+    fn sqlite3session_changeset(
+        session: *mut sqlite3_session,
+        length: *mut raw::c_int,
+        changeset: *mut *mut raw::c_void,
+    ) -> raw::c_int;
+    fn sqlite3changeset_invert(
+        length: raw::c_int,
+        changeset: *const raw::c_void,
+        inverse_length: *mut raw::c_int,
+        inverse: *mut *mut raw::c_void,
+    ) -> raw::c_int;
+    fn sqlite3changeset_apply(
+        db: *mut sqlite3::sqlite3,
+        length: raw::c_int,
+        changeset: *mut raw::c_void,
+        filter: Option<unsafe extern "C" fn(*mut raw::c_void, *const raw::c_char) -> raw::c_int>,
+        conflict: Option<ConflictCallback>,
+        context: *mut raw::c_void,
+    ) -> raw::c_int;
+    fn sqlite3changeset_op(
+        iter: *mut sqlite3_changeset_iter,
+        table: *mut *const raw::c_char,
+        columns: *mut raw::c_int,
+        operation: *mut raw::c_int,
+        indirect: *mut raw::c_int,
+    ) -> raw::c_int;
+    fn sqlite3changeset_old(
+        iter: *mut sqlite3_changeset_iter,
+        column: raw::c_int,
+        value: *mut *mut sqlite3::sqlite3_value,
+    ) -> raw::c_int;
+    fn sqlite3changeset_new(
+        iter: *mut sqlite3_changeset_iter,
+        column: raw::c_int,
+        value: *mut *mut sqlite3::sqlite3_value,
+    ) -> raw::c_int;
+    fn sqlite3changeset_conflict(
+        iter: *mut sqlite3_changeset_iter,
+        column: raw::c_int,
+        value: *mut *mut sqlite3::sqlite3_value,
+    ) -> raw::c_int;
+}
+
+fn check(code: raw::c_int, what: &str) -> anyhow::Result<()> {
+    match code {
+        sqlite3::SQLITE_OK => Ok(()),
+        code => Err(anyhow::Error::new(sqlite3::Error::new(code)).context(what.to_string())),
+    }
+}
+
+/// Takes over a buffer SQLite allocated.
+unsafe fn take_buffer(buffer: *mut raw::c_void, length: raw::c_int) -> Vec<u8> {
+    if buffer.is_null() {
+        return Vec::new();
+    }
+    let bytes = std::slice::from_raw_parts(buffer as *const u8, length as usize).to_vec();
+    sqlite3::sqlite3_free(buffer);
+    bytes
+}
+
+/// Why a change couldn't be applied as it was recorded.
+///
+/// See https://sqlite.org/session/c_changeset_conflict.html for what each of them means.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictType {
+    /// The row to update or delete is there, but doesn't hold what the change expected.
+    Data,
+    /// The row to update or delete isn't there.
+    NotFound,
+    /// The row to insert is there already.
+    Conflict,
+    /// The change breaks a constraint other than a foreign key.
+    Constraint,
+    /// The changeset as a whole leaves foreign keys broken.
+    ForeignKey,
+}
+
+impl ConflictType {
+    fn from_code(code: raw::c_int) -> Option<Self> {
+        match code {
+            1 => Some(Self::Data),
+            2 => Some(Self::NotFound),
+            3 => Some(Self::Conflict),
+            4 => Some(Self::Constraint),
+            5 => Some(Self::ForeignKey),
+            _ => None,
+        }
+    }
+}
+
+/// What to do about a change that conflicts; `Replace` is only allowed for `ConflictType::Data`
+/// and `ConflictType::Conflict`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictAction {
+    /// Leaves the change out.
+    Omit,
+    /// Applies the change over what's in the database.
+    Replace,
+    /// Stops applying the changeset and takes back what it already did.
+    Abort,
+}
+
+impl ConflictAction {
+    fn code(self) -> raw::c_int {
+        match self {
+            Self::Omit => 0,
+            Self::Replace => 1,
+            Self::Abort => 2,
+        }
+    }
+}
+
+/// The change a conflict is about.
+pub struct ChangesetItem<'a> {
+    iter: *mut sqlite3_changeset_iter,
+    lifetime: PhantomData<&'a ()>,
+}
+
+impl ChangesetItem<'_> {
+    /// The table the change is to.
+    pub fn table(&self) -> anyhow::Result<String> {
+        let (table, _, _) = self.operation()?;
+        Ok(table)
+    }
+
+    /// Whether the change is an insert, an update or a delete.
+    pub fn action(&self) -> anyhow::Result<Action> {
+        let (_, _, action) = self.operation()?;
+        Ok(action)
+    }
+
+    /// The number of columns in the table.
+    pub fn columns(&self) -> anyhow::Result<usize> {
+        let (_, columns, _) = self.operation()?;
+        Ok(columns)
+    }
+
+    /// The value `column` had before the change, for updates and deletes; `None` for columns an
+    /// update left alone.
+    pub fn old_value(&self, column: usize) -> anyhow::Result<Option<Value>> {
+        self.value(sqlite3changeset_old, column)
+    }
+
+    /// The value `column` has after the change, for inserts and updates; `None` for columns an
+    /// update left alone.
+    pub fn new_value(&self, column: usize) -> anyhow::Result<Option<Value>> {
+        self.value(sqlite3changeset_new, column)
+    }
+
+    /// The value `column` has in the row the change conflicts with, for `ConflictType::Data` and
+    /// `ConflictType::Conflict`.
+    pub fn conflicting_value(&self, column: usize) -> anyhow::Result<Option<Value>> {
+        self.value(sqlite3changeset_conflict, column)
+    }
+
+    fn operation(&self) -> anyhow::Result<(String, usize, Action)> {
+        let (mut table, mut columns, mut operation, mut indirect) = (ptr::null(), 0, 0, 0);
+        check(
+            unsafe {
+                sqlite3changeset_op(
+                    self.iter,
+                    &mut table,
+                    &mut columns,
+                    &mut operation,
+                    &mut indirect,
+                )
+            },
+            "Could not read the change",
+        )?;
+        let table = unsafe { CStr::from_ptr(table) }
+            .to_string_lossy()
+            .into_owned();
+        Ok((table, columns as usize, Action::from(operation)))
+    }
+
+    fn value(
+        &self,
+        read: unsafe extern "C" fn(
+            *mut sqlite3_changeset_iter,
+            raw::c_int,
+            *mut *mut sqlite3::sqlite3_value,
+        ) -> raw::c_int,
+        column: usize,
+    ) -> anyhow::Result<Option<Value>> {
+        let mut value = ptr::null_mut();
+        check(
+            unsafe { read(self.iter, column as raw::c_int, &mut value) },
+            "Could not read a value of the change",
+        )?;
+        if value.is_null() {
+            return Ok(None);
+        }
+        Ok(Some(unsafe { to_value(value) }))
+    }
+}
+
+unsafe fn to_value(value: *mut sqlite3::sqlite3_value) -> Value {
+    let bytes = |data: *const u8| {
+        let length = sqlite3::sqlite3_value_bytes(value) as usize;
+        if data.is_null() || length == 0 {
+            Vec::new()
+        } else {
+            std::slice::from_raw_parts(data, length).to_vec()
+        }
+    };
+    match sqlite3::sqlite3_value_type(value) {
+        sqlite3::SQLITE_INTEGER => Value::Integer(sqlite3::sqlite3_value_int64(value)),
+        sqlite3::SQLITE_FLOAT => Value::Real(sqlite3::sqlite3_value_double(value)),
+        sqlite3::SQLITE_TEXT => Value::Text(
+            String::from_utf8_lossy(&bytes(sqlite3::sqlite3_value_text(value))).into_owned(),
+        ),
+        sqlite3::SQLITE_BLOB => Value::Blob(bytes(sqlite3::sqlite3_value_blob(value) as _)),
+        _ => Value::Null,
+    }
+}
+
+/// Records the changes made to the main database of a connection.
+pub(super) struct Session<'conn> {
+    session: *mut sqlite3_session,
+    lifetime: PhantomData<&'conn Connection>,
+}
+
+impl<'conn> Session<'conn> {
+    /// Starts recording changes to every table of `conn`'s main database.
+    pub(super) fn new(conn: &'conn Connection) -> anyhow::Result<Self> {
+        let mut session = ptr::null_mut();
+        check(
+            unsafe {
+                sqlite3session_create(
+                    conn.handle(),
+                    b"main\0".as_ptr() as *const raw::c_char,
+                    &mut session,
+                )
+            },
+            "Could not start a session",
+        )?;
+        let session = Self {
+            session,
+            lifetime: PhantomData,
+        };
+        check(
+            unsafe { sqlite3session_attach(session.session, ptr::null()) },
+            "Could not attach the session to the database's tables",
+        )?;
+        Ok(session)
+    }
+
+    /// The changeset of what's been changed so far.
+    pub(super) fn changeset(&self) -> anyhow::Result<Vec<u8>> {
+        let (mut length, mut buffer) = (0, ptr::null_mut());
+        check(
+            unsafe { sqlite3session_changeset(self.session, &mut length, &mut buffer) },
+            "Could not build the changeset",
+        )?;
+        Ok(unsafe { take_buffer(buffer, length) })
+    }
+}
+
+impl Drop for Session<'_> {
+    fn drop(&mut self) {
+        unsafe { sqlite3session_delete(self.session) }
+    }
+}
+
+/// The changeset that undoes `changeset`.
+pub(super) fn invert(changeset: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let (mut length, mut buffer) = (0, ptr::null_mut());
+    check(
+        unsafe {
+            sqlite3changeset_invert(
+                changeset.len() as raw::c_int,
+                changeset.as_ptr() as *const raw::c_void,
+                &mut length,
+                &mut buffer,
+            )
+        },
+        "Could not invert the changeset",
+    )?;
+    Ok(unsafe { take_buffer(buffer, length) })
+}
+
+/// Applies `changeset` to `conn`'s main database, asking `on_conflict` what to do about changes
+/// that don't fit; nothing's applied if it aborts.
+pub(super) fn apply(
+    conn: &Connection,
+    changeset: &[u8],
+    on_conflict: &dyn Fn(ConflictType, ChangesetItem) -> ConflictAction,
+) -> anyhow::Result<()> {
+    unsafe extern "C" fn conflict(
+        context: *mut raw::c_void,
+        conflict: raw::c_int,
+        iter: *mut sqlite3_changeset_iter,
+    ) -> raw::c_int {
+        let on_conflict =
+            &*(context as *const &dyn Fn(ConflictType, ChangesetItem) -> ConflictAction);
+        let conflict = match ConflictType::from_code(conflict) {
+            Some(conflict) => conflict,
+            None => return ConflictAction::Abort.code(),
+        };
+        let item = ChangesetItem {
+            iter,
+            lifetime: PhantomData,
+        };
+        // Unwinding into SQLite isn't allowed, so a handler that panics gives up on the changeset.
+        catch_unwind(AssertUnwindSafe(|| on_conflict(conflict, item)))
+            .unwrap_or(ConflictAction::Abort)
+            .code()
+    }
+
+    check(
+        unsafe {
+            sqlite3changeset_apply(
+                conn.handle(),
+                changeset.len() as raw::c_int,
+                changeset.as_ptr() as *mut raw::c_void,
+                None,
+                Some(conflict),
+                &on_conflict as *const _ as *mut raw::c_void,
+            )
+        },
+        "Could not apply the changeset",
+    )
+}
@@ -0,0 +1,54 @@
+use super::*;
+
+#[test]
+fn counts_appends_that_outlast_the_wait_on_them() -> anyhow::Result<()> {
+    let storage = Storage::default();
+    let name = format!("outlasting-{}.db", std::process::id());
+    let mut feed = Feed::open(&Runtime::start(Timeouts::default())?, &storage, &name)?;
+    feed.runtime = Runtime::start(Timeouts {
+        io: Some(Duration::ZERO),
+        ..Timeouts::default()
+    })?;
+
+    // Whether or not the wait runs out, the block lands and the feed has to know about it.
+    let _ = feed.append(b"late");
+    let waited = std::time::Instant::now();
+    while feed.is_empty() && waited.elapsed() < Duration::from_secs(5) {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(feed.len(), 1);
+    drop(feed);
+    Feed::remove(&storage, &name)
+}
+
+#[test]
+fn keeps_a_bounded_number_of_checked_blocks() -> anyhow::Result<()> {
+    let storage = Storage::default();
+    let name = format!("checked-{}.db", std::process::id());
+    let mut feed = Feed::open(&Runtime::start(Timeouts::default())?, &storage, &name)?;
+    let blocks = 4 * VERIFIED_BLOCKS;
+    for seq in 0..blocks {
+        feed.append(&seq.to_be_bytes())?;
+    }
+
+    for seq in 0..blocks {
+        let (value, verified) = feed.get_verified(seq)?.expect("a stored block");
+        assert_eq!(value, seq.to_be_bytes());
+        assert!(verified);
+    }
+    let verifier = Arc::clone(&feed.verifier);
+    let held = feed
+        .runtime
+        .run(verifier.lock_owned(), move |mut verifier| async move {
+            let mut held = 0;
+            if let Some(checker) = verifier.as_mut() {
+                for seq in 0..blocks {
+                    held += checker.core.has(seq) as u64;
+                }
+            }
+            Ok(held)
+        })?;
+    assert!(held < VERIFIED_BLOCKS, "the verifier holds {} blocks", held);
+    drop(feed);
+    Feed::remove(&storage, &name)
+}
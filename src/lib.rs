@@ -1,2 +1,34 @@
+#[cfg(all(feature = "hypercore", not(any(feature = "memory", feature = "disk"))))]
+compile_error!("the `hypercore` feature needs the `memory` or `disk` feature for storage");
+#[cfg(all(
+    feature = "hypercore",
+    not(any(feature = "async-std", feature = "tokio"))
+))]
+compile_error!("the `hypercore` feature needs the `async-std` or `tokio` feature for a runtime");
+
+#[cfg(feature = "extension")]
+pub mod extension;
+#[cfg(feature = "hypercore")]
 mod hyper;
-mod vfs;
+pub mod vfs;
+
+#[cfg(feature = "session")]
+pub use hyper::{
+    ChangesetFeed, ChangesetItem, ConflictAction, ConflictHandler, ConflictType, LastWriterWins,
+    MultiWriter,
+};
+#[cfg(feature = "hypercore")]
+pub use hyper::{
+    Compression, CompressionStats, Durability, Peer, Replica, Replication, Storage, Timeouts,
+};
+#[cfg(feature = "encryption")]
+pub use hyper::{EncryptionKey, KEY_LENGTH};
+pub use vfs::backup::{export_to_file, import_from_file};
+#[cfg(feature = "hypercore")]
+pub use vfs::hyper::{register_functions, register_log_table, Audit, Transaction, Vfs, VfsOptions};
+#[cfg(feature = "session")]
+pub use vfs::hyper::{Recorder, StorageMode};
+pub use vfs::{
+    Cache, CacheStats, CachedFile, CrashHarness, FaultyFilesystem, FileKind, Instance, Instrument,
+    Layer, TempFiles, VfsBuilder, VfsStats,
+};
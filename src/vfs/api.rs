@@ -0,0 +1,132 @@
+// The few SQLite functions the VFS calls itself, to register with SQLite, find its parent and
+// hand back memory. They go to the SQLite bundled into the library unless another SQLite (like
+// the `sqlite3` shell's) has loaded it as an extension, in which case they go through the
+// `sqlite3_api_routines` it passed in, since that's the SQLite the VFS has to be registered with.
+use super::sqlite3;
+use std::ffi::c_void;
+#[cfg(feature = "hypercore")]
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int};
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+#[cfg(feature = "extension")]
+/// The oldest SQLite that can load the library as an extension (3.31.0, for `sqlite3_uri_key`).
+const MIN_VERSION: c_int = 3_031_000;
+
+// Where the functions used here are in `struct sqlite3_api_routines` (see `sqlite3ext.h`), which
+// SQLite only ever adds to at the end.
+#[cfg(feature = "extension")]
+const LIBVERSION_NUMBER: usize = 67;
+const MALLOC: usize = 68;
+#[cfg(feature = "hypercore")]
+const COMPILEOPTION_USED: usize = 161;
+const VFS_FIND: usize = 141;
+const VFS_REGISTER: usize = 142;
+const VFS_UNREGISTER: usize = 143;
+const URI_PARAMETER: usize = 189;
+const URI_KEY: usize = 245;
+
+/// The routines of the SQLite that loaded the library, if it's not the bundled one.
+static ROUTINES: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
+
+/// Reads the function at `index` out of `api`.
+///
+/// # Safety
+///
+/// `F` has to be the type of the function at `index`.
+unsafe fn routine<F: Copy>(api: *const c_void, index: usize) -> F {
+    std::mem::transmute_copy(&*(api as *const *const c_void).add(index))
+}
+
+fn routed<F: Copy>(index: usize) -> Option<F> {
+    let api = ROUTINES.load(Ordering::Acquire);
+    if api.is_null() {
+        None
+    } else {
+        Some(unsafe { routine(api, index) })
+    }
+}
+
+#[cfg(feature = "extension")]
+/// Sends the calls made from now on to the SQLite that handed over `api`, which is left alone if
+/// it's null (as it is for extensions linked in statically).
+///
+/// # Safety
+///
+/// `api` has to be what SQLite passed to the entry point of the extension, which has to stay
+/// loaded from then on.
+pub(crate) unsafe fn route_through(
+    api: *const sqlite3::sqlite3_api_routines,
+) -> anyhow::Result<()> {
+    if api.is_null() {
+        return Ok(());
+    }
+    let version = routine::<unsafe extern "C" fn() -> c_int>(api as _, LIBVERSION_NUMBER)();
+    if version < MIN_VERSION {
+        return Err(anyhow::anyhow!(
+            "SQLite {} is too old to load the Hypercore VFS; it takes {} or later",
+            version,
+            MIN_VERSION
+        ));
+    }
+    ROUTINES.store(api as *mut c_void, Ordering::Release);
+    Ok(())
+}
+
+pub(crate) unsafe fn vfs_find(name: *const c_char) -> *mut sqlite3::sqlite3_vfs {
+    match routed::<unsafe extern "C" fn(*const c_char) -> *mut sqlite3::sqlite3_vfs>(VFS_FIND) {
+        Some(vfs_find) => vfs_find(name),
+        None => sqlite3::sqlite3_vfs_find(name),
+    }
+}
+
+pub(crate) unsafe fn vfs_register(vfs: *mut sqlite3::sqlite3_vfs, make_default: c_int) -> c_int {
+    match routed::<unsafe extern "C" fn(*mut sqlite3::sqlite3_vfs, c_int) -> c_int>(VFS_REGISTER) {
+        Some(vfs_register) => vfs_register(vfs, make_default),
+        None => sqlite3::sqlite3_vfs_register(vfs, make_default),
+    }
+}
+
+pub(crate) unsafe fn vfs_unregister(vfs: *mut sqlite3::sqlite3_vfs) -> c_int {
+    match routed::<unsafe extern "C" fn(*mut sqlite3::sqlite3_vfs) -> c_int>(VFS_UNREGISTER) {
+        Some(vfs_unregister) => vfs_unregister(vfs),
+        None => sqlite3::sqlite3_vfs_unregister(vfs),
+    }
+}
+
+/// Memory SQLite can free once it's done with it.
+pub(crate) unsafe fn malloc(size: c_int) -> *mut c_void {
+    match routed::<unsafe extern "C" fn(c_int) -> *mut c_void>(MALLOC) {
+        Some(malloc) => malloc(size),
+        None => sqlite3::sqlite3_malloc(size),
+    }
+}
+
+#[cfg(feature = "hypercore")]
+/// Whether SQLite was built with `option` (named without its `SQLITE_` prefix).
+pub(crate) fn compileoption_used(option: &CStr) -> bool {
+    // It's left out of SQLites built with `SQLITE_OMIT_COMPILEOPTION_DIAGS`, which can't say.
+    type CompileoptionUsed = Option<unsafe extern "C" fn(*const c_char) -> c_int>;
+    let used = match routed::<CompileoptionUsed>(COMPILEOPTION_USED) {
+        Some(Some(compileoption_used)) => unsafe { compileoption_used(option.as_ptr()) },
+        Some(None) => 0,
+        None => unsafe { sqlite3::sqlite3_compileoption_used(option.as_ptr()) },
+    };
+    used != 0
+}
+
+pub(crate) unsafe fn uri_parameter(path_name: *const c_char, key: *const c_char) -> *const c_char {
+    type UriParameter = unsafe extern "C" fn(*const c_char, *const c_char) -> *const c_char;
+    match routed::<UriParameter>(URI_PARAMETER) {
+        Some(uri_parameter) => uri_parameter(path_name, key),
+        None => sqlite3::sqlite3_uri_parameter(path_name, key),
+    }
+}
+
+pub(crate) unsafe fn uri_key(path_name: *const c_char, index: c_int) -> *const c_char {
+    match routed::<unsafe extern "C" fn(*const c_char, c_int) -> *const c_char>(URI_KEY) {
+        Some(uri_key) => uri_key(path_name, index),
+        None => sqlite3::sqlite3_uri_key(path_name, index),
+    }
+}
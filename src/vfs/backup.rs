@@ -0,0 +1,70 @@
+// Copies databases between an `Instance` and the local filesystem with SQLite's online backup
+// API (https://sqlite.org/backup.html), a few pages at a time so that other connections to the
+// source can keep going while it runs.
+use super::Instance;
+use rusqlite::backup::Backup;
+use rusqlite::{Connection, OpenFlags};
+use std::path::Path;
+use std::time::Duration;
+
+/// How many pages are copied before the source is let go of for a moment.
+const PAGES_PER_STEP: std::os::raw::c_int = 64;
+
+/// How long to wait before trying again when the source is busy.
+const PAUSE_BETWEEN_STEPS: Duration = Duration::from_millis(10);
+
+fn vfs_name(instance: &Instance) -> anyhow::Result<String> {
+    if !instance.registered() {
+        return Err(anyhow::anyhow!("The VFS has to be registered first"));
+    }
+    instance
+        .vfs_name()
+        .ok_or_else(|| anyhow::anyhow!("The name of the VFS isn't valid UTF-8"))
+}
+
+/// Copies the database at `vfs_path` in `instance` to a regular SQLite file at `local_path`,
+/// replacing whatever that file held.
+pub fn export_to_file(
+    instance: &Instance,
+    vfs_path: &str,
+    local_path: impl AsRef<Path>,
+) -> anyhow::Result<()> {
+    let source = Connection::open_with_flags_and_vfs(
+        vfs_path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI,
+        &vfs_name(instance)?,
+    )?;
+    let mut destination = Connection::open(local_path)?;
+
+    copy(&source, &mut destination)
+        .map_err(|error| error.context(format!("Could not export {:?}", vfs_path)))
+}
+
+/// Copies the regular SQLite file at `local_path` into the database at `vfs_path` in `instance`,
+/// creating it if needed and replacing whatever it held.
+pub fn import_from_file(
+    instance: &Instance,
+    local_path: impl AsRef<Path>,
+    vfs_path: &str,
+) -> anyhow::Result<()> {
+    let source = Connection::open_with_flags(local_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut destination = Connection::open_with_flags_and_vfs(
+        vfs_path,
+        OpenFlags::SQLITE_OPEN_READ_WRITE
+            | OpenFlags::SQLITE_OPEN_CREATE
+            | OpenFlags::SQLITE_OPEN_URI,
+        &vfs_name(instance)?,
+    )?;
+
+    copy(&source, &mut destination)
+        .map_err(|error| error.context(format!("Could not import into {:?}", vfs_path)))
+}
+
+fn copy(source: &Connection, destination: &mut Connection) -> anyhow::Result<()> {
+    let backup = Backup::new(source, destination)?;
+    backup.run_to_completion(PAGES_PER_STEP, PAUSE_BETWEEN_STEPS, None)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test;
@@ -0,0 +1,64 @@
+#![cfg(feature = "memory")]
+
+use super::*;
+use crate::vfs::hyper::{Vfs, VfsOptions};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+fn register(name: &str) -> anyhow::Result<Rc<RefCell<Instance>>> {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let hyper_vfs = Rc::new(RefCell::new(Vfs::connect(VfsOptions::default())?));
+    let inst = Instance::new(name, hyper_vfs)?;
+    Instance::register(Rc::clone(&inst), false)?;
+    Ok(inst)
+}
+
+fn count_notes(conn: &Connection) -> rusqlite::Result<i64> {
+    conn.query_row("SELECT COUNT(*) FROM notes", rusqlite::NO_PARAMS, |row| {
+        row.get(0)
+    })
+}
+
+#[test]
+fn round_trips_through_a_local_file() -> anyhow::Result<()> {
+    let directory = tempfile::tempdir()?;
+    let seed = directory.path().join("seed.db");
+    let snapshot = directory.path().join("snapshot.db");
+
+    let local = Connection::open(&seed)?;
+    local.execute_batch(
+        r#"
+        CREATE TABLE notes(body TEXT);
+        INSERT INTO notes(body) VALUES ('hello'), ('world');
+        "#,
+    )?;
+    drop(local);
+
+    let inst = register("hyper-backup")?;
+    import_from_file(&inst.borrow(), &seed, "docs.db")?;
+
+    let conn = Connection::open_with_flags_and_vfs(
+        "docs.db",
+        OpenFlags::SQLITE_OPEN_READ_WRITE,
+        &inst.borrow().vfs_name().unwrap(),
+    )?;
+    assert_eq!(count_notes(&conn)?, 2);
+    conn.execute(
+        "INSERT INTO notes(body) VALUES ('again')",
+        rusqlite::NO_PARAMS,
+    )?;
+
+    export_to_file(&inst.borrow(), "docs.db", &snapshot)?;
+    assert_eq!(count_notes(&Connection::open(&snapshot)?)?, 3);
+    Ok(())
+}
+
+#[test]
+fn needs_a_registered_vfs() -> anyhow::Result<()> {
+    let hyper_vfs = Rc::new(RefCell::new(Vfs::connect(VfsOptions::default())?));
+    let inst = Instance::new("hyper-backup-unregistered", hyper_vfs)?;
+    let directory = tempfile::tempdir()?;
+
+    assert!(export_to_file(&inst.borrow(), "docs.db", directory.path().join("out.db")).is_err());
+    Ok(())
+}
@@ -0,0 +1,273 @@
+// A cache of recent reads in front of a file whose reads are costly, like the Hypercore-backed
+// ones (where a page can take a proof check, decryption and decompression). Writes made through
+// other handles are noticed when a lock is taken, by the change counter SQLite bumps in the header
+// of a database on every write; files SQLite doesn't lock (like journals) are only ever used
+// through one handle at a time.
+use super::{sqlite3, File, FileControl, Layer, LockFlag};
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::os::raw;
+use std::rc::Rc;
+
+/// Where the change counter and the rest of what SQLite checks before trusting its own cache are
+/// in a database's header.
+const CHANGE_COUNTER: std::ops::Range<usize> = 24..40;
+
+/// How well a page cache has done so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    /// Reads answered from memory.
+    pub hits: u64,
+    /// Reads that went through to the file.
+    pub misses: u64,
+    /// Reads dropped to make room for others.
+    pub evictions: u64,
+}
+
+impl CacheStats {
+    /// The fraction of reads answered from memory.
+    pub fn hit_ratio(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            reads => self.hits as f64 / reads as f64,
+        }
+    }
+}
+
+type Key = (sqlite3::sqlite3_int64, raw::c_int);
+
+#[derive(Default)]
+struct Entries {
+    reads: BTreeMap<Key, (Vec<u8>, u64)>,
+    /// When each read was last used, oldest first.
+    recency: BTreeMap<u64, Key>,
+    clock: u64,
+    /// The longest read kept, which bounds how far back one overlapping a write can start.
+    longest: raw::c_int,
+    /// The header bytes the reads were made against.
+    header: Option<Vec<u8>>,
+}
+
+impl Entries {
+    fn touch(&mut self, key: Key) -> u64 {
+        self.clock += 1;
+        self.recency.insert(self.clock, key);
+        self.clock
+    }
+
+    fn remove(&mut self, key: &Key) {
+        if let Some((_, used)) = self.reads.remove(key) {
+            self.recency.remove(&used);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.reads.clear();
+        self.recency.clear();
+        self.longest = 0;
+    }
+}
+
+/// Keeps up to `capacity` of the latest reads from a file in memory, least recently used first
+/// to go.
+pub struct CachedFile<F: File> {
+    file: F,
+    capacity: usize,
+    entries: RefCell<Entries>,
+    lock: Cell<LockFlag>,
+    stats: Rc<Cell<CacheStats>>,
+}
+
+impl<F: File> CachedFile<F> {
+    /// Wraps `file`, counting hits and misses in `stats` (which can be shared between files).
+    pub fn new(file: F, capacity: usize, stats: Rc<Cell<CacheStats>>) -> Self {
+        Self {
+            file,
+            capacity,
+            entries: RefCell::default(),
+            lock: Cell::new(LockFlag::None),
+            stats,
+        }
+    }
+
+    /// The file behind the cache.
+    pub fn inner(&self) -> &F {
+        &self.file
+    }
+
+    fn count(&self, change: impl FnOnce(&mut CacheStats)) {
+        let mut stats = self.stats.get();
+        change(&mut stats);
+        self.stats.set(stats);
+    }
+
+    /// Drops every read overlapping `start..end`.
+    fn invalidate(&self, start: sqlite3::sqlite3_int64, end: sqlite3::sqlite3_int64) {
+        let mut entries = self.entries.borrow_mut();
+        let from = (start - entries.longest as sqlite3::sqlite3_int64, 0);
+        let overlapping: Vec<_> = entries
+            .reads
+            .range(from..(end, 0))
+            .map(|(key, _)| *key)
+            .filter(|(offset, amount)| offset + *amount as sqlite3::sqlite3_int64 > start)
+            .collect();
+        overlapping.iter().for_each(|key| entries.remove(key));
+    }
+
+    /// Empties the cache if the file's been written to through some other handle since it was
+    /// filled.
+    fn revalidate(&self) -> anyhow::Result<()> {
+        let header = self.file.read(
+            CHANGE_COUNTER.len() as raw::c_int,
+            CHANGE_COUNTER.start as sqlite3::sqlite3_int64,
+        )?;
+        let mut entries = self.entries.borrow_mut();
+        if entries.header.as_ref() != Some(&header) {
+            entries.clear();
+            entries.header = Some(header);
+        }
+        Ok(())
+    }
+}
+
+impl<F: File> File for CachedFile<F> {
+    fn close(&self) -> anyhow::Result<()> {
+        self.entries.borrow_mut().clear();
+        self.file.close()
+    }
+
+    fn read(&self, amount: raw::c_int, offset: sqlite3::sqlite3_int64) -> anyhow::Result<Vec<u8>> {
+        let key = (offset, amount);
+        let mut entries = self.entries.borrow_mut();
+        if let Some(used) = entries.reads.get(&key).map(|(_, used)| *used) {
+            entries.recency.remove(&used);
+            let used = entries.touch(key);
+            let entry = entries.reads.get_mut(&key).expect("the read to be cached");
+            entry.1 = used;
+            let data = entry.0.clone();
+            drop(entries);
+            self.count(|stats| stats.hits += 1);
+            return Ok(data);
+        }
+        drop(entries);
+
+        let data = self.file.read(amount, offset)?;
+        self.count(|stats| stats.misses += 1);
+        if self.capacity == 0 {
+            return Ok(data);
+        }
+
+        let mut entries = self.entries.borrow_mut();
+        let used = entries.touch(key);
+        entries.reads.insert(key, (data.clone(), used));
+        entries.longest = entries.longest.max(amount);
+        while entries.reads.len() > self.capacity {
+            let oldest = match entries.recency.iter().next() {
+                Some((_, key)) => *key,
+                None => break,
+            };
+            entries.remove(&oldest);
+            self.count(|stats| stats.evictions += 1);
+        }
+        Ok(data)
+    }
+
+    fn write(
+        &self,
+        data: Vec<u8>,
+        amount: raw::c_int,
+        offset: sqlite3::sqlite3_int64,
+    ) -> anyhow::Result<raw::c_int> {
+        // What's written is dropped rather than kept, as it may yet be rolled back by the file.
+        self.invalidate(offset, offset + amount as sqlite3::sqlite3_int64);
+        let start = offset as usize;
+        if start <= CHANGE_COUNTER.start && start + data.len() >= CHANGE_COUNTER.end {
+            let header = data[CHANGE_COUNTER.start - start..CHANGE_COUNTER.end - start].to_vec();
+            self.entries.borrow_mut().header = Some(header);
+        }
+        self.file.write(data, amount, offset)
+    }
+
+    fn truncate(&self, length: sqlite3::sqlite3_int64) -> anyhow::Result<()> {
+        self.invalidate(length, sqlite3::sqlite3_int64::MAX);
+        self.file.truncate(length)
+    }
+
+    fn sync(&self, flags: raw::c_int) -> anyhow::Result<()> {
+        self.file.sync(flags)
+    }
+
+    fn size(&self) -> anyhow::Result<sqlite3::sqlite3_int64> {
+        self.file.size()
+    }
+
+    fn lock(&self, flag: LockFlag) -> anyhow::Result<()> {
+        self.file.lock(flag)?;
+        if self.lock.replace(flag) == LockFlag::None && flag >= LockFlag::Shared {
+            if let Err(error) = self.revalidate() {
+                self.entries.borrow_mut().clear();
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    fn unlock(&self, flag: LockFlag) -> anyhow::Result<()> {
+        self.file.unlock(flag)?;
+        self.lock.set(flag);
+        Ok(())
+    }
+
+    fn check_reserved_lock(&self) -> anyhow::Result<bool> {
+        self.file.check_reserved_lock()
+    }
+
+    fn file_control(&self, control: FileControl) -> anyhow::Result<Option<String>> {
+        self.file.file_control(control)
+    }
+
+    fn sector_size(&self) -> raw::c_int {
+        self.file.sector_size()
+    }
+
+    fn device_characteristics(&self) -> Vec<raw::c_int> {
+        self.file.device_characteristics()
+    }
+
+    fn as_any(&self) -> Option<&dyn Any> {
+        self.file.as_any()
+    }
+}
+
+/// A `Layer` putting a `CachedFile` of `capacity` reads in front of every file, all counting into
+/// the same `CacheStats`.
+pub struct Cache {
+    capacity: usize,
+    stats: Rc<Cell<CacheStats>>,
+}
+
+impl Cache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            stats: Rc::default(),
+        }
+    }
+
+    /// Where the hits and misses of every file opened through the layer are counted.
+    pub fn stats(&self) -> Rc<Cell<CacheStats>> {
+        Rc::clone(&self.stats)
+    }
+}
+
+impl Layer for Cache {
+    fn file(
+        &self,
+        _path: &str,
+        file: Rc<RefCell<dyn File>>,
+    ) -> anyhow::Result<Rc<RefCell<dyn File>>> {
+        let cached = CachedFile::new(file, self.capacity, Rc::clone(&self.stats));
+        Ok(Rc::new(RefCell::new(cached)))
+    }
+}
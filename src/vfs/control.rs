@@ -0,0 +1,172 @@
+// The opcodes SQLite passes to `xFileControl`, decoded from the pointer that comes with each of
+// them (see https://sqlite.org/c3ref/c_fcntl_begin_atomic_write.html). Opcodes that aren't listed
+// here are answered with `SQLITE_NOTFOUND` without reaching the `VirtualFile`.
+use super::sqlite3;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_uint, c_void};
+
+/// A request made to a file through `VirtualFile::file_control`.
+///
+/// Whatever the handler returns is handed back to SQLite where the opcode has room for it; see
+/// each variant for what's expected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileControl {
+    /// `PRAGMA name = value` (or `PRAGMA name` without a value) was run on the file's database;
+    /// the returned string becomes the result of the statement.
+    Pragma { name: String, value: Option<String> },
+    /// The file is about to grow to this many bytes.
+    SizeHint(i64),
+    /// The file should grow and shrink in chunks of this many bytes.
+    ChunkSize(c_int),
+    /// Asks for the name of the VFS (or stack of VFSes) the file was opened through.
+    VfsName,
+    /// Asks for the name of a temporary file that the VFS would pick.
+    TempFilename,
+    /// Whether WAL files should be kept after the last connection closes; `None` asks for the
+    /// current setting, which is returned as `"0"` or `"1"`.
+    PersistWal(Option<bool>),
+    /// Whether the file overwrites whole sectors safely; `None` asks for the current setting,
+    /// which is returned as `"0"` or `"1"`.
+    PowersafeOverwrite(Option<bool>),
+    /// Sent just before the file is synced, along with the name of the super-journal if one is
+    /// used.
+    Sync { super_journal: Option<String> },
+    /// Sent after a transaction has been committed, once its journal has been dealt with.
+    CommitPhaseTwo,
+    /// Asks whether the file has been renamed or removed since it was opened; returns `"0"` or
+    /// `"1"`.
+    HasMoved,
+    /// Asks for a number that changes whenever the file does; returned in decimal.
+    DataVersion,
+    /// What's written from now on has to be applied all at once, or not at all.
+    BeginAtomicWrite,
+    /// Applies what was written since `BeginAtomicWrite`.
+    CommitAtomicWrite,
+    /// Throws away what was written since `BeginAtomicWrite`.
+    RollbackAtomicWrite,
+}
+
+impl FileControl {
+    /// The error for handlers to return for requests they leave to SQLite's defaults.
+    pub fn not_found() -> anyhow::Error {
+        anyhow::Error::new(sqlite3::Error::new(sqlite3::SQLITE_NOTFOUND))
+    }
+
+    /// Whether `error` is a handler leaving the request to SQLite (see `FileControl::not_found`)
+    /// rather than a failure.
+    pub(crate) fn is_not_found(error: &anyhow::Error) -> bool {
+        matches!(
+            error.downcast_ref::<sqlite3::Error>(),
+            Some(error) if error.extended_code == sqlite3::SQLITE_NOTFOUND
+        )
+    }
+
+    /// Decodes the request for `op`, if it's one that's supported.
+    ///
+    /// # Safety
+    ///
+    /// `argument` has to be what SQLite passed to `xFileControl` along with `op`.
+    pub(crate) unsafe fn from_raw(op: c_int, argument: *mut c_void) -> Option<Self> {
+        let string = |pointer: *const c_char| {
+            if pointer.is_null() {
+                None
+            } else {
+                Some(CStr::from_ptr(pointer).to_string_lossy().into_owned())
+            }
+        };
+        let setting = |pointer: *mut c_void| match *(pointer as *const c_int) {
+            value if value < 0 => None,
+            value => Some(value != 0),
+        };
+
+        Some(match op {
+            sqlite3::SQLITE_FCNTL_PRAGMA => {
+                let arguments = argument as *const *const c_char;
+                Self::Pragma {
+                    name: string(*arguments.add(1))?,
+                    value: string(*arguments.add(2)),
+                }
+            }
+            sqlite3::SQLITE_FCNTL_SIZE_HINT => {
+                Self::SizeHint(*(argument as *const sqlite3::sqlite3_int64))
+            }
+            sqlite3::SQLITE_FCNTL_CHUNK_SIZE => Self::ChunkSize(*(argument as *const c_int)),
+            sqlite3::SQLITE_FCNTL_VFSNAME => Self::VfsName,
+            sqlite3::SQLITE_FCNTL_TEMPFILENAME => Self::TempFilename,
+            sqlite3::SQLITE_FCNTL_PERSIST_WAL => Self::PersistWal(setting(argument)),
+            sqlite3::SQLITE_FCNTL_POWERSAFE_OVERWRITE => {
+                Self::PowersafeOverwrite(setting(argument))
+            }
+            sqlite3::SQLITE_FCNTL_SYNC => Self::Sync {
+                super_journal: string(argument as *const c_char),
+            },
+            sqlite3::SQLITE_FCNTL_COMMIT_PHASETWO => Self::CommitPhaseTwo,
+            sqlite3::SQLITE_FCNTL_HAS_MOVED => Self::HasMoved,
+            sqlite3::SQLITE_FCNTL_DATA_VERSION => Self::DataVersion,
+            sqlite3::SQLITE_FCNTL_BEGIN_ATOMIC_WRITE => Self::BeginAtomicWrite,
+            sqlite3::SQLITE_FCNTL_COMMIT_ATOMIC_WRITE => Self::CommitAtomicWrite,
+            sqlite3::SQLITE_FCNTL_ROLLBACK_ATOMIC_WRITE => Self::RollbackAtomicWrite,
+            _ => return None,
+        })
+    }
+
+    /// Hands what the handler returned back to SQLite through `argument`.
+    ///
+    /// # Safety
+    ///
+    /// `argument` has to be what SQLite passed to `xFileControl` for this request.
+    pub(crate) unsafe fn respond(
+        &self,
+        argument: *mut c_void,
+        output: Option<String>,
+    ) -> anyhow::Result<()> {
+        let output = match output {
+            Some(output) => output,
+            None => return Ok(()),
+        };
+        let flag = || match output.as_str() {
+            "0" => Ok(0),
+            "1" => Ok(1),
+            other => Err(anyhow::anyhow!("Expected \"0\" or \"1\"; got {:?}", other)),
+        };
+
+        match self {
+            Self::Pragma { .. } | Self::VfsName | Self::TempFilename => {
+                *(argument as *mut *mut c_char) = allocate(&output)?
+            }
+            Self::PersistWal(None) | Self::PowersafeOverwrite(None) | Self::HasMoved => {
+                *(argument as *mut c_int) = flag()?
+            }
+            Self::DataVersion => *(argument as *mut c_uint) = output.parse()?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Reports why the request failed, where the opcode has room for it.
+    ///
+    /// # Safety
+    ///
+    /// `argument` has to be what SQLite passed to `xFileControl` for this request.
+    pub(crate) unsafe fn fail(&self, argument: *mut c_void, error: &anyhow::Error) {
+        if let Self::Pragma { .. } = self {
+            if let Ok(message) = allocate(&error.to_string()) {
+                *(argument as *mut *mut c_char) = message;
+            }
+        }
+    }
+}
+
+/// Copies `value` into memory from `sqlite3_malloc`, which SQLite frees once it's done with it.
+pub(crate) unsafe fn allocate(value: &str) -> anyhow::Result<*mut c_char> {
+    let value = CString::new(value)?;
+    let bytes = value.as_bytes_with_nul();
+    let copy = super::api::malloc(bytes.len() as c_int) as *mut c_char;
+    if copy.is_null() {
+        return Err(anyhow::Error::new(sqlite3::Error::new(
+            sqlite3::SQLITE_NOMEM,
+        )));
+    }
+    std::ptr::copy_nonoverlapping(bytes.as_ptr() as *const c_char, copy, bytes.len());
+    Ok(copy)
+}
@@ -0,0 +1,478 @@
+// Fault injection, for testing how SQLite (and the filesystem under it) comes through failures:
+// writes and syncs that fail, crashes that lose whatever wasn't synced (or tear it at sector
+// boundaries), and locks that are busy. Changes to a file are held back by the layer until it's
+// synced, so a crash has something to lose whatever the filesystem below does with them.
+use super::{sqlite3, File, FileControl, Instance, Layer, LockFlag, System};
+use rusqlite::backup::{Backup, StepResult};
+use rusqlite::{Connection, OpenFlags};
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::os::raw;
+use std::rc::{Rc, Weak};
+
+fn error(code: raw::c_int) -> anyhow::Error {
+    anyhow::Error::new(sqlite3::Error::new(code))
+}
+
+/// A change made to a file since it was last synced.
+enum Change {
+    Write {
+        offset: sqlite3::sqlite3_int64,
+        data: Vec<u8>,
+    },
+    Truncate(sqlite3::sqlite3_int64),
+}
+
+/// What's to go wrong, and what's been done so far.
+#[derive(Default)]
+struct Plan {
+    writes: u64,
+    syncs: u64,
+    crashes: u64,
+    /// The count of writes (or syncs) to fail at.
+    failed_write: Option<u64>,
+    failed_sync: Option<u64>,
+    crash_write: Option<u64>,
+    torn_sectors: Option<u64>,
+    busy_locks: u64,
+    files: Vec<Weak<RefCell<FaultyFile>>>,
+}
+
+enum Fault {
+    Fail,
+    Crash,
+}
+
+/// A `Layer` making the filesystem below fail on demand. Clones share what's to go wrong, so one
+/// can be kept to steer the faults while another is stacked on the filesystem.
+#[derive(Clone, Default)]
+pub struct FaultyFilesystem {
+    plan: Rc<RefCell<Plan>>,
+}
+
+impl FaultyFilesystem {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fails the `nth` write from now (counting from 1) with `SQLITE_IOERR_WRITE`.
+    pub fn fail_write(&self, nth: u64) {
+        let mut plan = self.plan.borrow_mut();
+        plan.failed_write = Some(plan.writes + nth);
+    }
+
+    /// Fails the `nth` sync from now (counting from 1) with `SQLITE_IOERR_FSYNC`, leaving the
+    /// changes it was to make durable unsynced.
+    pub fn fail_sync(&self, nth: u64) {
+        let mut plan = self.plan.borrow_mut();
+        plan.failed_sync = Some(plan.syncs + nth);
+    }
+
+    /// Crashes instead of making the `nth` write from now (counting from 1).
+    pub fn crash_at_write(&self, nth: u64) {
+        let mut plan = self.plan.borrow_mut();
+        plan.crash_write = Some(plan.writes + nth);
+    }
+
+    /// Has a crash keep the first `sectors` sectors of every unsynced write (and the truncations
+    /// between them), rather than losing them all.
+    pub fn tear_writes(&self, sectors: Option<u64>) {
+        self.plan.borrow_mut().torn_sectors = sectors;
+    }
+
+    /// Fails the next `count` locks with `SQLITE_BUSY`.
+    pub fn busy_locks(&self, count: u64) {
+        self.plan.borrow_mut().busy_locks = count;
+    }
+
+    /// Calls off any faults still to come.
+    pub fn disarm(&self) {
+        let mut plan = self.plan.borrow_mut();
+        plan.failed_write = None;
+        plan.failed_sync = None;
+        plan.crash_write = None;
+        plan.busy_locks = 0;
+    }
+
+    /// Simulates the machine going down: every file open through the layer loses what wasn't
+    /// synced and fails from then on, except to be unlocked and closed.
+    pub fn crash(&self) {
+        let (files, torn_sectors) = {
+            let mut plan = self.plan.borrow_mut();
+            plan.crashes += 1;
+            plan.files.retain(|file| file.strong_count() > 0);
+            (plan.files.clone(), plan.torn_sectors)
+        };
+        for file in files.iter().filter_map(Weak::upgrade) {
+            let file = file.borrow();
+            if let Err(error) = file.crash(torn_sectors) {
+                log::warn!("Could not tear the writes of a crashed file: {:#}", error);
+            }
+        }
+    }
+
+    /// How many writes have been made (or attempted) through the layer.
+    pub fn writes(&self) -> u64 {
+        self.plan.borrow().writes
+    }
+
+    /// How many times the layer has crashed.
+    pub fn crashes(&self) -> u64 {
+        self.plan.borrow().crashes
+    }
+
+    fn on_write(&self) -> Option<Fault> {
+        let mut plan = self.plan.borrow_mut();
+        plan.writes += 1;
+        if plan.failed_write == Some(plan.writes) {
+            plan.failed_write = None;
+            Some(Fault::Fail)
+        } else if plan.crash_write == Some(plan.writes) {
+            plan.crash_write = None;
+            Some(Fault::Crash)
+        } else {
+            None
+        }
+    }
+
+    fn on_sync(&self) -> Option<Fault> {
+        let mut plan = self.plan.borrow_mut();
+        plan.syncs += 1;
+        if plan.failed_sync == Some(plan.syncs) {
+            plan.failed_sync = None;
+            Some(Fault::Fail)
+        } else {
+            None
+        }
+    }
+
+    fn on_lock(&self) -> Option<Fault> {
+        let mut plan = self.plan.borrow_mut();
+        if plan.busy_locks == 0 {
+            return None;
+        }
+        plan.busy_locks -= 1;
+        Some(Fault::Fail)
+    }
+}
+
+impl Layer for FaultyFilesystem {
+    fn file(
+        &self,
+        _path: &str,
+        file: Rc<RefCell<dyn File>>,
+    ) -> anyhow::Result<Rc<RefCell<dyn File>>> {
+        let faulty = Rc::new(RefCell::new(FaultyFile {
+            file,
+            faults: self.clone(),
+            unsynced: RefCell::default(),
+            crashed: Cell::new(false),
+        }));
+        self.plan.borrow_mut().files.push(Rc::downgrade(&faulty));
+        Ok(faulty)
+    }
+}
+
+struct FaultyFile {
+    file: Rc<RefCell<dyn File>>,
+    faults: FaultyFilesystem,
+    unsynced: RefCell<Vec<Change>>,
+    crashed: Cell<bool>,
+}
+
+impl FaultyFile {
+    fn alive(&self) -> anyhow::Result<()> {
+        if self.crashed.get() {
+            return Err(error(sqlite3::SQLITE_IOERR).context("The file was lost in a crash"));
+        }
+        Ok(())
+    }
+
+    /// Hands the unsynced changes down to the file below.
+    fn flush(&self) -> anyhow::Result<()> {
+        let changes = self.unsynced.replace(Vec::default());
+        for change in changes {
+            match change {
+                Change::Write { offset, data } => {
+                    let amount = data.len() as raw::c_int;
+                    self.file.write(data, amount, offset)?;
+                }
+                Change::Truncate(length) => self.file.truncate(length)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn crash(&self, torn_sectors: Option<u64>) -> anyhow::Result<()> {
+        self.crashed.set(true);
+        let changes = self.unsynced.replace(Vec::default());
+        let sectors = match torn_sectors {
+            Some(sectors) => sectors as sqlite3::sqlite3_int64,
+            None => return Ok(()),
+        };
+
+        let sector_size = self.file.sector_size().max(1) as sqlite3::sqlite3_int64;
+        for change in changes {
+            match change {
+                Change::Write { offset, mut data } => {
+                    let tear = (offset / sector_size + sectors) * sector_size;
+                    data.truncate((tear - offset).max(0) as usize);
+                    if !data.is_empty() {
+                        let amount = data.len() as raw::c_int;
+                        self.file.write(data, amount, offset)?;
+                    }
+                }
+                Change::Truncate(length) => self.file.truncate(length)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl File for FaultyFile {
+    fn close(&self) -> anyhow::Result<()> {
+        // Closing doesn't lose anything short of a crash, even if it isn't synced.
+        if !self.crashed.get() {
+            self.flush()?;
+        }
+        self.file.close()
+    }
+
+    fn read(&self, amount: raw::c_int, offset: sqlite3::sqlite3_int64) -> anyhow::Result<Vec<u8>> {
+        self.alive()?;
+        let mut data = self.file.read(amount, offset)?;
+        let unsynced = self.unsynced.borrow();
+        if unsynced.is_empty() {
+            return Ok(data);
+        }
+
+        let (start, end) = (offset, offset + amount as sqlite3::sqlite3_int64);
+        let mut size = self.file.size()?;
+        data.resize(amount as usize, 0);
+        for change in unsynced.iter() {
+            match change {
+                Change::Write {
+                    offset: at,
+                    data: written,
+                } => {
+                    let written_end = at + written.len() as sqlite3::sqlite3_int64;
+                    let (from, to) = (start.max(*at), end.min(written_end));
+                    if from < to {
+                        data[(from - start) as usize..(to - start) as usize]
+                            .copy_from_slice(&written[(from - at) as usize..(to - at) as usize]);
+                    }
+                    size = size.max(written_end);
+                }
+                Change::Truncate(length) => {
+                    // What's cut off reads back as zeroes if the file grows again.
+                    if *length < end {
+                        let cut = (length - start).max(0) as usize;
+                        data[cut..].iter_mut().for_each(|byte| *byte = 0);
+                    }
+                    size = *length;
+                }
+            }
+        }
+        data.truncate((size - start).clamp(0, amount as sqlite3::sqlite3_int64) as usize);
+        Ok(data)
+    }
+
+    fn write(
+        &self,
+        mut data: Vec<u8>,
+        amount: raw::c_int,
+        offset: sqlite3::sqlite3_int64,
+    ) -> anyhow::Result<raw::c_int> {
+        self.alive()?;
+        match self.faults.on_write() {
+            Some(Fault::Fail) => return Err(error(sqlite3::SQLITE_IOERR_WRITE)),
+            Some(Fault::Crash) => {
+                self.faults.crash();
+                return Err(error(sqlite3::SQLITE_IOERR_WRITE).context("Crashed while writing"));
+            }
+            None => {}
+        }
+        data.truncate(amount as usize);
+        self.unsynced
+            .borrow_mut()
+            .push(Change::Write { offset, data });
+        Ok(amount)
+    }
+
+    fn truncate(&self, length: sqlite3::sqlite3_int64) -> anyhow::Result<()> {
+        self.alive()?;
+        self.unsynced.borrow_mut().push(Change::Truncate(length));
+        Ok(())
+    }
+
+    fn sync(&self, flags: raw::c_int) -> anyhow::Result<()> {
+        self.alive()?;
+        if self.faults.on_sync().is_some() {
+            return Err(error(sqlite3::SQLITE_IOERR_FSYNC));
+        }
+        self.flush()?;
+        self.file.sync(flags)
+    }
+
+    fn size(&self) -> anyhow::Result<sqlite3::sqlite3_int64> {
+        self.alive()?;
+        let size = self.file.size()?;
+        Ok(self
+            .unsynced
+            .borrow()
+            .iter()
+            .fold(size, |size, change| match change {
+                Change::Write { offset, data } => {
+                    size.max(offset + data.len() as sqlite3::sqlite3_int64)
+                }
+                Change::Truncate(length) => *length,
+            }))
+    }
+
+    fn lock(&self, flag: LockFlag) -> anyhow::Result<()> {
+        self.alive()?;
+        if self.faults.on_lock().is_some() {
+            return Err(error(sqlite3::SQLITE_BUSY));
+        }
+        self.file.lock(flag)
+    }
+
+    fn unlock(&self, flag: LockFlag) -> anyhow::Result<()> {
+        // A crashed process lets go of its locks.
+        self.file.unlock(flag)
+    }
+
+    fn check_reserved_lock(&self) -> anyhow::Result<bool> {
+        self.file.check_reserved_lock()
+    }
+
+    fn file_control(&self, control: FileControl) -> anyhow::Result<Option<String>> {
+        self.alive()?;
+        match control {
+            // A batch of atomic writes is applied by the file below, so it has to see them all,
+            // and nothing from before it that could get caught up in it.
+            FileControl::BeginAtomicWrite | FileControl::CommitAtomicWrite => self.flush()?,
+            // The writes since the batch began never reached the file below.
+            FileControl::RollbackAtomicWrite => self.unsynced.borrow_mut().clear(),
+            // Anything else (like the `Sync` sent just before a sync) leaves the changes where
+            // they are, so they're only durable once synced.
+            _ => {}
+        }
+        self.file.file_control(control)
+    }
+
+    fn sector_size(&self) -> raw::c_int {
+        self.file.sector_size()
+    }
+
+    fn device_characteristics(&self) -> Vec<raw::c_int> {
+        self.file.device_characteristics()
+    }
+
+    fn as_any(&self) -> Option<&dyn Any> {
+        self.file.as_any()
+    }
+}
+
+/// Runs SQL against a database through a `FaultyFilesystem`, crashing it at every write in turn
+/// and checking that the database comes back intact each time.
+pub struct CrashHarness {
+    instance: Rc<RefCell<Instance>>,
+    faults: FaultyFilesystem,
+    path: String,
+}
+
+impl CrashHarness {
+    /// Registers a VFS called `name` for the database at `path`, with a `FaultyFilesystem` on top
+    /// of `system`.
+    pub fn new(
+        name: impl ToString,
+        system: Rc<RefCell<dyn System>>,
+        path: impl ToString,
+    ) -> anyhow::Result<Self> {
+        let faults = FaultyFilesystem::new();
+        let instance = Instance::builder(name, system)
+            .layer(faults.clone())
+            .register(false)?;
+        Ok(Self {
+            instance,
+            faults,
+            path: path.to_string(),
+        })
+    }
+
+    /// The faults to come, for setting up more than crashes (like torn writes).
+    pub fn faults(&self) -> &FaultyFilesystem {
+        &self.faults
+    }
+
+    pub fn connect(&self) -> anyhow::Result<Connection> {
+        let vfs_name = self
+            .instance
+            .borrow()
+            .vfs_name()
+            .ok_or_else(|| anyhow::anyhow!("The VFS name isn't valid UTF-8"))?;
+        Ok(Connection::open_with_flags_and_vfs(
+            &self.path,
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+            &vfs_name,
+        )?)
+    }
+
+    /// Reopens the database (which rolls back whatever a crash cut short) and fails unless it
+    /// passes `PRAGMA integrity_check` and `check`.
+    pub fn verify(&self, check: impl Fn(&Connection) -> anyhow::Result<()>) -> anyhow::Result<()> {
+        let conn = self.connect()?;
+        let problems = conn
+            .prepare("PRAGMA integrity_check")?
+            .query_map(rusqlite::NO_PARAMS, |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        anyhow::ensure!(
+            problems == ["ok"],
+            "The database failed its integrity check: {}",
+            problems.join("; ")
+        );
+        check(&conn)
+    }
+
+    /// Runs `workload` once, then once more for each write that took, crashing at that write and
+    /// verifying the database after. Each run starts from the database as it was before the
+    /// first. Returns how many times it crashed.
+    pub fn run(
+        &self,
+        workload: &str,
+        check: impl Fn(&Connection) -> anyhow::Result<()>,
+    ) -> anyhow::Result<u64> {
+        let mut original = Connection::open_in_memory()?;
+        copy(&self.connect()?, &mut original)?;
+
+        let before = self.faults.writes();
+        self.connect()?.execute_batch(workload)?;
+        let writes = self.faults.writes() - before;
+        self.verify(&check)?;
+
+        let crashes = self.faults.crashes();
+        for nth in 1..=writes {
+            copy(&original, &mut self.connect()?)?;
+            self.faults.crash_at_write(nth);
+            let result = self.connect()?.execute_batch(workload);
+            self.faults.disarm();
+            if let Err(error) = result {
+                log::debug!("The workload stopped at write {}: {}", nth, error);
+            }
+            self.verify(&check)
+                .map_err(|error| error.context(format!("After crashing at write {}", nth)))?;
+        }
+        Ok(self.faults.crashes() - crashes)
+    }
+}
+
+/// Replaces the main database of `destination` with a copy of `source`'s.
+fn copy(source: &Connection, destination: &mut Connection) -> anyhow::Result<()> {
+    match Backup::new(source, destination)?.step(-1)? {
+        StepResult::Done => Ok(()),
+        _ => Err(anyhow::anyhow!(
+            "The database was in use while it was copied"
+        )),
+    }
+}
@@ -1,6 +1,8 @@
-use super::{sqlite3, LockFlag};
+use super::{sqlite3, FileControl, LockFlag};
+use std::any::Any;
 use std::cell::RefCell;
 use std::os::raw;
+use std::ptr;
 use std::rc::Rc;
 
 // NOTE: This is fixed to version 1 of sqlite3_file.
@@ -9,6 +11,8 @@ pub trait VirtualFile {
     fn close(&self) -> anyhow::Result<()>;
 
     // int (*xRead)(sqlite3_file*, void*, int iAmt, sqlite3_int64 iOfst);
+    /// Returns at most `amount` bytes; anything shorter is treated as reading past the end of the
+    /// file.
     fn read(&self, amount: raw::c_int, offset: sqlite3::sqlite3_int64) -> anyhow::Result<Vec<u8>>;
 
     // int (*xWrite)(sqlite3_file*, const void*, int iAmt, sqlite3_int64 iOfst);
@@ -38,7 +42,9 @@ pub trait VirtualFile {
     fn check_reserved_lock(&self) -> anyhow::Result<bool>;
 
     // int (*xFileControl)(sqlite3_file*, int op, void *pArg);
-    fn file_control(&self, op: raw::c_int, structure: *const raw::c_void);
+    /// Returns what's to be handed back to SQLite, if anything; failing with
+    /// `FileControl::not_found()` leaves the request to SQLite's defaults.
+    fn file_control(&self, control: FileControl) -> anyhow::Result<Option<String>>;
 
     // int (*xSectorSize)(sqlite3_file*);
     fn sector_size(&self) -> raw::c_int;
@@ -46,52 +52,396 @@ pub trait VirtualFile {
     // int (*xDeviceCharacteristics)(sqlite3_file*);
     // FIXME: Make a bitwise flag of the IO characteristics to use.
     fn device_characteristics(&self) -> Vec<raw::c_int>;
+
+    /// Lets code that's handed a file by SQLite (like a virtual table asking for
+    /// `SQLITE_FCNTL_FILE_POINTER`) get back to the type that implements it.
+    fn as_any(&self) -> Option<&dyn Any> {
+        None
+    }
 }
 
+/// Lets a file handed to a `Layer` be wrapped like any other.
+impl VirtualFile for Rc<RefCell<dyn VirtualFile>> {
+    fn close(&self) -> anyhow::Result<()> {
+        self.borrow().close()
+    }
+
+    fn read(&self, amount: raw::c_int, offset: sqlite3::sqlite3_int64) -> anyhow::Result<Vec<u8>> {
+        self.borrow().read(amount, offset)
+    }
+
+    fn write(
+        &self,
+        data: Vec<u8>,
+        amount: raw::c_int,
+        offset: sqlite3::sqlite3_int64,
+    ) -> anyhow::Result<raw::c_int> {
+        self.borrow().write(data, amount, offset)
+    }
+
+    fn truncate(&self, length: sqlite3::sqlite3_int64) -> anyhow::Result<()> {
+        self.borrow().truncate(length)
+    }
+
+    fn sync(&self, flags: raw::c_int) -> anyhow::Result<()> {
+        self.borrow().sync(flags)
+    }
+
+    fn size(&self) -> anyhow::Result<sqlite3::sqlite3_int64> {
+        self.borrow().size()
+    }
+
+    fn lock(&self, flag: LockFlag) -> anyhow::Result<()> {
+        self.borrow().lock(flag)
+    }
+
+    fn unlock(&self, flag: LockFlag) -> anyhow::Result<()> {
+        self.borrow().unlock(flag)
+    }
+
+    fn check_reserved_lock(&self) -> anyhow::Result<bool> {
+        self.borrow().check_reserved_lock()
+    }
+
+    fn file_control(&self, control: FileControl) -> anyhow::Result<Option<String>> {
+        self.borrow().file_control(control)
+    }
+
+    fn sector_size(&self) -> raw::c_int {
+        self.borrow().sector_size()
+    }
+
+    fn device_characteristics(&self) -> Vec<raw::c_int> {
+        self.borrow().device_characteristics()
+    }
+
+    fn as_any(&self) -> Option<&dyn Any> {
+        // SAFETY: Files are never borrowed mutably; every method of `VirtualFile` takes `&self`.
+        unsafe { self.try_borrow_unguarded() }
+            .ok()
+            .and_then(|file| file.as_any())
+    }
+}
+
+static IO_METHODS: sqlite3::sqlite3_io_methods = sqlite3::sqlite3_io_methods {
+    iVersion: 1,
+    xClose: Some(funcs::close),
+    xRead: Some(funcs::read),
+    xWrite: Some(funcs::write),
+    xTruncate: Some(funcs::truncate),
+    xSync: Some(funcs::sync),
+    xFileSize: Some(funcs::file_size),
+    xLock: Some(funcs::lock),
+    xUnlock: Some(funcs::unlock),
+    xCheckReservedLock: Some(funcs::check_reserved_lock),
+    xFileControl: Some(funcs::file_control),
+    xSectorSize: Some(funcs::sector_size),
+    xDeviceCharacteristics: Some(funcs::device_characteristics),
+    xShmMap: None,
+    xShmLock: None,
+    xShmBarrier: None,
+    xShmUnmap: None,
+    xFetch: None,
+    xUnfetch: None,
+};
+
+/// The structure SQLite allocates (`szOsFile` bytes of it) for every file opened through an
+/// `Instance`; the `sqlite3_file` header has to come first so SQLite can find its methods.
 #[repr(C)]
 #[derive(Clone)]
 pub struct WrappedFile {
-    pub ptr: Option<sqlite3::sqlite3_file>,
-    methods: Option<sqlite3::sqlite3_io_methods>,
-    handle: Rc<RefCell<dyn VirtualFile>>,
+    ptr: sqlite3::sqlite3_file,
+    handle: Option<Rc<RefCell<dyn VirtualFile>>>,
 }
 
 impl WrappedFile {
-    fn bind(&mut self) {
-        self.methods = Some(sqlite3::sqlite3_io_methods {
-            iVersion: 1,
-            xClose: todo!(),
-            xRead: todo!(),
-            xWrite: todo!(),
-            xTruncate: todo!(),
-            xSync: todo!(),
-            xFileSize: todo!(),
-            xLock: todo!(),
-            xUnlock: todo!(),
-            xCheckReservedLock: todo!(),
-            xFileControl: todo!(),
-            xSectorSize: todo!(),
-            xDeviceCharacteristics: todo!(),
-            xShmMap: todo!(),
-            xShmLock: todo!(),
-            xShmBarrier: todo!(),
-            xShmUnmap: todo!(),
-            xFetch: todo!(),
-            xUnfetch: todo!(),
-        });
-
-        self.ptr = Some(sqlite3::sqlite3_file {
-            pMethods: self.methods.as_ref().unwrap(),
-        });
-    }
     pub fn wrap(file_ptr: Rc<RefCell<dyn VirtualFile>>) -> Self {
-        let mut item = Self {
-            ptr: None,
-            methods: None,
-            handle: Rc::clone(&file_ptr),
+        Self {
+            ptr: sqlite3::sqlite3_file {
+                pMethods: &IO_METHODS,
+            },
+            handle: Some(Rc::clone(&file_ptr)),
+        }
+    }
+
+    /// The file this wraps, unless it's been closed.
+    pub fn file(&self) -> Option<Rc<RefCell<dyn VirtualFile>>> {
+        self.handle.clone()
+    }
+
+    /// Moves this file into the memory SQLite reserved for it.
+    ///
+    /// # Safety
+    ///
+    /// `file_ptr` has to point to at least `size_of::<WrappedFile>()` bytes, which is what
+    /// `szOsFile` promises SQLite will allocate.
+    pub(crate) unsafe fn write_into(self, file_ptr: *mut sqlite3::sqlite3_file) {
+        ptr::write(file_ptr as *mut Self, self);
+    }
+
+    /// The file behind `file_ptr`, if it was opened through an `Instance`.
+    ///
+    /// # Safety
+    ///
+    /// `file_ptr` has to be null or point to an open `sqlite3_file`, which has to outlive the
+    /// returned handle.
+    #[cfg(feature = "hypercore")]
+    pub(crate) unsafe fn handle_of<'a>(
+        file_ptr: *const sqlite3::sqlite3_file,
+    ) -> Option<&'a Rc<RefCell<dyn VirtualFile>>> {
+        if file_ptr.is_null() || !ptr::eq((*file_ptr).pMethods, &IO_METHODS) {
+            return None;
+        }
+        (*(file_ptr as *const Self)).handle.as_ref()
+    }
+}
+
+/// Converts an error raised by a `VirtualFile` into a result code, preferring the one carried by
+/// a `rusqlite::ffi::Error` if the implementation provided one, and keeps it for `xGetLastError`.
+fn error_result_code(error: &anyhow::Error, fallback: raw::c_int) -> raw::c_int {
+    let code = match error.downcast_ref::<sqlite3::Error>() {
+        Some(sqlite_error) => sqlite_error.extended_code,
+        None => fallback,
+    };
+    super::set_last_error(code, format!("{:#}", error));
+    code
+}
+
+mod funcs {
+    use std::cell::RefCell;
+    use std::os::raw::{c_int, c_void};
+    use std::ptr;
+    use std::rc::Rc;
+    use std::slice;
+
+    use super::{
+        error_result_code,
+        sqlite3::{self, sqlite3_file, sqlite3_int64},
+        FileControl, LockFlag, VirtualFile, WrappedFile,
+    };
+
+    unsafe fn extract_handle<'a>(
+        file_ptr: *mut sqlite3_file,
+    ) -> Option<&'a Rc<RefCell<dyn VirtualFile>>> {
+        (*(file_ptr as *mut WrappedFile)).handle.as_ref()
+    }
+
+    fn lock_flag(flag: c_int) -> Option<LockFlag> {
+        match flag {
+            sqlite3::SQLITE_LOCK_NONE => Some(LockFlag::None),
+            sqlite3::SQLITE_LOCK_SHARED => Some(LockFlag::Shared),
+            sqlite3::SQLITE_LOCK_RESERVED => Some(LockFlag::Reserved),
+            sqlite3::SQLITE_LOCK_PENDING => Some(LockFlag::Pending),
+            sqlite3::SQLITE_LOCK_EXCLUSIVE => Some(LockFlag::Exclusive),
+            _ => None,
+        }
+    }
+
+    fn report(operation: &str, result: anyhow::Result<()>, fallback: c_int) -> c_int {
+        match result {
+            Ok(()) => sqlite3::SQLITE_OK,
+            Err(error) => {
+                let code = error_result_code(&error, fallback);
+                if code == sqlite3::SQLITE_BUSY {
+                    log::debug!("Could not {} the file: {:?}", operation, error);
+                } else {
+                    log::error!("Failed to {} the file: {:?}", operation, error);
+                }
+                code
+            }
+        }
+    }
+
+    pub unsafe extern "C" fn close(file_ptr: *mut sqlite3_file) -> c_int {
+        log::trace!("Closing the file at {:?}.", file_ptr);
+        let wrapped_file = &mut *(file_ptr as *mut WrappedFile);
+        let result = match wrapped_file.handle.take() {
+            Some(handle) => report(
+                "close",
+                handle.borrow().close(),
+                sqlite3::SQLITE_IOERR_CLOSE,
+            ),
+            None => sqlite3::SQLITE_OK,
+        };
+        wrapped_file.ptr.pMethods = ptr::null();
+        result
+    }
+
+    pub unsafe extern "C" fn read(
+        file_ptr: *mut sqlite3_file,
+        buffer: *mut c_void,
+        amount: c_int,
+        offset: sqlite3_int64,
+    ) -> c_int {
+        let handle = match extract_handle(file_ptr) {
+            Some(handle) => handle,
+            None => return sqlite3::SQLITE_IOERR_READ,
+        };
+        let output = slice::from_raw_parts_mut(buffer as *mut u8, amount as usize);
+
+        match handle.borrow().read(amount, offset) {
+            Ok(data) if data.len() >= output.len() => {
+                output.copy_from_slice(&data[..output.len()]);
+                sqlite3::SQLITE_OK
+            }
+            Ok(data) => {
+                // SQLite expects the unread remainder of the buffer to be zeroed on short reads.
+                output[..data.len()].copy_from_slice(&data);
+                output[data.len()..].iter_mut().for_each(|byte| *byte = 0);
+                sqlite3::SQLITE_IOERR_SHORT_READ
+            }
+            Err(error) => {
+                log::error!(
+                    "Failed to read {} bytes at {} from the file: {:?}",
+                    amount,
+                    offset,
+                    error
+                );
+                error_result_code(&error, sqlite3::SQLITE_IOERR_READ)
+            }
+        }
+    }
+
+    pub unsafe extern "C" fn write(
+        file_ptr: *mut sqlite3_file,
+        buffer: *const c_void,
+        amount: c_int,
+        offset: sqlite3_int64,
+    ) -> c_int {
+        let handle = match extract_handle(file_ptr) {
+            Some(handle) => handle,
+            None => return sqlite3::SQLITE_IOERR_WRITE,
+        };
+        let data = slice::from_raw_parts(buffer as *const u8, amount as usize).to_vec();
+        let result = handle.borrow().write(data, amount, offset).map(|_| ());
+        report("write to", result, sqlite3::SQLITE_IOERR_WRITE)
+    }
+
+    pub unsafe extern "C" fn truncate(file_ptr: *mut sqlite3_file, size: sqlite3_int64) -> c_int {
+        match extract_handle(file_ptr) {
+            Some(handle) => report(
+                "truncate",
+                handle.borrow().truncate(size),
+                sqlite3::SQLITE_IOERR_TRUNCATE,
+            ),
+            None => sqlite3::SQLITE_IOERR_TRUNCATE,
+        }
+    }
+
+    pub unsafe extern "C" fn sync(file_ptr: *mut sqlite3_file, flags: c_int) -> c_int {
+        match extract_handle(file_ptr) {
+            Some(handle) => report(
+                "sync",
+                handle.borrow().sync(flags),
+                sqlite3::SQLITE_IOERR_FSYNC,
+            ),
+            None => sqlite3::SQLITE_IOERR_FSYNC,
+        }
+    }
+
+    pub unsafe extern "C" fn file_size(
+        file_ptr: *mut sqlite3_file,
+        size: *mut sqlite3_int64,
+    ) -> c_int {
+        let handle = match extract_handle(file_ptr) {
+            Some(handle) => handle,
+            None => return sqlite3::SQLITE_IOERR_FSTAT,
         };
-        item.bind();
 
-        item
+        match handle.borrow().size() {
+            Ok(file_size) => {
+                *size = file_size;
+                sqlite3::SQLITE_OK
+            }
+            Err(error) => {
+                log::error!("Failed to determine the size of the file: {:?}", error);
+                error_result_code(&error, sqlite3::SQLITE_IOERR_FSTAT)
+            }
+        }
+    }
+
+    pub unsafe extern "C" fn lock(file_ptr: *mut sqlite3_file, flag: c_int) -> c_int {
+        match (extract_handle(file_ptr), lock_flag(flag)) {
+            (Some(handle), Some(flag)) => report(
+                "lock",
+                handle.borrow().lock(flag),
+                sqlite3::SQLITE_IOERR_LOCK,
+            ),
+            _ => sqlite3::SQLITE_IOERR_LOCK,
+        }
+    }
+
+    pub unsafe extern "C" fn unlock(file_ptr: *mut sqlite3_file, flag: c_int) -> c_int {
+        match (extract_handle(file_ptr), lock_flag(flag)) {
+            (Some(handle), Some(flag)) => report(
+                "unlock",
+                handle.borrow().unlock(flag),
+                sqlite3::SQLITE_IOERR_UNLOCK,
+            ),
+            _ => sqlite3::SQLITE_IOERR_UNLOCK,
+        }
+    }
+
+    pub unsafe extern "C" fn check_reserved_lock(
+        file_ptr: *mut sqlite3_file,
+        reserved: *mut c_int,
+    ) -> c_int {
+        let handle = match extract_handle(file_ptr) {
+            Some(handle) => handle,
+            None => return sqlite3::SQLITE_IOERR_CHECKRESERVEDLOCK,
+        };
+
+        match handle.borrow().check_reserved_lock() {
+            Ok(is_reserved) => {
+                *reserved = is_reserved as c_int;
+                sqlite3::SQLITE_OK
+            }
+            Err(error) => {
+                log::error!("Failed to check for a reserved lock: {:?}", error);
+                error_result_code(&error, sqlite3::SQLITE_IOERR_CHECKRESERVEDLOCK)
+            }
+        }
+    }
+
+    pub unsafe extern "C" fn file_control(
+        file_ptr: *mut sqlite3_file,
+        op: c_int,
+        argument: *mut c_void,
+    ) -> c_int {
+        let (handle, control) = match (
+            extract_handle(file_ptr),
+            FileControl::from_raw(op, argument),
+        ) {
+            (Some(handle), Some(control)) => (handle, control),
+            _ => return sqlite3::SQLITE_NOTFOUND,
+        };
+
+        let result = handle.borrow().file_control(control.clone());
+        match result.and_then(|output| control.respond(argument, output)) {
+            Ok(()) => sqlite3::SQLITE_OK,
+            Err(error) if FileControl::is_not_found(&error) => sqlite3::SQLITE_NOTFOUND,
+            Err(error) => {
+                control.fail(argument, &error);
+                report("control", Err(error), sqlite3::SQLITE_ERROR)
+            }
+        }
+    }
+
+    pub unsafe extern "C" fn sector_size(file_ptr: *mut sqlite3_file) -> c_int {
+        extract_handle(file_ptr)
+            .map(|handle| handle.borrow().sector_size())
+            .unwrap_or(0)
+    }
+
+    pub unsafe extern "C" fn device_characteristics(file_ptr: *mut sqlite3_file) -> c_int {
+        extract_handle(file_ptr)
+            .map(|handle| {
+                handle
+                    .borrow()
+                    .device_characteristics()
+                    .into_iter()
+                    .fold(0, |characteristics, flag| characteristics | flag)
+            })
+            .unwrap_or(0)
     }
 }
@@ -1,5 +1,1054 @@
 // This should hold some wrapping logic over how this extension will communicate with Hyperdrives
-// to emulate a local filesystem. We'll have to add some extra logic for handling file locking to
-// provide that support.
+// to emulate a local filesystem. File locking is handled in `lock`, the audit log of committed
+// transactions in `audit`, where the latest block of each page is in `index`, the pragmas
+// answered by files in `pragma`, the `hyper_log` table in `log_table`, the `hyper_*()` SQL
+// functions in `functions`, the files kept out of the Hypercores in `local` and databases kept as
+// changesets in `view`.
 //
 // FIXME: Check for status on Hyperdrive client support in Rust in https://github.com/datrs/
+use super::{
+    api, file::WrappedFile, sqlite3, AccessFlag, File, FileControl, FileKind, LockFlag, System,
+};
+use crate::hyper::{
+    decode_hex, encode_hex, Block, BlockKind, Compression, CompressionStats, Durability, Feed,
+    Peer, Replica, Replication, Runtime, Storage, Timeouts,
+};
+#[cfg(feature = "encryption")]
+use crate::hyper::{Cipher, EncryptionKey};
+use audit::Commits;
+use index::{Latest, PageIndex};
+use local::LocalFiles;
+use lock::LockTable;
+use rusqlite::Connection;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::ffi::CStr;
+use std::os::raw;
+use std::rc::Rc;
+use std::sync::Arc;
+#[cfg(feature = "session")]
+pub use view::Recorder;
+#[cfg(feature = "session")]
+use view::View;
+
+mod audit;
+mod functions;
+mod index;
+mod local;
+mod lock;
+mod log_table;
+mod pragma;
+#[cfg(feature = "session")]
+mod view;
+
+pub use audit::{Audit, Transaction};
+pub use functions::register_functions;
+pub use log_table::register_log_table;
+
+/// The size of the pages a file is split into before being appended to its Hypercore.
+pub const DEFAULT_PAGE_SIZE: usize = 4096;
+
+/// What SQLite has to be built with to write transactions in batches.
+const BATCH_ATOMIC_WRITE: &CStr =
+    unsafe { CStr::from_bytes_with_nul_unchecked(b"ENABLE_BATCH_ATOMIC_WRITE\0") };
+
+/// Options used when connecting a `Vfs` to Hypercore.
+#[derive(Debug, Clone)]
+pub struct VfsOptions {
+    pub storage: Storage,
+    /// The size of each page block; it's best kept equal to SQLite's `page_size`.
+    pub page_size: usize,
+    /// Encrypts every page before it's appended; the `key=` URI parameter overrides it per file.
+    #[cfg(feature = "encryption")]
+    pub encryption_key: Option<EncryptionKey>,
+    /// Compresses every page before it's encrypted and appended; pages that don't shrink are
+    /// stored as they are.
+    pub compression: Compression,
+    /// How long SQLite waits on Hypercore before failing with `SQLITE_BUSY` or `SQLITE_IOERR`.
+    pub timeouts: Timeouts,
+    /// Where the blocks of files opened by the key of their feed (as `hyper://<key>`) come from.
+    pub peers: Vec<Arc<dyn Peer>>,
+    /// How much of those files is fetched ahead of reads.
+    pub replication: Replication,
+    /// What a sync waits for with `PRAGMA synchronous` at FULL (SQLite's default) or EXTRA, or
+    /// with `PRAGMA fullfsync`; NORMAL only goes as far as `Durability::Flushed`, and OFF doesn't
+    /// sync at all. Files without replicas (like journals) stop at `Durability::Flushed` too.
+    pub durability: Durability,
+    /// What the Hypercores of main databases hold.
+    #[cfg(feature = "session")]
+    pub mode: StorageMode,
+}
+
+impl Default for VfsOptions {
+    fn default() -> Self {
+        Self {
+            storage: Storage::default(),
+            page_size: DEFAULT_PAGE_SIZE,
+            #[cfg(feature = "encryption")]
+            encryption_key: None,
+            compression: Compression::default(),
+            timeouts: Timeouts::default(),
+            peers: Vec::default(),
+            replication: Replication::default(),
+            durability: Durability::default(),
+            #[cfg(feature = "session")]
+            mode: StorageMode::default(),
+        }
+    }
+}
+
+/// What the Hypercore of each main database holds.
+#[cfg(feature = "session")]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum StorageMode {
+    /// The pages SQLite writes, as it writes them.
+    #[default]
+    Pages,
+    /// The changeset of every transaction committed through a `Recorder` (see `Vfs::recorder`),
+    /// the only thing that can write to the database. SQLite works on a copy of it in memory,
+    /// built by running `schema` and applying the changesets; changesets don't carry schema
+    /// changes, so `schema` has to be the same wherever the database is opened, and a `Recorder`
+    /// can't change it.
+    Changesets { schema: String },
+}
+
+/// The key of the feed to replicate for a file named `hyper://<key>`.
+fn replicated_key(path: &str) -> Option<[u8; 32]> {
+    let key = path.strip_prefix("hyper://")?;
+    decode_hex(key).ok()?.try_into().ok()
+}
+
+/// What's shared by every open handle on the same file.
+struct FileState {
+    feed: RefCell<Feed>,
+    index: RefCell<PageIndex>,
+    /// Only covers the pages written since the file was first opened by this VFS.
+    compression_stats: Cell<CompressionStats>,
+    locks: RefCell<LockTable>,
+    /// Transactions committed through audited connections whose records have yet to be appended.
+    commits: Commits,
+    /// What `Durability::Replicated` waits on.
+    replicas: RefCell<Vec<Arc<dyn Replica>>>,
+    /// The copy SQLite works on, if the file's kept as changesets.
+    #[cfg(feature = "session")]
+    view: Option<View>,
+}
+
+/// A virtual filesystem that keeps every main database in its own Hypercore.
+pub struct Vfs {
+    options: VfsOptions,
+    runtime: Runtime,
+    files: RefCell<HashMap<String, Rc<FileState>>>,
+    local: LocalFiles,
+}
+
+impl Vfs {
+    pub fn connect(options: VfsOptions) -> anyhow::Result<Self> {
+        #[cfg(feature = "disk")]
+        #[allow(irrefutable_let_patterns)]
+        if let Storage::Disk(root) = &options.storage {
+            std::fs::create_dir_all(root)?;
+        }
+
+        Ok(Self {
+            runtime: Runtime::start(options.timeouts)?,
+            options,
+            files: RefCell::default(),
+            local: LocalFiles::default(),
+        })
+    }
+
+    pub fn options(&self) -> &VfsOptions {
+        &self.options
+    }
+
+    /// How well the pages written to `path` have compressed, if it's been opened.
+    pub fn compression_stats(&self, path: &str) -> Option<CompressionStats> {
+        self.files
+            .borrow()
+            .get(path)
+            .map(|state| state.compression_stats.get())
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        // Files opened by key are wherever the peers have them.
+        if replicated_key(path).is_some() || self.local.exists(&self.options.storage, path) {
+            return true;
+        }
+        // Other processes can create and remove files kept on disk.
+        match self.options.storage.directory_of(path) {
+            Some(_) => Feed::exists(&self.options.storage, path),
+            None => self.files.borrow().contains_key(path),
+        }
+    }
+
+    fn state(&self, path: &str) -> anyhow::Result<Rc<FileState>> {
+        if let Some(state) = self.files.borrow().get(path) {
+            // Without handles on it, the file could have been changed by another process.
+            if Rc::strong_count(state) == 1 {
+                state.feed.borrow_mut().reload()?;
+            }
+            return Ok(Rc::clone(state));
+        }
+
+        let mut feed = match replicated_key(path) {
+            Some(key) => {
+                let mut feed = Feed::open_replica(&self.runtime, &self.options.storage, path, key)?;
+                feed.replicate(
+                    self.options.peers.clone(),
+                    self.options.replication,
+                    self.options.timeouts.peer,
+                )?;
+                feed
+            }
+            None => Feed::open(&self.runtime, &self.options.storage, path)?,
+        };
+        let directory = self.options.storage.directory_of(path);
+        let index_file = directory
+            .as_ref()
+            .map(|directory| directory.join("pages.idx"));
+        let lock_file = directory.map(|directory| directory.join("sqlite.lock"));
+        let state = Rc::new(FileState {
+            index: RefCell::new(PageIndex::open(index_file.as_deref(), &mut feed)?),
+            feed: RefCell::new(feed),
+            compression_stats: Cell::default(),
+            locks: RefCell::new(LockTable::new(lock_file.as_deref())?),
+            commits: Commits::default(),
+            replicas: RefCell::default(),
+            #[cfg(feature = "session")]
+            view: self.view(path)?,
+        });
+        self.files
+            .borrow_mut()
+            .insert(path.to_string(), Rc::clone(&state));
+        Ok(state)
+    }
+
+    /// The copy of `path` SQLite works on if it's kept as changesets, which is built afresh from
+    /// them; journals of earlier copies are left over from transactions that never committed.
+    #[cfg(feature = "session")]
+    fn view(&self, path: &str) -> anyhow::Result<Option<View>> {
+        match &self.options.mode {
+            StorageMode::Pages => Ok(None),
+            StorageMode::Changesets { schema } => {
+                let journal = format!("{}-journal", path);
+                self.local.remove(&self.options.storage, &journal)?;
+                Ok(Some(View::new(schema)))
+            }
+        }
+    }
+
+    /// Checks the tree of the Hypercore behind `path` against its signature before the file is
+    /// handed to SQLite; blocks are checked as they're read.
+    fn verify(&self, path: &str, state: &FileState) -> Result<(), sqlite3::ErrorCode> {
+        let mut feed = state.feed.borrow_mut();
+        match feed.verify_tree() {
+            Ok(true) => Ok(()),
+            Ok(false) => {
+                let message = format!(
+                    "{:?} has been tampered with: the tree of the feed {} doesn't match its \
+                     signature",
+                    path,
+                    encode_hex(&feed.key())
+                );
+                log::error!("Could not open {:?}: {}", path, message);
+                super::set_last_error(sqlite3::SQLITE_CORRUPT, message);
+                Err(sqlite3::ErrorCode::DatabaseCorrupt)
+            }
+            Err(error) => {
+                log::error!("Could not check the Hypercore for {:?}: {}", path, error);
+                Err(sqlite3::ErrorCode::CannotOpen)
+            }
+        }
+    }
+
+    /// Something for the `VfsOptions::peers` of other VFSes to fetch the blocks of `path` from,
+    /// which they can open as `hyper://<key>` with the key from `PRAGMA hyper_key`.
+    pub fn peer(&self, path: &str) -> anyhow::Result<Arc<dyn Peer>> {
+        Ok(self.state(path)?.feed.borrow().peer())
+    }
+
+    /// Has syncs of `path` with `Durability::Replicated` count `replica` towards their quorum.
+    pub fn replicate_to(&self, path: &str, replica: Arc<dyn Replica>) -> anyhow::Result<()> {
+        self.state(path)?.replicas.borrow_mut().push(replica);
+        Ok(())
+    }
+
+    /// Something for `Vfs::replicate_to` on the VFS writing the feed behind `path` (opened here as
+    /// `hyper://<key>`) to wait on.
+    pub fn replica(&self, path: &str) -> anyhow::Result<Arc<dyn Replica>> {
+        self.state(path)?
+            .feed
+            .borrow()
+            .replica()
+            .ok_or_else(|| anyhow::anyhow!("{:?} isn't opened by the key of its feed", path))
+    }
+
+    /// Records every transaction committed through `conn`, which has to be a connection to `path`
+    /// through this VFS (or this fails), in the Hypercore of `path`.
+    ///
+    /// This replaces any commit, rollback or update hooks set on `conn`.
+    pub fn audit(&self, conn: &Connection, path: &str) -> anyhow::Result<Audit> {
+        self.with_handle(conn, path, |file| {
+            let audit = Audit::install(conn, Arc::clone(&file.state.commits));
+            file.connection.set(Some(audit.connection()));
+            Ok(audit)
+        })
+    }
+
+    /// Runs `f` on the handle `conn` has on `path`, failing if it's a connection to something else.
+    fn with_handle<T>(
+        &self,
+        conn: &Connection,
+        path: &str,
+        f: impl FnOnce(&HyperFile) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        HyperFile::with_handle(conn, path, &self.state(path)?, f)
+    }
+
+    /// Records what's written to `path`, which has to be kept in `StorageMode::Changesets`,
+    /// through `conn`, which has to be a connection to it through this VFS (or this fails).
+    #[cfg(feature = "session")]
+    pub fn recorder(&self, conn: &Connection, path: &str) -> anyhow::Result<Recorder> {
+        self.with_handle(conn, path, |file| {
+            if file.state.view.is_none() {
+                return Err(anyhow::anyhow!(
+                    "{:?} keeps pages rather than changesets",
+                    path
+                ));
+            }
+            Ok(Recorder::new(path, Rc::clone(&file.state)))
+        })
+    }
+
+    /// Applies every changeset in the Hypercore of `path` (kept in `StorageMode::Changesets`) to
+    /// `conn`, which needs to have the same tables and none of their rows, or none of them if one
+    /// doesn't fit.
+    ///
+    /// Changesets sealed with a key other than `VfsOptions::encryption_key` can't be read.
+    #[cfg(feature = "session")]
+    pub fn replay_into(&self, path: &str, conn: &Connection) -> anyhow::Result<()> {
+        let file = self.file(
+            path,
+            self.state(path)?,
+            #[cfg(feature = "encryption")]
+            self.options.encryption_key.as_ref().map(Cipher::new),
+        );
+        let length = file.state.feed.borrow().len();
+        file.replay(conn, 0..length)
+    }
+
+    /// The transactions recorded in the audit log of `path`, oldest first.
+    ///
+    /// Records sealed with a key other than `VfsOptions::encryption_key` can't be read.
+    pub fn transactions(&self, path: &str) -> anyhow::Result<Vec<Transaction>> {
+        let file = self.file(
+            path,
+            self.state(path)?,
+            #[cfg(feature = "encryption")]
+            self.options.encryption_key.as_ref().map(Cipher::new),
+        );
+        file.transactions()
+    }
+
+    fn file(
+        &self,
+        path: &str,
+        state: Rc<FileState>,
+        #[cfg(feature = "encryption")] cipher: Option<Cipher>,
+    ) -> HyperFile {
+        HyperFile {
+            name: path.to_string(),
+            state,
+            #[cfg(feature = "encryption")]
+            cipher,
+            compression: self.options.compression,
+            page_size: self.options.page_size,
+            lock: Cell::new(LockFlag::None),
+            batch: RefCell::default(),
+            durability: self.options.durability,
+            synchronous: Cell::new(self.options.durability),
+            replica_timeout: self.options.timeouts.peer,
+            connection: Cell::default(),
+            #[cfg(feature = "session")]
+            recording: Cell::default(),
+        }
+    }
+
+    #[cfg(feature = "encryption")]
+    fn cipher(&self, parameters: &HashMap<String, String>) -> anyhow::Result<Option<Cipher>> {
+        let key = match parameters.get("key") {
+            Some(value) => Some(EncryptionKey::from_hex(value)?),
+            None => self.options.encryption_key.clone(),
+        };
+
+        Ok(key.as_ref().map(Cipher::new))
+    }
+}
+
+impl System for Vfs {
+    fn open(
+        &self,
+        path: &str,
+        kind: FileKind,
+        open_flags: &rusqlite::OpenFlags,
+        parameters: &HashMap<String, String>,
+    ) -> Result<Box<WrappedFile>, sqlite3::ErrorCode> {
+        let create = open_flags.contains(rusqlite::OpenFlags::SQLITE_OPEN_CREATE);
+        if !create && !self.exists(path) {
+            log::trace!("There's no Hypercore for {:?} to open.", path);
+            return Err(sqlite3::ErrorCode::CannotOpen);
+        }
+
+        if kind != FileKind::MainDb {
+            let file = self
+                .local
+                .open(&self.options.storage, path, create)
+                .map_err(|error| {
+                    log::error!("Could not open the {:?} {:?}: {}", kind, path, error);
+                    sqlite3::ErrorCode::CannotOpen
+                })?;
+            return Ok(Box::new(WrappedFile::wrap(file)));
+        }
+
+        #[cfg(feature = "encryption")]
+        let cipher = self.cipher(parameters).map_err(|error| {
+            log::error!("Could not use the encryption key for {:?}: {}", path, error);
+            sqlite3::ErrorCode::CannotOpen
+        })?;
+        #[cfg(not(feature = "encryption"))]
+        if parameters.contains_key("key") {
+            log::error!(
+                "Could not use the encryption key for {:?}: encryption isn't enabled",
+                path
+            );
+            return Err(sqlite3::ErrorCode::CannotOpen);
+        }
+        let state = self.state(path).map_err(|error| {
+            log::error!("Could not open the Hypercore for {:?}: {}", path, error);
+            sqlite3::ErrorCode::CannotOpen
+        })?;
+        self.verify(path, &state)?;
+        if open_flags.contains(rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE)
+            && !state.feed.borrow().is_writable()
+        {
+            log::error!("{:?} can only be read; its feed is written elsewhere", path);
+            return Err(sqlite3::ErrorCode::ReadOnly);
+        }
+
+        let file = self.file(
+            path,
+            state,
+            #[cfg(feature = "encryption")]
+            cipher,
+        );
+        Ok(Box::new(WrappedFile::wrap(Rc::new(RefCell::new(file)))))
+    }
+
+    fn delete(&mut self, path: &str, _sync_to_system: bool) -> Result<(), sqlite3::ErrorCode> {
+        match self.local.remove(&self.options.storage, path) {
+            Ok(true) => return Ok(()),
+            Ok(false) => {}
+            Err(error) => {
+                log::error!("Could not remove {:?}: {}", path, error);
+                return Err(sqlite3::ErrorCode::SystemIOFailure);
+            }
+        }
+        self.files.borrow_mut().remove(path);
+        Feed::remove(&self.options.storage, path).map_err(|error| {
+            log::error!("Could not remove the Hypercore for {:?}: {}", path, error);
+            sqlite3::ErrorCode::SystemIOFailure
+        })
+    }
+
+    fn access(&self, path: &str, _access_flags: &[AccessFlag]) -> Result<(), sqlite3::ErrorCode> {
+        if self.exists(path) {
+            Ok(())
+        } else {
+            Err(sqlite3::ErrorCode::NotFound)
+        }
+    }
+
+    fn full_pathname(&self, path: &str) -> Result<String, sqlite3::ErrorCode> {
+        Ok(path.to_string())
+    }
+}
+
+/// A file whose contents are kept as a log of page blocks in a Hypercore.
+pub struct HyperFile {
+    name: String,
+    state: Rc<FileState>,
+    #[cfg(feature = "encryption")]
+    cipher: Option<Cipher>,
+    compression: Compression,
+    page_size: usize,
+    /// The lock this handle holds on the file.
+    lock: Cell<LockFlag>,
+    /// Blocks written since `SQLITE_FCNTL_BEGIN_ATOMIC_WRITE`, held back until the batch commits.
+    batch: RefCell<Option<Vec<Prepared>>>,
+    /// What a full sync waits for.
+    durability: Durability,
+    /// What a sync waits for as `PRAGMA synchronous` was last set on this handle.
+    synchronous: Cell<Durability>,
+    replica_timeout: Option<std::time::Duration>,
+    /// The audited connection this handle belongs to, which tags the transactions it queues.
+    connection: Cell<Option<u64>>,
+    /// Whether its connection is in `Recorder::record`, the only thing that writes to files kept as
+    /// changesets.
+    #[cfg(feature = "session")]
+    recording: Cell<bool>,
+}
+
+/// A block that's ready to be appended, along with the length of its page before compression.
+struct Prepared {
+    block: Block,
+    raw_length: Option<usize>,
+}
+
+impl HyperFile {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// How well the pages written to this file have compressed so far.
+    pub fn compression_stats(&self) -> CompressionStats {
+        self.state.compression_stats.get()
+    }
+
+    /// Runs `f` on the handle `conn` has on the file `state` is shared by, failing if it's a
+    /// connection to something else.
+    fn with_handle<T>(
+        conn: &Connection,
+        path: &str,
+        state: &Rc<FileState>,
+        f: impl FnOnce(&HyperFile) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        // SAFETY: `conn` stays open for as long as it's borrowed.
+        unsafe {
+            Self::with_main(conn.handle(), |file| {
+                if !Rc::ptr_eq(&file.state, state) {
+                    return Err(anyhow::anyhow!("The connection isn't to {:?}", path));
+                }
+                f(file)
+            })
+        }
+    }
+
+    /// Runs `f` on the main database of `db`, for the SQL that looks into the Hypercore behind it.
+    ///
+    /// # Safety
+    ///
+    /// `db` has to be an open connection, and stay open until this returns.
+    unsafe fn with_main<T>(
+        db: *mut sqlite3::sqlite3,
+        f: impl FnOnce(&HyperFile) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let mut file: *mut sqlite3::sqlite3_file = std::ptr::null_mut();
+        sqlite3::sqlite3_file_control(
+            db,
+            b"main\0".as_ptr() as *const raw::c_char,
+            sqlite3::SQLITE_FCNTL_FILE_POINTER,
+            &mut file as *mut _ as *mut raw::c_void,
+        );
+
+        let handle = WrappedFile::handle_of(file).map(|handle| handle.borrow());
+        match handle
+            .as_ref()
+            .and_then(|handle| handle.as_any()?.downcast_ref())
+        {
+            Some(file) => f(file),
+            None => Err(anyhow::anyhow!(
+                "Only databases opened through the Hypercore VFS have a feed to look into"
+            )),
+        }
+    }
+
+    fn corrupt(&self, reason: impl std::fmt::Display) -> anyhow::Error {
+        anyhow::Error::new(sqlite3::Error::new(sqlite3::SQLITE_CORRUPT)).context(format!(
+            "{:?} has been tampered with: {}",
+            self.name, reason
+        ))
+    }
+
+    fn not_a_database(&self, reason: impl std::fmt::Display) -> anyhow::Error {
+        anyhow::Error::new(sqlite3::Error::new(sqlite3::SQLITE_NOTADB))
+            .context(format!("Could not read {:?}: {}", self.name, reason))
+    }
+
+    /// Compresses and encrypts a block as needed before it's appended.
+    fn prepare(&self, mut block: Block) -> anyhow::Result<Prepared> {
+        let mut raw_length = None;
+        if block.kind == BlockKind::Page {
+            raw_length = Some(block.payload.len());
+            let compressed = self.compression.compress(&block.payload)?;
+            if compressed.len() < block.payload.len() {
+                block.codec = self.compression.codec();
+                block.payload = compressed;
+            }
+        }
+
+        // Index blocks only say what's in the headers of the blocks they cover, which aren't
+        // sealed either.
+        #[cfg(feature = "encryption")]
+        if !matches!(block.kind, BlockKind::Truncate | BlockKind::Index) {
+            if let Some(cipher) = &self.cipher {
+                cipher.seal(&mut block)?;
+            }
+        }
+
+        Ok(Prepared { block, raw_length })
+    }
+
+    /// Appends a block to the file's Hypercore, or to the batch being written if there's one.
+    fn append(&self, block: Block) -> anyhow::Result<()> {
+        let prepared = self.prepare(block)?;
+        if let Some(batch) = self.batch.borrow_mut().as_mut() {
+            batch.push(prepared);
+            return Ok(());
+        }
+
+        let seq = self
+            .state
+            .feed
+            .borrow_mut()
+            .append(&prepared.block.encode())?;
+        self.record(seq, &[prepared])
+    }
+
+    /// Keeps track of the blocks just appended from `seq` on.
+    fn record(&self, seq: u64, appended: &[Prepared]) -> anyhow::Result<()> {
+        let mut stats = self.state.compression_stats.get();
+        for prepared in appended {
+            if let Some(raw_length) = prepared.raw_length {
+                stats.record(raw_length, prepared.block.payload.len());
+            }
+        }
+        self.state.compression_stats.set(stats);
+
+        let blocks: Vec<_> = appended.iter().map(|prepared| &prepared.block).collect();
+        self.state.index.borrow_mut().appended(seq, &blocks)
+    }
+
+    /// Appends every block of the batch being written at once, so that other readers of the
+    /// Hypercore see all of it or none of it.
+    fn commit_batch(&self) -> anyhow::Result<()> {
+        let batch = self.batch.borrow_mut().take().unwrap_or_default();
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let encoded: Vec<_> = batch
+            .iter()
+            .map(|prepared| prepared.block.encode())
+            .collect();
+        let seq = self.state.feed.borrow_mut().append_batch(&encoded)?;
+        self.record(seq, &batch)
+    }
+
+    /// Reads the block at `seq`, once it's been checked against the feed's signatures.
+    fn block(&self, seq: u64) -> anyhow::Result<Block> {
+        let (bytes, verified) = self
+            .state
+            .feed
+            .borrow_mut()
+            .get_verified(seq)?
+            .ok_or_else(|| anyhow::anyhow!("Block {} of {:?} isn't available", seq, self.name))?;
+        if !verified {
+            return Err(self.corrupt(format!(
+                "block {} doesn't match the signature of the feed {}",
+                seq,
+                encode_hex(&self.state.feed.borrow().key())
+            )));
+        }
+        Block::decode(&bytes)
+    }
+
+    /// Decrypts and decompresses the payload of a page block, if needed.
+    fn open_block(&self, block: &mut Block) -> anyhow::Result<()> {
+        #[cfg(feature = "encryption")]
+        match (&self.cipher, block.is_encrypted()) {
+            (Some(cipher), _) => cipher
+                .open(block)
+                .map_err(|error| self.not_a_database(error))?,
+            (None, true) => {
+                return Err(self.not_a_database("it's encrypted and no key was provided"))
+            }
+            (None, false) => {}
+        }
+        #[cfg(not(feature = "encryption"))]
+        if block.is_encrypted() {
+            return Err(self.not_a_database("it's encrypted and encryption isn't enabled"));
+        }
+
+        block.payload = Compression::decompress(block.codec, &block.payload)
+            .map_err(|error| self.not_a_database(error))?;
+        block.codec = Compression::None.codec();
+        Ok(())
+    }
+
+    /// Finds the latest contents of a page, taking truncations that came after it into account.
+    fn page(&self, page_no: u64) -> anyhow::Result<Vec<u8>> {
+        let page_start = page_no * self.page_size as u64;
+        let mut surviving_length = self.page_size;
+        let length = self.state.feed.borrow().len();
+        let indexed = {
+            let mut index = self.state.index.borrow_mut();
+            let indexed = index.sync(&mut self.state.feed.borrow_mut())?;
+            // Replicas get the rest from the index blocks in the feed.
+            if indexed < length {
+                index.fetch(length, |seq| self.block(seq))?
+            } else {
+                indexed
+            }
+        };
+
+        // Only what's yet to be committed or indexed is scanned.
+        let batch = self.batch.borrow();
+        let batched = batch
+            .iter()
+            .flatten()
+            .rev()
+            .map(|prepared| Ok(prepared.block.clone()));
+        let appended = (indexed..length).rev().map(|seq| self.block(seq));
+
+        for block in batched.chain(appended) {
+            let block = block?;
+
+            match block.kind {
+                BlockKind::Truncate if block.file_size <= page_start => {
+                    return Ok(vec![0; self.page_size])
+                }
+                BlockKind::Truncate => {
+                    surviving_length =
+                        surviving_length.min((block.file_size - page_start) as usize);
+                }
+                BlockKind::Page if block.page_no == page_no => {
+                    return self.open_page(block, surviving_length);
+                }
+                BlockKind::Page | BlockKind::Transaction | BlockKind::Index => {}
+                #[cfg(feature = "session")]
+                BlockKind::Changeset => {}
+            }
+        }
+
+        let trusted = self.state.index.borrow().is_trusted();
+        let latest = self
+            .state
+            .index
+            .borrow()
+            .latest(page_no, page_start, self.page_size);
+        match latest {
+            Latest::Page {
+                seq,
+                surviving_length: surviving,
+            } => {
+                let block = self.block(seq)?;
+                if block.kind == BlockKind::Page && block.page_no == page_no {
+                    return self.open_page(block, surviving_length.min(surviving));
+                }
+                if trusted {
+                    return Err(self.corrupt(format!(
+                        "block {} was indexed as page {} but isn't",
+                        seq, page_no
+                    )));
+                }
+            }
+            // SQLite writes every page it extends the file by, so one that's never been written
+            // is bound to be past the end of the file.
+            Latest::Missing if trusted || page_start >= self.size()? as u64 => {
+                return Ok(vec![0; self.page_size])
+            }
+            Latest::Missing => {}
+        }
+
+        // What the index says doesn't add up; a rebuilt one is trusted, so this only happens once.
+        self.state
+            .index
+            .borrow_mut()
+            .rebuild(&mut self.state.feed.borrow_mut())?;
+        self.page(page_no)
+    }
+
+    /// Turns a page block into the page it holds, zeroing anything from `surviving_length` on.
+    fn open_page(&self, mut block: Block, surviving_length: usize) -> anyhow::Result<Vec<u8>> {
+        self.open_block(&mut block)?;
+        let mut page = block.payload;
+        page.resize(self.page_size, 0);
+        page[surviving_length..]
+            .iter_mut()
+            .for_each(|byte| *byte = 0);
+        Ok(page)
+    }
+
+    /// Appends the records of the transactions this handle's connection committed since it last
+    /// wrote to the file; each stays queued until it's in, so one that fails is tried again next
+    /// time.
+    fn append_commits(&self) -> anyhow::Result<()> {
+        let connection = match self.connection.get() {
+            Some(connection) => connection,
+            None => return Ok(()),
+        };
+        let commits = || {
+            self.state
+                .commits
+                .lock()
+                .map_err(|_| anyhow::anyhow!("The audit log of {:?} is poisoned", self.name))
+        };
+
+        loop {
+            let queued = commits()?
+                .iter()
+                .find(|(committer, _)| *committer == connection)
+                .map(|(_, transaction)| transaction.clone());
+            let transaction = match queued {
+                Some(transaction) => transaction,
+                None => return Ok(()),
+            };
+
+            let file_size = self.size()? as u64;
+            self.append(Block::transaction(file_size, transaction.encode()))?;
+            let mut commits = commits()?;
+            if let Some(index) = commits
+                .iter()
+                .position(|(committer, _)| *committer == connection)
+            {
+                commits.remove(index);
+            }
+        }
+    }
+
+    /// Picks up what's been appended to the feed since it was last read, which a copy of a file
+    /// kept as changesets is brought up to date with.
+    fn reload(&self) -> anyhow::Result<()> {
+        self.state.feed.borrow_mut().reload()?;
+        #[cfg(feature = "session")]
+        if self.state.view.is_some() {
+            self.catch_up()?;
+        }
+        Ok(())
+    }
+
+    fn transactions(&self) -> anyhow::Result<Vec<Transaction>> {
+        let length = self.state.feed.borrow().len();
+        let mut transactions = Vec::new();
+
+        for seq in 0..length {
+            // Blocks cleared by `PRAGMA hyper_compact` are gone, but transactions never are.
+            let mut block = match self.state.feed.borrow_mut().get(seq)? {
+                Some(bytes) => Block::decode(&bytes)?,
+                None => continue,
+            };
+            if block.kind == BlockKind::Transaction {
+                self.open_block(&mut block)?;
+                transactions.push(Transaction::decode(seq, block.timestamp, &block.payload)?);
+            }
+        }
+        Ok(transactions)
+    }
+}
+
+impl File for HyperFile {
+    fn close(&self) -> anyhow::Result<()> {
+        self.batch.borrow_mut().take();
+        self.unlock(LockFlag::None)
+    }
+
+    fn read(&self, amount: raw::c_int, offset: sqlite3::sqlite3_int64) -> anyhow::Result<Vec<u8>> {
+        #[cfg(feature = "session")]
+        if let Some(view) = &self.state.view {
+            return view.contents().read(amount, offset);
+        }
+
+        let page_size = self.page_size as u64;
+        let size = self.size()? as u64;
+        let start = offset as u64;
+        let end = (start + amount as u64).min(size);
+        let mut data = Vec::with_capacity(end.saturating_sub(start) as usize);
+        let mut position = start;
+
+        while position < end {
+            let page = self.page(position / page_size)?;
+            let page_offset = (position % page_size) as usize;
+            let length = (page_size - page_offset as u64).min(end - position) as usize;
+            data.extend_from_slice(&page[page_offset..page_offset + length]);
+            position += length as u64;
+        }
+
+        Ok(data)
+    }
+
+    fn write(
+        &self,
+        data: Vec<u8>,
+        amount: raw::c_int,
+        offset: sqlite3::sqlite3_int64,
+    ) -> anyhow::Result<raw::c_int> {
+        #[cfg(feature = "session")]
+        if let Some(view) = &self.state.view {
+            self.check_recording()?;
+            return view.contents().write(data, amount, offset);
+        }
+
+        let page_size = self.page_size as u64;
+        let amount = (amount as usize).min(data.len());
+        let file_size = (self.size()? as u64).max(offset as u64 + amount as u64);
+        let mut written = 0;
+
+        while written < amount {
+            let position = offset as u64 + written as u64;
+            let page_no = position / page_size;
+            let page_offset = (position % page_size) as usize;
+            let length = (self.page_size - page_offset).min(amount - written);
+
+            let mut page = if length == self.page_size {
+                vec![0; self.page_size]
+            } else {
+                self.page(page_no)?
+            };
+            page[page_offset..page_offset + length]
+                .copy_from_slice(&data[written..written + length]);
+            self.append(Block::page(page_no, file_size, page))?;
+            written += length;
+        }
+
+        Ok(amount as raw::c_int)
+    }
+
+    fn truncate(&self, length: sqlite3::sqlite3_int64) -> anyhow::Result<()> {
+        #[cfg(feature = "session")]
+        if let Some(view) = &self.state.view {
+            self.check_recording()?;
+            return view.contents().truncate(length);
+        }
+
+        if self.size()? != length {
+            self.append(Block::truncate(length as u64))?;
+        }
+        Ok(())
+    }
+
+    fn sync(&self, flags: raw::c_int) -> anyhow::Result<()> {
+        let durability = if flags & 0x0f == sqlite3::SQLITE_SYNC_FULL {
+            self.durability
+        } else {
+            self.synchronous.get()
+        };
+        let feed = self.state.feed.borrow();
+        if durability >= Durability::Flushed {
+            feed.flush()?;
+        }
+        let replicas = self.state.replicas.borrow().clone();
+        // Files nothing replicates (like journals) only go as far as the disk.
+        if let (Durability::Replicated { quorum }, false) = (durability, replicas.is_empty()) {
+            // What's been appended stays appended; the transaction just isn't reported as
+            // committed until enough replicas have it.
+            feed.acknowledge(replicas, quorum, self.replica_timeout)
+                .map_err(|error| {
+                    anyhow::Error::new(sqlite3::Error::new(sqlite3::SQLITE_IOERR_FSYNC))
+                        .context(format!("Could not replicate {:?}: {:#}", self.name, error))
+                })?;
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> anyhow::Result<sqlite3::sqlite3_int64> {
+        #[cfg(feature = "session")]
+        if let Some(view) = &self.state.view {
+            return view.contents().size();
+        }
+
+        if let Some(prepared) = self.batch.borrow().iter().flatten().last() {
+            return Ok(prepared.block.file_size as _);
+        }
+        if self.state.feed.borrow().is_empty() {
+            return Ok(0);
+        }
+
+        let latest = self.state.feed.borrow().len() - 1;
+        Ok(self.block(latest)?.file_size as _)
+    }
+
+    fn lock(&self, flag: LockFlag) -> anyhow::Result<()> {
+        // Turned away before anything's written, so there's nothing to roll back.
+        #[cfg(feature = "session")]
+        if flag >= LockFlag::Reserved && self.state.view.is_some() {
+            self.check_recording()?;
+        }
+
+        let mut held = self.lock.get();
+        let result = self.state.locks.borrow_mut().lock(&mut held, flag);
+        self.lock.set(held);
+
+        if result? {
+            if let Err(error) = self.reload() {
+                self.unlock(LockFlag::None)?;
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    fn unlock(&self, flag: LockFlag) -> anyhow::Result<()> {
+        // Whatever was committed is in by the time the writer lets go; anything its connection
+        // queued while only reading didn't write to the file. Other connections' records are
+        // left for them.
+        if flag <= LockFlag::Shared && self.lock.get() > LockFlag::Shared {
+            self.append_commits()?;
+            self.append_index()?;
+        } else if let (Some(connection), Ok(mut commits)) =
+            (self.connection.get(), self.state.commits.lock())
+        {
+            commits.retain(|(committer, _)| *committer != connection);
+        }
+
+        let mut held = self.lock.get();
+        let result = self.state.locks.borrow_mut().unlock(&mut held, flag);
+        self.lock.set(held);
+        result
+    }
+
+    fn check_reserved_lock(&self) -> anyhow::Result<bool> {
+        self.state.locks.borrow().is_reserved()
+    }
+
+    fn file_control(&self, control: FileControl) -> anyhow::Result<Option<String>> {
+        match control {
+            FileControl::BeginAtomicWrite => *self.batch.borrow_mut() = Some(Vec::default()),
+            FileControl::CommitAtomicWrite => self.commit_batch()?,
+            FileControl::RollbackAtomicWrite => drop(self.batch.borrow_mut().take()),
+            FileControl::Pragma { name, value } => {
+                return self.pragma(&name, value.as_deref()).map(Some)
+            }
+            _ => return Err(FileControl::not_found()),
+        }
+        Ok(None)
+    }
+
+    fn sector_size(&self) -> raw::c_int {
+        self.page_size as _
+    }
+
+    fn device_characteristics(&self) -> Vec<raw::c_int> {
+        // The copy of a file kept as changesets is written to as SQLite goes.
+        #[cfg(feature = "session")]
+        if self.state.view.is_some() {
+            return Vec::new();
+        }
+        // Lets SQLite write transactions as a single batch rather than through a journal. The
+        // SQLite `build.rs` builds always can, but one loading the library as an extension may
+        // not have been built with `SQLITE_ENABLE_BATCH_ATOMIC_WRITE`.
+        if api::compileoption_used(BATCH_ATOMIC_WRITE) {
+            vec![sqlite3::SQLITE_IOCAP_BATCH_ATOMIC]
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn as_any(&self) -> Option<&dyn std::any::Any> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod test;
@@ -0,0 +1,154 @@
+// An audit log of committed transactions, kept in the same Hypercore as the pages they wrote so
+// that it replicates along with them. SQLite's hooks note what each transaction touched; the
+// record is queued on commit and appended once the writer lets go of the file (which is when its
+// pages are all in), so it always comes after the pages it describes.
+use rusqlite::{Action, Connection};
+use std::collections::BTreeSet;
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Hands out an identifier to every audited connection.
+static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// A transaction as it's recorded in a file's Hypercore.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Transaction {
+    /// The position of its block in the feed, which identifies it in every replica.
+    pub id: u64,
+    /// Milliseconds since the UNIX epoch at which it was recorded.
+    pub timestamp: u64,
+    /// The tables with rows inserted, updated or deleted; `WITHOUT ROWID` tables aren't noticed.
+    pub tables: BTreeSet<String>,
+    /// What the application said about the transaction through `Audit::set_message`.
+    pub message: Option<String>,
+}
+
+impl Transaction {
+    /// Encodes what goes into the payload of a transaction block; the rest is in its header.
+    pub(super) fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(self.tables.len() as u16).to_le_bytes());
+        for table in &self.tables {
+            bytes.extend_from_slice(&(table.len() as u16).to_le_bytes());
+            bytes.extend_from_slice(table.as_bytes());
+        }
+        if let Some(message) = &self.message {
+            bytes.extend_from_slice(message.as_bytes());
+        }
+        bytes
+    }
+
+    pub(super) fn decode(id: u64, timestamp: u64, bytes: &[u8]) -> anyhow::Result<Self> {
+        let mut rest = bytes;
+        let read_u16 = |bytes: &[u8]| u16::from_le_bytes(bytes.try_into().expect("two bytes"));
+
+        let mut tables = BTreeSet::new();
+        for _ in 0..read_u16(take(&mut rest, 2)?) {
+            let length = read_u16(take(&mut rest, 2)?) as usize;
+            tables.insert(String::from_utf8(take(&mut rest, length)?.to_vec())?);
+        }
+
+        Ok(Self {
+            id,
+            timestamp,
+            tables,
+            message: if rest.is_empty() {
+                None
+            } else {
+                Some(String::from_utf8(rest.to_vec())?)
+            },
+        })
+    }
+}
+
+fn take<'a>(rest: &mut &'a [u8], length: usize) -> anyhow::Result<&'a [u8]> {
+    if rest.len() < length {
+        return Err(anyhow::anyhow!("The transaction record is truncated"));
+    }
+    let (taken, remaining) = rest.split_at(length);
+    *rest = remaining;
+    Ok(taken)
+}
+
+/// Transactions committed on a file that are waiting for their pages to be written, tagged with
+/// the connection that committed them.
+pub(super) type Commits = Arc<Mutex<Vec<(u64, Transaction)>>>;
+
+/// What an audited connection has done in its current transaction.
+#[derive(Default)]
+struct Ongoing {
+    tables: BTreeSet<String>,
+    message: Option<String>,
+}
+
+/// Records every transaction committed through a connection; see `Vfs::audit`.
+///
+/// The hooks stay on the connection after this is dropped; only the message can't be set then.
+pub struct Audit {
+    /// Tags what the connection queues in `Commits`.
+    connection: u64,
+    ongoing: Arc<Mutex<Ongoing>>,
+}
+
+impl Audit {
+    /// Installs hooks on `conn` that queue a record of each transaction it commits in `commits`;
+    /// any hooks set on it before are replaced.
+    pub(super) fn install(conn: &Connection, commits: Commits) -> Self {
+        let id = CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+        let ongoing = Arc::new(Mutex::new(Ongoing::default()));
+
+        let touched = Arc::clone(&ongoing);
+        conn.update_hook(Some(move |_: Action, _: &str, table: &str, _: i64| {
+            if let Ok(mut ongoing) = touched.lock() {
+                ongoing.tables.insert(table.to_string());
+            }
+        }));
+
+        let (committed, queued) = (Arc::clone(&ongoing), Arc::clone(&commits));
+        conn.commit_hook(Some(move || {
+            if let (Ok(mut ongoing), Ok(mut commits)) = (committed.lock(), queued.lock()) {
+                let mut transaction = Transaction {
+                    tables: std::mem::take(&mut ongoing.tables),
+                    message: ongoing.message.take(),
+                    ..Transaction::default()
+                };
+
+                // A commit that failed with `SQLITE_BUSY` is tried again later, by which point
+                // what it touched has been taken out of `ongoing`.
+                if let Some(index) = commits.iter().position(|(connection, _)| *connection == id) {
+                    let (_, earlier) = commits.remove(index);
+                    transaction.tables.extend(earlier.tables);
+                    transaction.message = transaction.message.or(earlier.message);
+                }
+                commits.push((id, transaction));
+            }
+            false
+        }));
+
+        let rolled_back = Arc::clone(&ongoing);
+        conn.rollback_hook(Some(move || {
+            if let (Ok(mut ongoing), Ok(mut commits)) = (rolled_back.lock(), commits.lock()) {
+                *ongoing = Ongoing::default();
+                commits.retain(|(connection, _)| *connection != id);
+            }
+        }));
+
+        Self {
+            connection: id,
+            ongoing,
+        }
+    }
+
+    /// The identifier the connection's transactions are queued under.
+    pub(super) fn connection(&self) -> u64 {
+        self.connection
+    }
+
+    /// Attaches `message` to the record of the transaction that's committed next.
+    pub fn set_message(&self, message: impl Into<String>) {
+        if let Ok(mut ongoing) = self.ongoing.lock() {
+            ongoing.message = Some(message.into());
+        }
+    }
+}
@@ -0,0 +1,66 @@
+// Scalar functions that give SQL the same view of the Hypercore behind a database as the Rust API,
+// for when the library is only around as a loadable extension. They take no arguments and always
+// look at the main database of the connection they're called on.
+use super::HyperFile;
+use crate::hyper::encode_hex;
+use rusqlite::ffi as sqlite3;
+use rusqlite::functions::{Context, FunctionFlags};
+use rusqlite::{Connection, ToSql};
+
+/// The handle of the connection a function was registered on.
+#[derive(Clone, Copy)]
+struct Database(*mut sqlite3::sqlite3);
+
+// SAFETY: SQLite serializes calls on a connection, and functions are only called on the one they
+// were registered on.
+unsafe impl Send for Database {}
+
+/// Makes `hyper_key()`, `hyper_version()`, `hyper_share()` and `hyper_verify()` available on
+/// `conn`.
+pub fn register_functions(conn: &Connection) -> anyhow::Result<()> {
+    // SAFETY: The handle is only used by the functions below, which SQLite drops before the
+    // connection is closed.
+    let db = Database(unsafe { conn.handle() });
+
+    scalar(conn, db, "hyper_key", |file| {
+        Ok(encode_hex(&file.state.feed.borrow().key()))
+    })?;
+    scalar(conn, db, "hyper_version", |file| {
+        Ok(file.state.feed.borrow().len() as i64)
+    })?;
+    scalar(conn, db, "hyper_share", |file| {
+        Ok(format!(
+            "hyper://{}",
+            encode_hex(&file.state.feed.borrow().key())
+        ))
+    })?;
+    scalar(conn, db, "hyper_verify", HyperFile::verify)?;
+    Ok(())
+}
+
+fn scalar<T: ToSql + 'static>(
+    conn: &Connection,
+    db: Database,
+    name: &str,
+    f: fn(&HyperFile) -> anyhow::Result<T>,
+) -> anyhow::Result<()> {
+    conn.create_scalar_function(name, 0, FunctionFlags::SQLITE_UTF8, move |_: &Context| {
+        // SAFETY: The function is being called on `db`, so it's open.
+        unsafe { HyperFile::with_main(db.0, f) }
+            .map_err(|error| rusqlite::Error::UserFunctionError(error.into()))
+    })?;
+    Ok(())
+}
+
+impl HyperFile {
+    /// Checks every block that's stored locally against the feed's signatures.
+    fn verify(&self) -> anyhow::Result<bool> {
+        let mut feed = self.state.feed.borrow_mut();
+        for seq in 0..feed.len() {
+            if feed.verify(seq)? == Some(false) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
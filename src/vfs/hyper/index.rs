@@ -0,0 +1,443 @@
+// Where the latest block of every page is, so reads don't have to scan the feed back for it. For
+// files on disk it's kept next to the Hypercore as the key of the feed followed by one record per
+// block, each at a fixed place, so processes indexing the same blocks write the same bytes. It's
+// never trusted: whatever it points at is checked, and it's rebuilt from the feed when it's off.
+//
+// Replicas haven't got the blocks to build it from, so writers also append an index block to the
+// feed at the end of every transaction. Each covers a span of the feed up to itself: the blocks
+// since the index block before it, merged with the spans of earlier index blocks the way a binary
+// counter carries, so the latest index block and the few that came before its span cover the
+// whole feed. Replicas fetch those and then only the pages they read.
+use super::{File as _, HyperFile};
+use crate::hyper::{Block, BlockKind, Feed};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const HEADER_LENGTH: u64 = 32;
+/// A kind (`BlockKind::Page`, `BlockKind::Truncate` or 0 for anything else) and a page number or
+/// file size.
+const RECORD_LENGTH: usize = 9;
+
+/// Where the latest contents of a page are, as far as the index goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Latest {
+    /// In the page block at `seq`; anything from `surviving_length` on has been truncated since.
+    Page { seq: u64, surviving_length: usize },
+    /// Nowhere; the page was never written, or it's been truncated away since.
+    Missing,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Record {
+    Page(u64),
+    Truncate(u64),
+    Other,
+}
+
+impl Record {
+    fn of(block: &Block) -> Self {
+        match block.kind {
+            BlockKind::Page => Self::Page(block.page_no),
+            BlockKind::Truncate => Self::Truncate(block.file_size),
+            _ => Self::Other,
+        }
+    }
+
+    fn encode(self) -> [u8; RECORD_LENGTH] {
+        let (kind, value) = match self {
+            Self::Page(page_no) => (BlockKind::Page as u8, page_no),
+            Self::Truncate(file_size) => (BlockKind::Truncate as u8, file_size),
+            Self::Other => (0, 0),
+        };
+        let mut bytes = [0; RECORD_LENGTH];
+        bytes[0] = kind;
+        bytes[1..].copy_from_slice(&value.to_le_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Self {
+        let mut value = [0; 8];
+        value.copy_from_slice(&bytes[1..RECORD_LENGTH]);
+        let value = u64::from_le_bytes(value);
+        match bytes[0] {
+            kind if kind == BlockKind::Page as u8 => Self::Page(value),
+            kind if kind == BlockKind::Truncate as u8 => Self::Truncate(value),
+            _ => Self::Other,
+        }
+    }
+}
+
+/// What an index block says about the span of the feed it covers.
+struct Span {
+    /// The first block covered; the span runs up to the index block itself.
+    from: u64,
+    /// How many index blocks have been merged into this one.
+    weight: u64,
+    /// The page and truncation records of the span, by sequence number. Only the latest block of
+    /// each page is in there; the others are superseded within the span anyway.
+    records: Vec<(u64, Record)>,
+}
+
+impl Span {
+    /// Takes `later`, which covers the blocks right after `self`, into it.
+    fn merge(self, later: Self) -> Self {
+        let mut pages = HashMap::new();
+        let mut records = Vec::new();
+        for (seq, record) in self.records.into_iter().chain(later.records) {
+            match record {
+                Record::Page(page_no) => {
+                    pages.insert(page_no, seq);
+                }
+                Record::Truncate(_) => records.push((seq, record)),
+                Record::Other => {}
+            }
+        }
+        records.extend(
+            pages
+                .into_iter()
+                .map(|(page_no, seq)| (seq, Record::Page(page_no))),
+        );
+        records.sort_unstable_by_key(|(seq, _)| *seq);
+
+        Self {
+            from: self.from,
+            weight: self.weight + later.weight,
+            records,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(16 + self.records.len() * (8 + RECORD_LENGTH));
+        bytes.extend_from_slice(&self.from.to_le_bytes());
+        bytes.extend_from_slice(&self.weight.to_le_bytes());
+        for (seq, record) in &self.records {
+            bytes.extend_from_slice(&seq.to_le_bytes());
+            bytes.extend_from_slice(&record.encode());
+        }
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> anyhow::Result<Self> {
+        let read_u64 = |bytes: &[u8]| {
+            let mut value = [0; 8];
+            value.copy_from_slice(&bytes[..8]);
+            u64::from_le_bytes(value)
+        };
+        if bytes.len() < 16 || !(bytes.len() - 16).is_multiple_of(8 + RECORD_LENGTH) {
+            return Err(anyhow::anyhow!(
+                "An index block can't be {} bytes long",
+                bytes.len()
+            ));
+        }
+
+        Ok(Self {
+            from: read_u64(&bytes[0..]),
+            weight: read_u64(&bytes[8..]),
+            records: bytes[16..]
+                .chunks_exact(8 + RECORD_LENGTH)
+                .map(|entry| (read_u64(entry), Record::decode(&entry[8..])))
+                .collect(),
+        })
+    }
+}
+
+/// Maps page numbers to the block holding their latest contents.
+pub struct PageIndex {
+    file: Option<File>,
+    key: [u8; 32],
+    /// How many blocks from the start of the feed have been indexed.
+    covered: u64,
+    pages: HashMap<u64, u64>,
+    /// The sequence number and file size of every truncation, oldest first.
+    truncations: Vec<(u64, u64)>,
+    /// Whether every record came straight from the feed, rather than from the file.
+    trusted: bool,
+}
+
+impl PageIndex {
+    /// Picks up the index kept at `path` (if any), or builds it from `feed`.
+    pub fn open(path: Option<&Path>, feed: &mut Feed) -> anyhow::Result<Self> {
+        let file = match path {
+            Some(path) => Some(
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(path)?,
+            ),
+            None => None,
+        };
+        let mut index = Self {
+            file,
+            key: feed.key(),
+            covered: 0,
+            pages: HashMap::default(),
+            truncations: Vec::default(),
+            trusted: true,
+        };
+
+        let mut key = [0; HEADER_LENGTH as usize];
+        let stored = match &mut index.file {
+            Some(file) => file.read_exact(&mut key).is_ok() && key == index.key,
+            None => true,
+        };
+        if !stored {
+            index.reset(feed.key())?;
+        }
+        index.sync(feed)?;
+        Ok(index)
+    }
+
+    /// Indexes the blocks appended to `feed` since the last call (as far as they're stored
+    /// locally; replicas index the rest with `fetch`), and returns how many blocks from the start
+    /// of the feed are indexed.
+    pub fn sync(&mut self, feed: &mut Feed) -> anyhow::Result<u64> {
+        let length = feed.len();
+        if feed.key() != self.key || self.covered > length {
+            self.reset(feed.key())?;
+        }
+        if self.covered == length {
+            return Ok(self.covered);
+        }
+
+        // Other processes index what they append, too.
+        self.read(length)?;
+
+        let start = self.covered;
+        let mut records = Vec::new();
+        while self.covered < length {
+            let record = match feed.get(self.covered)? {
+                // Blocks that don't decode are left for reads to complain about.
+                Some(bytes) => {
+                    Block::decode(&bytes).map_or(Record::Other, |block| Record::of(&block))
+                }
+                // Only `PRAGMA hyper_compact` clears blocks of feeds written here...
+                None if feed.is_writable() => Record::Other,
+                // ...while replicas just haven't fetched them yet; see `fetch`.
+                None => break,
+            };
+            self.apply(self.covered, record);
+            records.push(record);
+            self.covered += 1;
+        }
+        self.write(start, &records)?;
+        Ok(self.covered)
+    }
+
+    /// Indexes the blocks of a replica past the ones stored locally, up to `length`, reading them
+    /// with `block` (which fetches them from peers); index blocks stand in for the spans they
+    /// cover, so only they and the blocks after the latest of them are read.
+    pub fn fetch(
+        &mut self,
+        length: u64,
+        mut block: impl FnMut(u64) -> anyhow::Result<Block>,
+    ) -> anyhow::Result<u64> {
+        let start = self.covered;
+        if start >= length {
+            return Ok(start);
+        }
+
+        let mut records = vec![Record::Other; (length - start) as usize];
+        let mut seq = length;
+        while seq > start {
+            seq -= 1;
+            let block = block(seq)?;
+            if block.kind != BlockKind::Index {
+                records[(seq - start) as usize] = Record::of(&block);
+                continue;
+            }
+
+            let span = Span::decode(&block.payload)
+                .map_err(|error| error.context(format!("Could not read index block {}", seq)))?;
+            for (at, record) in span.records {
+                if (start..seq).contains(&at) {
+                    records[(at - start) as usize] = record;
+                }
+            }
+            seq = span.from.min(seq);
+        }
+
+        for (offset, record) in records.iter().enumerate() {
+            self.apply(start + offset as u64, *record);
+        }
+        self.covered = length;
+        self.write(start, &records)?;
+        Ok(self.covered)
+    }
+
+    /// Indexes `blocks`, just appended to the feed from `seq` on.
+    pub fn appended(&mut self, seq: u64, blocks: &[&Block]) -> anyhow::Result<()> {
+        // Anything else is picked up by the next `sync`.
+        if seq != self.covered {
+            return Ok(());
+        }
+
+        let records: Vec<_> = blocks.iter().map(|block| Record::of(block)).collect();
+        for (offset, record) in records.iter().enumerate() {
+            self.apply(seq + offset as u64, *record);
+        }
+        self.covered += records.len() as u64;
+        self.write(seq, &records)
+    }
+
+    /// Throws the index away and builds it again from `feed`.
+    pub fn rebuild(&mut self, feed: &mut Feed) -> anyhow::Result<()> {
+        log::warn!(
+            "Rebuilding the page index of the feed {}",
+            crate::hyper::encode_hex(&feed.key())
+        );
+        self.reset(feed.key())?;
+        self.sync(feed)?;
+        Ok(())
+    }
+
+    /// Whether the index was built from the feed by this process, so it doesn't need checking.
+    pub fn is_trusted(&self) -> bool {
+        self.trusted
+    }
+
+    /// Finds where the latest contents of `page_no`, starting at `page_start` in the file, are
+    /// among the indexed blocks.
+    pub fn latest(&self, page_no: u64, page_start: u64, page_size: usize) -> Latest {
+        let seq = self.pages.get(&page_no).copied();
+        let mut surviving_length = page_size;
+        for &(at, file_size) in self.truncations.iter().rev() {
+            if seq.is_some_and(|seq| at < seq) {
+                break;
+            }
+            if file_size <= page_start {
+                return Latest::Missing;
+            }
+            surviving_length = surviving_length.min((file_size - page_start) as usize);
+        }
+
+        match seq {
+            Some(seq) => Latest::Page {
+                seq,
+                surviving_length,
+            },
+            None => Latest::Missing,
+        }
+    }
+
+    fn apply(&mut self, seq: u64, record: Record) {
+        match record {
+            Record::Page(page_no) => {
+                self.pages.insert(page_no, seq);
+            }
+            Record::Truncate(file_size) => self.truncations.push((seq, file_size)),
+            Record::Other => {}
+        }
+    }
+
+    fn reset(&mut self, key: [u8; 32]) -> anyhow::Result<()> {
+        self.key = key;
+        self.covered = 0;
+        self.pages.clear();
+        self.truncations.clear();
+        self.trusted = true;
+        if let Some(file) = &mut self.file {
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(&key)?;
+        }
+        Ok(())
+    }
+
+    /// Picks up the records stored past what's been indexed, up to `length`.
+    fn read(&mut self, length: u64) -> anyhow::Result<()> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+
+        let mut bytes = Vec::new();
+        file.seek(SeekFrom::Start(
+            HEADER_LENGTH + self.covered * RECORD_LENGTH as u64,
+        ))?;
+        Read::by_ref(file)
+            .take((length - self.covered) * RECORD_LENGTH as u64)
+            .read_to_end(&mut bytes)?;
+        // A record cut short is written again along with the ones after it.
+        for record in bytes.chunks_exact(RECORD_LENGTH) {
+            self.trusted = false;
+            self.apply(self.covered, Record::decode(record));
+            self.covered += 1;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, seq: u64, records: &[Record]) -> anyhow::Result<()> {
+        let file = match &mut self.file {
+            Some(file) if !records.is_empty() => file,
+            _ => return Ok(()),
+        };
+
+        let bytes: Vec<u8> = records.iter().flat_map(|record| record.encode()).collect();
+        file.seek(SeekFrom::Start(HEADER_LENGTH + seq * RECORD_LENGTH as u64))?;
+        file.write_all(&bytes)?;
+        Ok(())
+    }
+}
+
+impl HyperFile {
+    /// Appends an index block covering the blocks appended since the last one, merged with the
+    /// spans of earlier index blocks of the same weight; there's nothing to append if no blocks
+    /// were.
+    pub(super) fn append_index(&self) -> anyhow::Result<()> {
+        #[cfg(feature = "session")]
+        if self.state.view.is_some() {
+            return Ok(());
+        }
+        let read = |seq: u64| -> anyhow::Result<Option<Block>> {
+            let bytes = self.state.feed.borrow_mut().get(seq)?;
+            Ok(bytes.and_then(|bytes| Block::decode(&bytes).ok()))
+        };
+
+        // Blocks cleared by `PRAGMA hyper_compact` have all been appended again since.
+        let mut records = Vec::new();
+        let mut seq = self.state.feed.borrow().len();
+        let mut earlier = None;
+        while seq > 0 {
+            seq -= 1;
+            match read(seq)? {
+                Some(block) if block.kind == BlockKind::Index => {
+                    earlier = Some(Span::decode(&block.payload)?);
+                    seq += 1;
+                    break;
+                }
+                Some(block) => records.push((seq, Record::of(&block))),
+                None => {}
+            }
+        }
+        if records.is_empty() {
+            return Ok(());
+        }
+        records.reverse();
+
+        let mut span = Span {
+            from: seq,
+            weight: 1,
+            records,
+        };
+        while let Some(previous) = earlier.take() {
+            if previous.weight > span.weight {
+                break;
+            }
+            let before = previous.from.checked_sub(1);
+            span = previous.merge(span);
+            earlier = match before.map(read).transpose()?.flatten() {
+                Some(block) if block.kind == BlockKind::Index => {
+                    Some(Span::decode(&block.payload)?)
+                }
+                _ => None,
+            };
+        }
+
+        let file_size = self.size()? as u64;
+        self.append(Block::index(file_size, span.encode()))
+    }
+}
@@ -0,0 +1,148 @@
+// Files other than main databases (journals, and temporary files if the `Instance` hands them
+// over) only matter to this node, for as long as it takes to finish or roll back a transaction, so
+// they're kept out of the Hypercores: as plain files next to them on disk, or in memory.
+use super::super::{sqlite3, File, FileControl, LockFlag, MemoryFile};
+use crate::hyper::Storage;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::raw;
+use std::rc::Rc;
+
+/// The files kept out of the Hypercores by a `Vfs`.
+#[derive(Default)]
+pub(super) struct LocalFiles {
+    /// The contents of the files kept in memory, by name.
+    memory: RefCell<HashMap<String, Rc<RefCell<Vec<u8>>>>>,
+}
+
+impl LocalFiles {
+    pub(super) fn open(
+        &self,
+        storage: &Storage,
+        path: &str,
+        create: bool,
+    ) -> anyhow::Result<Rc<RefCell<dyn File>>> {
+        match storage.file_of(path) {
+            Some(location) => {
+                let file = std::fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(create)
+                    .truncate(false)
+                    .open(location)?;
+                Ok(Rc::new(RefCell::new(DiskFile {
+                    file: RefCell::new(file),
+                })))
+            }
+            None => {
+                let mut memory = self.memory.borrow_mut();
+                let data = match memory.get(path) {
+                    Some(data) => Rc::clone(data),
+                    None if create => Rc::clone(memory.entry(path.to_string()).or_default()),
+                    None => return Err(anyhow::anyhow!("There's no {:?} to open", path)),
+                };
+                Ok(Rc::new(RefCell::new(MemoryFile::shared(data))))
+            }
+        }
+    }
+
+    pub(super) fn exists(&self, storage: &Storage, path: &str) -> bool {
+        match storage.file_of(path) {
+            Some(location) => location.is_file(),
+            None => self.memory.borrow().contains_key(path),
+        }
+    }
+
+    /// Removes the file at `path`, returning whether there was one.
+    pub(super) fn remove(&self, storage: &Storage, path: &str) -> anyhow::Result<bool> {
+        match storage.file_of(path) {
+            Some(location) if location.is_file() => {
+                std::fs::remove_file(location)?;
+                Ok(true)
+            }
+            Some(_) => Ok(false),
+            None => Ok(self.memory.borrow_mut().remove(path).is_some()),
+        }
+    }
+}
+
+/// A plain file on disk.
+struct DiskFile {
+    file: RefCell<std::fs::File>,
+}
+
+impl File for DiskFile {
+    fn close(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn read(&self, amount: raw::c_int, offset: sqlite3::sqlite3_int64) -> anyhow::Result<Vec<u8>> {
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(offset as u64))?;
+        let mut data = Vec::with_capacity(amount as usize);
+        Read::by_ref(&mut *file)
+            .take(amount as u64)
+            .read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn write(
+        &self,
+        data: Vec<u8>,
+        amount: raw::c_int,
+        offset: sqlite3::sqlite3_int64,
+    ) -> anyhow::Result<raw::c_int> {
+        let mut file = self.file.borrow_mut();
+        let amount = (amount as usize).min(data.len());
+        file.seek(SeekFrom::Start(offset as u64))?;
+        file.write_all(&data[..amount])?;
+        Ok(amount as raw::c_int)
+    }
+
+    fn truncate(&self, length: sqlite3::sqlite3_int64) -> anyhow::Result<()> {
+        Ok(self.file.borrow().set_len(length as u64)?)
+    }
+
+    fn sync(&self, flags: raw::c_int) -> anyhow::Result<()> {
+        let file = self.file.borrow();
+        if flags & sqlite3::SQLITE_SYNC_DATAONLY != 0 {
+            file.sync_data()
+        } else {
+            file.sync_all()
+        }
+        .map_err(|error| {
+            anyhow::Error::new(sqlite3::Error::new(sqlite3::SQLITE_IOERR_FSYNC))
+                .context(format!("Could not sync: {}", error))
+        })
+    }
+
+    fn size(&self) -> anyhow::Result<sqlite3::sqlite3_int64> {
+        Ok(self.file.borrow().metadata()?.len() as _)
+    }
+
+    // Whoever uses a journal holds a lock on its database.
+    fn lock(&self, _flag: LockFlag) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn unlock(&self, _flag: LockFlag) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn check_reserved_lock(&self) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+
+    fn file_control(&self, _control: FileControl) -> anyhow::Result<Option<String>> {
+        Err(FileControl::not_found())
+    }
+
+    fn sector_size(&self) -> raw::c_int {
+        512
+    }
+
+    fn device_characteristics(&self) -> Vec<raw::c_int> {
+        vec![]
+    }
+}
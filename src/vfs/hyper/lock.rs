@@ -0,0 +1,284 @@
+// SQLite's five-level locking (see https://sqlite.org/lockingv3.html), kept per file. Handles in
+// the same process are arbitrated through the `LockTable` shared by them; other processes using
+// the same storage directory are arbitrated through byte-range locks on a lock file, laid out
+// like the ones SQLite's own Unix VFS takes on database files.
+use super::{sqlite3, LockFlag};
+use std::path::Path;
+
+fn busy() -> anyhow::Error {
+    anyhow::Error::new(sqlite3::Error::new(sqlite3::SQLITE_BUSY))
+}
+
+/// Tracks the locks held on a file by every handle in this process.
+#[derive(Default)]
+pub struct LockTable {
+    /// The number of handles holding at least a `SHARED` lock.
+    readers: usize,
+    /// The lock held by the one handle that's at `RESERVED` or above, if any.
+    writer: Option<LockFlag>,
+    file: Option<LockFile>,
+}
+
+impl LockTable {
+    /// Creates a table whose locks are also taken on the lock file at `path`, if provided.
+    pub fn new(path: Option<&Path>) -> anyhow::Result<Self> {
+        Ok(Self {
+            file: path.map(LockFile::open).transpose()?,
+            ..Self::default()
+        })
+    }
+
+    /// Raises the lock `held` by a handle to `requested`, failing with `SQLITE_BUSY` if another
+    /// handle (or process) is in the way.
+    ///
+    /// Returns `true` when this took the first `SHARED` lock in the process, meaning that the
+    /// file could have been changed by another process (or a peer) since it was last read.
+    pub fn lock(&mut self, held: &mut LockFlag, requested: LockFlag) -> anyhow::Result<bool> {
+        if *held >= requested {
+            return Ok(false);
+        }
+
+        match requested {
+            LockFlag::None => Ok(false),
+            LockFlag::Shared => {
+                if matches!(self.writer, Some(LockFlag::Pending | LockFlag::Exclusive)) {
+                    return Err(busy());
+                }
+
+                let first = self.readers == 0;
+                if first {
+                    if let Some(file) = &self.file {
+                        file.share()?;
+                    }
+                }
+
+                self.readers += 1;
+                *held = LockFlag::Shared;
+                Ok(first)
+            }
+            LockFlag::Reserved => {
+                if self.writer.is_some() {
+                    return Err(busy());
+                }
+                if let Some(file) = &self.file {
+                    file.reserve()?;
+                }
+
+                self.writer = Some(LockFlag::Reserved);
+                *held = LockFlag::Reserved;
+                Ok(false)
+            }
+            LockFlag::Pending => Err(anyhow::anyhow!(
+                "A PENDING lock can only be taken on the way to an EXCLUSIVE one"
+            )),
+            LockFlag::Exclusive => {
+                // A handle at SHARED can go straight to EXCLUSIVE when rolling back a hot journal.
+                if *held < LockFlag::Reserved && self.writer.is_some() {
+                    return Err(busy());
+                }
+
+                if *held < LockFlag::Pending {
+                    if let Some(file) = &self.file {
+                        file.pend()?;
+                    }
+                    self.writer = Some(LockFlag::Pending);
+                    *held = LockFlag::Pending;
+                }
+
+                // New readers are kept out from here on, but the ones that are in have to leave.
+                if self.readers > 1 {
+                    return Err(busy());
+                }
+                if let Some(file) = &self.file {
+                    file.exclude()?;
+                }
+
+                self.writer = Some(LockFlag::Exclusive);
+                *held = LockFlag::Exclusive;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Lowers the lock `held` by a handle to `requested`, which is either `SHARED` or `NONE`.
+    pub fn unlock(&mut self, held: &mut LockFlag, requested: LockFlag) -> anyhow::Result<()> {
+        if *held <= requested {
+            return Ok(());
+        }
+
+        if *held > LockFlag::Shared {
+            self.writer = None;
+            if let Some(file) = &self.file {
+                file.downgrade()?;
+            }
+            *held = LockFlag::Shared;
+        }
+
+        if requested == LockFlag::None {
+            self.readers -= 1;
+            if self.readers == 0 {
+                if let Some(file) = &self.file {
+                    file.release()?;
+                }
+            }
+            *held = LockFlag::None;
+        }
+
+        Ok(())
+    }
+
+    /// Checks if any handle, in this process or another, holds a `RESERVED` lock or above.
+    pub fn is_reserved(&self) -> anyhow::Result<bool> {
+        match (&self.writer, &self.file) {
+            (Some(_), _) => Ok(true),
+            (None, Some(file)) => file.is_reserved(),
+            (None, None) => Ok(false),
+        }
+    }
+}
+
+/// Byte-range locks on a file, standing in for the locks of every handle in this process.
+///
+/// On Linux, these are open file description locks so that two `Vfs` in one process exclude each
+/// other like separate processes would.
+#[cfg(unix)]
+struct LockFile {
+    file: std::fs::File,
+}
+
+#[cfg(unix)]
+impl LockFile {
+    const PENDING_BYTE: libc::off_t = 0;
+    const RESERVED_BYTE: libc::off_t = 1;
+    const SHARED_BYTE: libc::off_t = 2;
+
+    #[cfg(target_os = "linux")]
+    const SET_LOCK: libc::c_int = libc::F_OFD_SETLK;
+    #[cfg(target_os = "linux")]
+    const GET_LOCK: libc::c_int = libc::F_OFD_GETLK;
+    #[cfg(not(target_os = "linux"))]
+    const SET_LOCK: libc::c_int = libc::F_SETLK;
+    #[cfg(not(target_os = "linux"))]
+    const GET_LOCK: libc::c_int = libc::F_GETLK;
+
+    fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        Ok(Self { file })
+    }
+
+    fn region(kind: libc::c_int, byte: libc::off_t) -> libc::flock {
+        // SAFETY: `flock` is plain old data; the fields that matter are set below.
+        let mut region: libc::flock = unsafe { std::mem::zeroed() };
+        region.l_type = kind as _;
+        region.l_whence = libc::SEEK_SET as _;
+        region.l_start = byte;
+        region.l_len = 1;
+        region
+    }
+
+    /// Sets the lock on `byte`, failing with `SQLITE_BUSY` if another process holds a
+    /// conflicting one.
+    fn set(&self, kind: libc::c_int, byte: libc::off_t) -> anyhow::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let mut region = Self::region(kind, byte);
+        // SAFETY: The descriptor is owned by `self.file` and `region` outlives the call.
+        let result = unsafe { libc::fcntl(self.file.as_raw_fd(), Self::SET_LOCK, &mut region) };
+        if result == 0 {
+            return Ok(());
+        }
+
+        let error = std::io::Error::last_os_error();
+        match error.raw_os_error() {
+            Some(libc::EAGAIN) | Some(libc::EACCES) => Err(busy()),
+            _ => Err(error.into()),
+        }
+    }
+
+    fn share(&self) -> anyhow::Result<()> {
+        self.set(libc::F_RDLCK, Self::PENDING_BYTE)?;
+        let shared = self.set(libc::F_RDLCK, Self::SHARED_BYTE);
+        self.set(libc::F_UNLCK, Self::PENDING_BYTE)?;
+        shared
+    }
+
+    fn reserve(&self) -> anyhow::Result<()> {
+        self.set(libc::F_WRLCK, Self::RESERVED_BYTE)
+    }
+
+    fn pend(&self) -> anyhow::Result<()> {
+        self.set(libc::F_WRLCK, Self::PENDING_BYTE)
+    }
+
+    fn exclude(&self) -> anyhow::Result<()> {
+        self.set(libc::F_WRLCK, Self::SHARED_BYTE)
+    }
+
+    fn downgrade(&self) -> anyhow::Result<()> {
+        self.set(libc::F_RDLCK, Self::SHARED_BYTE)?;
+        self.set(libc::F_UNLCK, Self::PENDING_BYTE)?;
+        self.set(libc::F_UNLCK, Self::RESERVED_BYTE)
+    }
+
+    fn release(&self) -> anyhow::Result<()> {
+        self.set(libc::F_UNLCK, Self::SHARED_BYTE)
+    }
+
+    fn is_reserved(&self) -> anyhow::Result<bool> {
+        use std::os::unix::io::AsRawFd;
+
+        let mut region = Self::region(libc::F_WRLCK, Self::RESERVED_BYTE);
+        // SAFETY: The descriptor is owned by `self.file` and `region` outlives the call.
+        let result = unsafe { libc::fcntl(self.file.as_raw_fd(), Self::GET_LOCK, &mut region) };
+        if result == 0 {
+            Ok(region.l_type != libc::F_UNLCK as _)
+        } else {
+            Err(std::io::Error::last_os_error().into())
+        }
+    }
+}
+
+/// Without byte-range locks, only handles in this process are kept from stepping on each other.
+#[cfg(not(unix))]
+struct LockFile;
+
+#[cfg(not(unix))]
+impl LockFile {
+    fn open(_path: &Path) -> anyhow::Result<Self> {
+        log::warn!("Locking across processes isn't supported on this platform.");
+        Ok(Self)
+    }
+
+    fn share(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn reserve(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn pend(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn exclude(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn downgrade(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn release(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn is_reserved(&self) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+}
@@ -0,0 +1,244 @@
+// The `hyper_log` table lists every block in the Hypercore behind the main database of a
+// connection, for looking into how a database got to where it is (and whether its replicas agree)
+// with plain SQL. It's eponymous, so there's nothing to create: `SELECT * FROM hyper_log`.
+//
+// Rows are read (and their blocks checked) one at a time as the query steps through them, and
+// only over the range of `seq` the query's constraints leave, so looking up a few blocks doesn't
+// go through the whole feed.
+use super::{Block, BlockKind, HyperFile};
+use rusqlite::ffi as sqlite3;
+use rusqlite::vtab::{
+    eponymous_only_module, Context, IndexConstraintOp, IndexInfo, VTab, VTabConnection, VTabCursor,
+    Values,
+};
+use rusqlite::Connection;
+use std::os::raw::c_int;
+
+/// Makes the `hyper_log` table available on `conn`.
+pub fn register_log_table(conn: &Connection) -> anyhow::Result<()> {
+    conn.create_module("hyper_log", eponymous_only_module::<LogTable>(), None)?;
+    Ok(())
+}
+
+/// A row of `hyper_log`; everything but `seq` is missing for blocks that aren't stored locally.
+struct Entry {
+    seq: u64,
+    length: Option<usize>,
+    block: Option<Block>,
+    signature_ok: Option<bool>,
+}
+
+// The constraints on `seq` that `best_index` hands to `filter`, as bits of `idx_num`; their
+// values come in the same order.
+const SEQ_EQ: c_int = 1;
+const SEQ_GT: c_int = 2;
+const SEQ_GE: c_int = 4;
+const SEQ_LT: c_int = 8;
+const SEQ_LE: c_int = 16;
+
+impl HyperFile {
+    /// The row of `hyper_log` for the block at `seq`.
+    fn log_entry(&self, seq: u64) -> anyhow::Result<Entry> {
+        let mut feed = self.state.feed.borrow_mut();
+        let bytes = feed.get(seq)?;
+        Ok(Entry {
+            seq,
+            length: bytes.as_ref().map(Vec::len),
+            block: bytes.and_then(|bytes| Block::decode(&bytes).ok()),
+            signature_ok: feed.verify(seq)?,
+        })
+    }
+}
+
+#[repr(C)]
+struct LogTable {
+    base: sqlite3::sqlite3_vtab,
+    db: *mut sqlite3::sqlite3,
+}
+
+impl LogTable {
+    /// The number of blocks in the log of the main database of the connection this table
+    /// belongs to.
+    fn length(&self) -> anyhow::Result<u64> {
+        // SAFETY: `db` is the connection that's running the query.
+        unsafe { HyperFile::with_main(self.db, |file| Ok(file.state.feed.borrow().len())) }
+    }
+
+    fn entry(&self, seq: u64) -> anyhow::Result<Entry> {
+        // SAFETY: As above.
+        unsafe { HyperFile::with_main(self.db, |file| file.log_entry(seq)) }
+    }
+}
+
+fn module_error(error: anyhow::Error) -> rusqlite::Error {
+    rusqlite::Error::ModuleError(format!("{:#}", error))
+}
+
+unsafe impl<'vtab> VTab<'vtab> for LogTable {
+    type Aux = ();
+    type Cursor = LogCursor;
+
+    fn connect(
+        db: &mut VTabConnection,
+        _aux: Option<&()>,
+        _args: &[&[u8]],
+    ) -> rusqlite::Result<(String, Self)> {
+        let table = LogTable {
+            base: sqlite3::sqlite3_vtab::default(),
+            // SAFETY: The handle is only used to find the database file, while the table's
+            // connection is open.
+            db: unsafe { db.handle() },
+        };
+        Ok((
+            "CREATE TABLE x(seq, page_no, length, kind, timestamp, signature_ok)".to_string(),
+            table,
+        ))
+    }
+
+    fn best_index(&self, info: &mut IndexInfo) -> rusqlite::Result<()> {
+        let mut used: Vec<(usize, c_int)> = Vec::new();
+        for (index, constraint) in info.constraints().enumerate() {
+            if !constraint.is_usable() || constraint.column() != 0 {
+                continue;
+            }
+            let bit = match constraint.operator() {
+                IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_EQ => SEQ_EQ,
+                IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_GT => SEQ_GT,
+                IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_GE => SEQ_GE,
+                IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_LT => SEQ_LT,
+                IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_LE => SEQ_LE,
+                _ => continue,
+            };
+            if used.iter().all(|(_, used)| *used != bit) {
+                used.push((index, bit));
+            }
+        }
+        used.sort_by_key(|(_, bit)| *bit);
+
+        // SQLite still checks the constraints itself, which covers values `filter` can't use.
+        for (argument, (index, _)) in used.iter().enumerate() {
+            info.constraint_usage(*index)
+                .set_argv_index(argument as c_int + 1);
+        }
+        let plan = used.iter().fold(0, |plan, (_, bit)| plan | bit);
+        info.set_idx_num(plan);
+        info.set_estimated_cost(if plan & SEQ_EQ != 0 {
+            1.0
+        } else if plan != 0 {
+            1_000.0
+        } else {
+            1_000_000.0
+        });
+        Ok(())
+    }
+
+    fn open(&'vtab self) -> rusqlite::Result<LogCursor> {
+        Ok(LogCursor {
+            base: sqlite3::sqlite3_vtab_cursor::default(),
+            table: self,
+            seq: 0,
+            end: 0,
+            entry: None,
+        })
+    }
+}
+
+#[repr(C)]
+struct LogCursor {
+    base: sqlite3::sqlite3_vtab_cursor,
+    table: *const LogTable,
+    /// The block the cursor is on.
+    seq: u64,
+    /// Where the blocks left by the query's constraints end.
+    end: u64,
+    /// The row for `seq`, read once the cursor gets to it.
+    entry: Option<Entry>,
+}
+
+impl LogCursor {
+    fn table(&self) -> &LogTable {
+        // SAFETY: SQLite closes every cursor of a table before disconnecting it.
+        unsafe { &*self.table }
+    }
+
+    fn read(&mut self) -> rusqlite::Result<()> {
+        self.entry = if self.seq < self.end {
+            Some(self.table().entry(self.seq).map_err(module_error)?)
+        } else {
+            None
+        };
+        Ok(())
+    }
+}
+
+unsafe impl VTabCursor for LogCursor {
+    fn filter(
+        &mut self,
+        idx_num: c_int,
+        _idx_str: Option<&str>,
+        args: &Values<'_>,
+    ) -> rusqlite::Result<()> {
+        let (mut start, mut end) = (0, self.table().length().map_err(module_error)?);
+        let at_least = |value: f64| value.ceil().max(0.0) as u64;
+        let above = |value: f64| (value.floor() + 1.0).max(0.0) as u64;
+
+        let bits = [SEQ_EQ, SEQ_GT, SEQ_GE, SEQ_LT, SEQ_LE];
+        for (argument, bit) in bits.iter().filter(|bit| idx_num & *bit != 0).enumerate() {
+            // Anything that isn't a number is left for SQLite to compare.
+            let value = match args.get::<f64>(argument) {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+            match *bit {
+                SEQ_EQ => {
+                    start = start.max(at_least(value));
+                    end = end.min(above(value));
+                }
+                SEQ_GT => start = start.max(above(value)),
+                SEQ_GE => start = start.max(at_least(value)),
+                SEQ_LT => end = end.min(at_least(value)),
+                _ => end = end.min(above(value)),
+            }
+        }
+
+        self.seq = start;
+        self.end = end;
+        self.read()
+    }
+
+    fn next(&mut self) -> rusqlite::Result<()> {
+        self.seq += 1;
+        self.read()
+    }
+
+    fn eof(&self) -> bool {
+        self.seq >= self.end
+    }
+
+    fn column(&self, ctx: &mut Context, column: c_int) -> rusqlite::Result<()> {
+        let entry = self.entry.as_ref().ok_or_else(|| {
+            rusqlite::Error::ModuleError("hyper_log has no more rows".to_string())
+        })?;
+        let block = entry.block.as_ref();
+        match column {
+            0 => ctx.set_result(&(entry.seq as i64)),
+            1 => ctx.set_result(
+                &block
+                    .filter(|block| block.kind == BlockKind::Page)
+                    .map(|block| block.page_no as i64),
+            ),
+            2 => ctx.set_result(&entry.length.map(|length| length as i64)),
+            3 => ctx.set_result(&block.map(|block| block.kind.name())),
+            4 => ctx.set_result(&block.map(|block| block.timestamp as i64)),
+            5 => ctx.set_result(&entry.signature_ok),
+            _ => Err(rusqlite::Error::ModuleError(format!(
+                "hyper_log has no column {}",
+                column
+            ))),
+        }
+    }
+
+    fn rowid(&self) -> rusqlite::Result<i64> {
+        Ok(self.seq as i64)
+    }
+}
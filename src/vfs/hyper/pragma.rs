@@ -0,0 +1,129 @@
+// Pragmas for inspecting and managing the Hypercore behind a database from SQL, for when there's
+// no Rust code around (like in the `sqlite3` shell, with the extension loaded). They're answered
+// through `SQLITE_FCNTL_PRAGMA`, so they only exist on databases opened through this VFS, but
+// don't need anything registered with the SQLite that opened them.
+use super::{Block, BlockKind, File, HyperFile, LockFlag};
+use crate::hyper::{encode_hex, Durability};
+
+const PRAGMAS: &[&str] = &[
+    "hyper_key",
+    "hyper_version",
+    "hyper_peers",
+    "hyper_writable",
+    "hyper_snapshot",
+    "hyper_compact",
+];
+
+impl HyperFile {
+    /// Answers `PRAGMA name`, or fails with `FileControl::not_found()` for pragmas that aren't
+    /// ours; none of them take a value.
+    pub(super) fn pragma(&self, name: &str, value: Option<&str>) -> anyhow::Result<String> {
+        if name.eq_ignore_ascii_case("synchronous") {
+            if let Some(value) = value {
+                self.set_synchronous(value);
+            }
+            // SQLite goes on to set it (or answer it) itself.
+            return Err(super::FileControl::not_found());
+        }
+        if value.is_some() && PRAGMAS.contains(&name) {
+            return Err(anyhow::anyhow!("{} can't be set", name));
+        }
+        let feed = || self.state.feed.borrow();
+
+        Ok(match name {
+            "hyper_key" => encode_hex(&feed().key()),
+            "hyper_version" => feed().len().to_string(),
+            "hyper_peers" => feed().peers().to_string(),
+            "hyper_writable" => (feed().is_writable() as u8).to_string(),
+            "hyper_snapshot" => format!("hyper://{}+{}", encode_hex(&feed().key()), feed().len()),
+            "hyper_compact" => self.compact()?.to_string(),
+            _ => return Err(super::FileControl::not_found()),
+        })
+    }
+
+    /// Picks what syncs wait for from a level of `PRAGMA synchronous`, leaving it as it was for
+    /// anything SQLite won't take either.
+    fn set_synchronous(&self, level: &str) {
+        let durability = match level.to_ascii_lowercase().as_str() {
+            "0" | "off" | "no" | "false" => Durability::Buffered,
+            "1" | "normal" | "on" | "yes" | "true" => self.durability.min(Durability::Flushed),
+            "2" | "full" | "3" | "extra" => self.durability,
+            _ => return,
+        };
+        self.synchronous.set(durability);
+    }
+
+    /// Appends the current contents of every page and frees the local copies of the blocks they
+    /// supersede, returning how many blocks were freed.
+    ///
+    /// Transaction records are kept; everything else before the new pages reads as missing from
+    /// then on, here and in every replica that syncs the clearing.
+    fn compact(&self) -> anyhow::Result<u64> {
+        #[cfg(feature = "session")]
+        if self.state.view.is_some() {
+            return Err(anyhow::anyhow!(
+                "{:?} keeps changesets, which are all needed to build it",
+                self.name
+            ));
+        }
+        let held = self.lock.get();
+        if held > LockFlag::Shared {
+            return Err(anyhow::anyhow!(
+                "{:?} can't be compacted in the middle of a write",
+                self.name
+            ));
+        }
+
+        let result = self
+            .lock(LockFlag::Shared)
+            .and_then(|()| self.lock(LockFlag::Reserved))
+            .and_then(|()| self.lock(LockFlag::Exclusive))
+            .and_then(|()| self.checkpoint());
+        self.unlock(held)?;
+        result
+    }
+
+    fn checkpoint(&self) -> anyhow::Result<u64> {
+        let file_size = self.size()? as u64;
+        let page_size = self.page_size as u64;
+        let superseded = self.state.feed.borrow().len();
+
+        // Reading any page before the truncation finds it among the ones appended with it, and
+        // reading past the end of the file stops at the truncation; neither goes further back.
+        *self.batch.borrow_mut() = Some(vec![self.prepare(Block::truncate(file_size))?]);
+        let appended = (0..file_size.div_ceil(page_size)).try_for_each(|page_no| {
+            let page = self.page(page_no)?;
+            self.append(Block::page(page_no, file_size, page))
+        });
+        match appended {
+            Ok(()) => self.commit_batch()?,
+            Err(error) => {
+                self.batch.borrow_mut().take();
+                return Err(error);
+            }
+        }
+
+        let mut cleared = 0;
+        let mut run_start = None;
+        for seq in 0..=superseded {
+            let keep = seq == superseded || {
+                let bytes = self.state.feed.borrow_mut().get(seq)?;
+                match bytes {
+                    Some(bytes) => Block::decode(&bytes)?.kind == BlockKind::Transaction,
+                    None => true,
+                }
+            };
+
+            match (keep, run_start) {
+                (false, None) => run_start = Some(seq),
+                (true, Some(start)) => {
+                    self.state.feed.borrow_mut().clear(start, seq)?;
+                    cleared += seq - start;
+                    run_start = None;
+                }
+                _ => {}
+            }
+        }
+        Ok(cleared)
+    }
+}
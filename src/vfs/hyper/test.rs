@@ -0,0 +1,1292 @@
+use super::*;
+#[cfg(feature = "disk")]
+use crate::hyper::Feed;
+#[cfg(all(feature = "encryption", feature = "disk"))]
+use crate::hyper::Runtime;
+use crate::hyper::Timeouts;
+#[cfg(feature = "encryption")]
+use crate::hyper::KEY_LENGTH;
+use crate::vfs::Instance;
+use futures::future::{self, BoxFuture};
+use futures::FutureExt;
+use hypercore::{Proof, RequestBlock, RequestUpgrade};
+use rusqlite::{Connection, OpenFlags};
+
+#[cfg(feature = "encryption")]
+const SECRET: &str = "attack at dawn";
+
+fn register(name: &str, options: VfsOptions) -> anyhow::Result<Rc<RefCell<Instance>>> {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let hyper_vfs = Rc::new(RefCell::new(Vfs::connect(options)?));
+    let inst = Instance::new(name, hyper_vfs)?;
+    Instance::register(Rc::clone(&inst), false)?;
+    Ok(inst)
+}
+
+fn connect(inst: &Rc<RefCell<Instance>>, path: &str) -> rusqlite::Result<Connection> {
+    Connection::open_with_flags_and_vfs(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_WRITE
+            | OpenFlags::SQLITE_OPEN_CREATE
+            | OpenFlags::SQLITE_OPEN_URI,
+        &inst.borrow().vfs_name().unwrap(),
+    )
+}
+
+#[cfg(all(feature = "encryption", feature = "disk"))]
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+#[test]
+fn stores_database_in_hypercore() -> anyhow::Result<()> {
+    let inst = register("hyper-memory", VfsOptions::default())?;
+    let conn = connect(&inst, "docs.db")?;
+
+    conn.execute_batch(
+        r#"
+        CREATE TABLE notes(body TEXT);
+        INSERT INTO notes(body) VALUES ('hello'), ('world');
+        "#,
+    )?;
+    let count: i64 = conn.query_row("SELECT COUNT(*) FROM notes", rusqlite::NO_PARAMS, |row| {
+        row.get(0)
+    })?;
+
+    assert_eq!(count, 2);
+    Ok(())
+}
+
+#[cfg(all(feature = "encryption", feature = "disk"))]
+#[test]
+fn encrypts_page_blocks_at_rest() -> anyhow::Result<()> {
+    let directory = tempfile::tempdir()?;
+    let storage = Storage::Disk(directory.path().to_path_buf());
+    let key = "42".repeat(KEY_LENGTH);
+
+    let writer = register(
+        "hyper-encrypted-writer",
+        VfsOptions {
+            storage: storage.clone(),
+            encryption_key: Some(EncryptionKey::from_hex(&key)?),
+            ..VfsOptions::default()
+        },
+    )?;
+    let conn = connect(&writer, "secrets.db")?;
+    conn.execute_batch("CREATE TABLE secrets(body TEXT);")?;
+    conn.execute("INSERT INTO secrets(body) VALUES (?)", &[SECRET])?;
+    drop(conn);
+
+    // This is all a replica holding only the discovery key gets to see.
+    let runtime = Runtime::start(Timeouts::default())?;
+    let mut feed = Feed::open(&runtime, &storage, "secrets.db")?;
+    assert!(!feed.is_empty());
+    for seq in 0..feed.len() {
+        let block = feed.get(seq)?.expect("the block to be stored locally");
+        assert!(!contains(&block, SECRET.as_bytes()));
+        assert!(!contains(&block, b"SQLite format 3"));
+    }
+    drop(feed);
+
+    let reader = register(
+        "hyper-encrypted-reader",
+        VfsOptions {
+            storage,
+            ..VfsOptions::default()
+        },
+    )?;
+    let without_key = connect(&reader, "secrets.db").and_then(|conn| {
+        conn.query_row("SELECT body FROM secrets", rusqlite::NO_PARAMS, |row| {
+            row.get::<_, String>(0)
+        })
+    });
+    assert!(without_key.is_err());
+
+    let with_key = connect(&reader, &format!("file:secrets.db?key={}", key))?;
+    let body: String =
+        with_key.query_row("SELECT body FROM secrets", rusqlite::NO_PARAMS, |row| {
+            row.get(0)
+        })?;
+    assert_eq!(body, SECRET);
+    Ok(())
+}
+
+fn is_busy(result: rusqlite::Result<usize>) -> bool {
+    matches!(
+        result,
+        Err(rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error {
+                code: rusqlite::ErrorCode::DatabaseBusy,
+                ..
+            },
+            _
+        ))
+    )
+}
+
+#[test]
+fn locks_out_other_connections_in_the_process() -> anyhow::Result<()> {
+    let inst = register("hyper-locking", VfsOptions::default())?;
+    let writer = connect(&inst, "locked.db")?;
+    let other = connect(&inst, "locked.db")?;
+    other.busy_timeout(std::time::Duration::from_millis(0))?;
+    writer.execute_batch("CREATE TABLE notes(body TEXT);")?;
+
+    writer.execute_batch("BEGIN EXCLUSIVE;")?;
+    assert!(is_busy(other.execute(
+        "INSERT INTO notes VALUES ('other')",
+        rusqlite::NO_PARAMS
+    )));
+    writer.execute_batch("INSERT INTO notes VALUES ('writer'); COMMIT;")?;
+
+    // A reader in the middle of a transaction keeps writers from committing.
+    writer.execute_batch("BEGIN; SELECT * FROM notes;")?;
+    assert!(is_busy(other.execute(
+        "INSERT INTO notes VALUES ('other')",
+        rusqlite::NO_PARAMS
+    )));
+    writer.execute_batch("COMMIT;")?;
+
+    other.execute("INSERT INTO notes VALUES ('other')", rusqlite::NO_PARAMS)?;
+    let count: i64 =
+        writer.query_row("SELECT COUNT(*) FROM notes", rusqlite::NO_PARAMS, |row| {
+            row.get(0)
+        })?;
+    assert_eq!(count, 2);
+    Ok(())
+}
+
+#[cfg(all(feature = "disk", target_os = "linux"))]
+#[test]
+fn locks_out_other_processes_sharing_the_storage() -> anyhow::Result<()> {
+    let directory = tempfile::tempdir()?;
+    let options = VfsOptions {
+        storage: Storage::Disk(directory.path().to_path_buf()),
+        ..VfsOptions::default()
+    };
+    // Each `Vfs` keeps its own lock table and Hypercores, like a separate process would.
+    let first = connect(
+        &register("hyper-process-one", options.clone())?,
+        "shared.db",
+    )?;
+    let second = connect(&register("hyper-process-two", options)?, "shared.db")?;
+    second.busy_timeout(std::time::Duration::from_millis(0))?;
+
+    first.execute_batch("CREATE TABLE notes(body TEXT); INSERT INTO notes VALUES ('first');")?;
+    first.execute_batch("BEGIN EXCLUSIVE;")?;
+    assert!(is_busy(second.execute(
+        "INSERT INTO notes VALUES ('second')",
+        rusqlite::NO_PARAMS
+    )));
+    first.execute_batch("COMMIT;")?;
+
+    second.execute("INSERT INTO notes VALUES ('second')", rusqlite::NO_PARAMS)?;
+    let bodies = first
+        .prepare("SELECT body FROM notes ORDER BY rowid")?
+        .query_map(rusqlite::NO_PARAMS, |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    assert_eq!(bodies, vec!["first", "second"]);
+    Ok(())
+}
+
+#[cfg(feature = "disk")]
+#[test]
+fn writes_transactions_without_a_journal() -> anyhow::Result<()> {
+    let directory = tempfile::tempdir()?;
+    let storage = Storage::Disk(directory.path().to_path_buf());
+    let inst = register(
+        "hyper-batch",
+        VfsOptions {
+            storage: storage.clone(),
+            ..VfsOptions::default()
+        },
+    )?;
+    let conn = connect(&inst, "batched.db")?;
+
+    conn.execute_batch(
+        r#"
+        CREATE TABLE notes(body TEXT);
+        PRAGMA journal_mode = PERSIST;
+        BEGIN;
+        INSERT INTO notes(body) VALUES ('hello');
+        INSERT INTO notes(body) VALUES (zeroblob(20000));
+        COMMIT;
+        "#,
+    )?;
+    let count: i64 = conn.query_row("SELECT COUNT(*) FROM notes", rusqlite::NO_PARAMS, |row| {
+        row.get(0)
+    })?;
+
+    assert_eq!(count, 2);
+    // A persisted journal would be left behind if one had been written.
+    assert!(!Feed::exists(&storage, "batched.db-journal"));
+    assert!(!directory.path().join("batched.db-journal").exists());
+    Ok(())
+}
+
+#[test]
+fn offers_batch_writes_only_to_a_sqlite_built_for_them() {
+    // The SQLite `build.rs` builds is, whatever the environment it's built in.
+    assert!(api::compileoption_used(BATCH_ATOMIC_WRITE));
+    let missing = CStr::from_bytes_with_nul(b"ENABLE_NOTHING_AT_ALL\0").unwrap();
+    assert!(!api::compileoption_used(missing));
+}
+
+#[cfg(feature = "disk")]
+#[test]
+fn keeps_journals_and_temporary_files_out_of_hypercores() -> anyhow::Result<()> {
+    let directory = tempfile::tempdir()?;
+    let storage = Storage::Disk(directory.path().to_path_buf());
+    let inst = register(
+        "hyper-journaled",
+        VfsOptions {
+            storage: storage.clone(),
+            ..VfsOptions::default()
+        },
+    )?;
+    let conn = connect(&inst, "journaled.db")?;
+
+    // Spilling pages out of a tiny cache mid-transaction makes SQLite fall back to a journal.
+    conn.execute_batch(
+        r#"
+        PRAGMA cache_size = 2;
+        PRAGMA journal_mode = PERSIST;
+        CREATE TABLE notes(body BLOB);
+        CREATE TEMP TABLE scratch(n INTEGER);
+        INSERT INTO scratch VALUES (1), (2), (3);
+        BEGIN;
+        INSERT INTO notes(body) SELECT zeroblob(20000) FROM scratch;
+        COMMIT;
+        "#,
+    )?;
+    let count: i64 = conn.query_row("SELECT COUNT(*) FROM notes", rusqlite::NO_PARAMS, |row| {
+        row.get(0)
+    })?;
+    assert_eq!(count, 3);
+
+    let journal = directory.path().join("journaled.db-journal");
+    assert!(journal.is_file());
+    assert!(!Feed::exists(&storage, "journaled.db-journal"));
+    let entries: Vec<_> = std::fs::read_dir(directory.path())?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<Result<_, _>>()?;
+    assert_eq!(entries.len(), 2, "{:?}", entries);
+
+    // Deleting the journal deletes the plain file.
+    conn.execute_batch("PRAGMA journal_mode = DELETE;")?;
+    assert!(!journal.exists());
+    Ok(())
+}
+
+#[cfg(feature = "async-std")]
+#[test]
+fn works_from_within_async_std() -> anyhow::Result<()> {
+    let inst = register("hyper-async-std", VfsOptions::default())?;
+
+    async_std::task::block_on(async {
+        let conn = connect(&inst, "async-std.db")?;
+        conn.execute_batch("CREATE TABLE notes(body TEXT); INSERT INTO notes VALUES ('hi');")?;
+        Ok(())
+    })
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn works_from_within_tokio() -> anyhow::Result<()> {
+    let inst = register("hyper-tokio", VfsOptions::default())?;
+
+    tokio::runtime::Builder::new_current_thread()
+        .build()?
+        .block_on(async {
+            let conn = connect(&inst, "tokio.db")?;
+            conn.execute_batch("CREATE TABLE notes(body TEXT); INSERT INTO notes VALUES ('hi');")?;
+            Ok(())
+        })
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn rejects_pages_replayed_under_another_page_number() -> anyhow::Result<()> {
+    let cipher = Cipher::new(&EncryptionKey::new([7; KEY_LENGTH]));
+    let mut block = Block::page(1, 8192, vec![1; DEFAULT_PAGE_SIZE]);
+    cipher.seal(&mut block)?;
+
+    let mut moved = block.clone();
+    moved.page_no = 0;
+    assert!(cipher.open(&mut moved).is_err());
+
+    cipher.open(&mut block)?;
+    assert_eq!(block.payload, vec![1; DEFAULT_PAGE_SIZE]);
+    Ok(())
+}
+
+#[cfg(all(feature = "compression-zstd", feature = "encryption"))]
+#[test]
+fn compresses_sparse_pages() -> anyhow::Result<()> {
+    let hyper_vfs = Rc::new(RefCell::new(Vfs::connect(VfsOptions {
+        encryption_key: Some(EncryptionKey::new([9; KEY_LENGTH])),
+        compression: Compression::Zstd(3),
+        ..VfsOptions::default()
+    })?));
+    let inst = Instance::new("hyper-compressed", Rc::clone(&hyper_vfs) as _)?;
+    Instance::register(Rc::clone(&inst), false)?;
+
+    let conn = connect(&inst, "sparse.db")?;
+    conn.execute_batch("CREATE TABLE notes(body TEXT);")?;
+    conn.execute("INSERT INTO notes(body) VALUES (?)", &[SECRET])?;
+    let body: String = conn.query_row("SELECT body FROM notes", rusqlite::NO_PARAMS, |row| {
+        row.get(0)
+    })?;
+    assert_eq!(body, SECRET);
+
+    let stats = hyper_vfs
+        .borrow()
+        .compression_stats("sparse.db")
+        .expect("the file to have been opened");
+    assert!(stats.blocks > 0);
+    assert!(stats.ratio() < 0.5, "{:?}", stats);
+    Ok(())
+}
+
+#[test]
+fn records_committed_transactions_in_an_audit_log() -> anyhow::Result<()> {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let hyper_vfs = Rc::new(RefCell::new(Vfs::connect(VfsOptions::default())?));
+    let inst = Instance::new(
+        "hyper-audit",
+        Rc::clone(&hyper_vfs) as Rc<RefCell<dyn System>>,
+    )?;
+    Instance::register(Rc::clone(&inst), false)?;
+
+    let conn = connect(&inst, "audited.db")?;
+    let audit = hyper_vfs.borrow().audit(&conn, "audited.db")?;
+
+    conn.execute_batch("CREATE TABLE notes(body TEXT); CREATE TABLE tags(name TEXT);")?;
+    audit.set_message("Say hello");
+    conn.execute(
+        "INSERT INTO notes(body) VALUES ('hello')",
+        rusqlite::NO_PARAMS,
+    )?;
+    conn.execute_batch(
+        r#"
+        BEGIN;
+        INSERT INTO tags(name) VALUES ('discarded');
+        ROLLBACK;
+        "#,
+    )?;
+    conn.execute_batch(
+        r#"
+        BEGIN;
+        INSERT INTO notes(body) VALUES ('world');
+        INSERT INTO tags(name) VALUES ('greeting');
+        COMMIT;
+        "#,
+    )?;
+    let count: i64 = conn.query_row("SELECT COUNT(*) FROM notes", rusqlite::NO_PARAMS, |row| {
+        row.get(0)
+    })?;
+    assert_eq!(count, 2);
+
+    let transactions = hyper_vfs.borrow().transactions("audited.db")?;
+    let recent: Vec<_> = transactions
+        .iter()
+        .rev()
+        .take(2)
+        .map(|transaction| {
+            let tables: Vec<_> = transaction.tables.iter().map(String::as_str).collect();
+            (tables, transaction.message.as_deref())
+        })
+        .collect();
+    assert_eq!(
+        recent,
+        vec![
+            (vec!["notes", "tags"], None),
+            (vec!["notes"], Some("Say hello"))
+        ]
+    );
+    assert!(transactions.windows(2).all(|pair| pair[0].id < pair[1].id));
+    Ok(())
+}
+
+#[test]
+fn keeps_other_connections_records_queued() -> anyhow::Result<()> {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let hyper_vfs = Rc::new(RefCell::new(Vfs::connect(VfsOptions::default())?));
+    let inst = Instance::new(
+        "hyper-audit-shared",
+        Rc::clone(&hyper_vfs) as Rc<RefCell<dyn System>>,
+    )?;
+    Instance::register(Rc::clone(&inst), false)?;
+
+    let writer = connect(&inst, "shared-audit.db")?;
+    writer.execute_batch("CREATE TABLE notes(body TEXT);")?;
+    let reader = connect(&inst, "shared-audit.db")?;
+    let audit = hyper_vfs.borrow().audit(&writer, "shared-audit.db")?;
+    assert!(hyper_vfs.borrow().audit(&reader, "audited.db").is_err());
+    let _reading = hyper_vfs.borrow().audit(&reader, "shared-audit.db")?;
+
+    // A record the writer has queued, as if the pages of its transaction were still coming in.
+    let commits = Arc::clone(&hyper_vfs.borrow().state("shared-audit.db")?.commits);
+    commits.lock().unwrap().push((
+        audit.connection(),
+        Transaction {
+            tables: std::iter::once("notes".to_string()).collect(),
+            message: Some("Held up".to_string()),
+            ..Transaction::default()
+        },
+    ));
+    reader.execute_batch("BEGIN; SELECT * FROM notes; COMMIT;")?;
+    assert_eq!(commits.lock().unwrap().len(), 1);
+
+    writer.execute(
+        "INSERT INTO notes(body) VALUES ('hello')",
+        rusqlite::NO_PARAMS,
+    )?;
+    assert!(commits.lock().unwrap().is_empty());
+    let transactions = hyper_vfs.borrow().transactions("shared-audit.db")?;
+    let latest = transactions.last().expect("a transaction to be recorded");
+    assert_eq!(latest.tables.iter().collect::<Vec<_>>(), vec!["notes"]);
+    assert_eq!(latest.message.as_deref(), Some("Held up"));
+    Ok(())
+}
+
+#[test]
+fn answers_hypercore_pragmas() -> anyhow::Result<()> {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let hyper_vfs = Rc::new(RefCell::new(Vfs::connect(VfsOptions::default())?));
+    let inst = Instance::new(
+        "hyper-pragmas",
+        Rc::clone(&hyper_vfs) as Rc<RefCell<dyn System>>,
+    )?;
+    Instance::register(Rc::clone(&inst), false)?;
+    let conn = connect(&inst, "pragmas.db")?;
+    let audit = hyper_vfs.borrow().audit(&conn, "pragmas.db")?;
+    let pragma = |name: &str| -> rusqlite::Result<String> {
+        conn.query_row(&format!("PRAGMA {}", name), rusqlite::NO_PARAMS, |row| {
+            row.get(0)
+        })
+    };
+
+    conn.execute_batch("CREATE TABLE notes(body TEXT);")?;
+    let key = pragma("hyper_key")?;
+    let version: u64 = pragma("hyper_version")?.parse()?;
+    assert_eq!(key.len(), 64);
+    assert_eq!(pragma("hyper_writable")?, "1");
+    assert_eq!(pragma("hyper_peers")?, "0");
+    assert_eq!(
+        pragma("hyper_snapshot")?,
+        format!("hyper://{}+{}", key, version)
+    );
+    assert!(conn.execute_batch("PRAGMA hyper_version = 1").is_err());
+
+    audit.set_message("Fill in notes");
+    for round in 0..20 {
+        conn.execute(
+            "INSERT INTO notes(body) VALUES (?)",
+            &[format!("note {}", round)],
+        )?;
+    }
+    assert!(pragma("hyper_version")?.parse::<u64>()? > version);
+
+    let cleared: u64 = pragma("hyper_compact")?.parse()?;
+    assert!(cleared > 0);
+
+    conn.execute(
+        "INSERT INTO notes(body) VALUES ('after')",
+        rusqlite::NO_PARAMS,
+    )?;
+    let count: i64 = conn.query_row("SELECT COUNT(*) FROM notes", rusqlite::NO_PARAMS, |row| {
+        row.get(0)
+    })?;
+    assert_eq!(count, 21);
+    let transactions = hyper_vfs.borrow().transactions("pragmas.db")?;
+    assert!(transactions
+        .iter()
+        .any(|transaction| transaction.message.as_deref() == Some("Fill in notes")));
+    Ok(())
+}
+
+#[test]
+fn lists_blocks_in_the_log_table() -> anyhow::Result<()> {
+    let inst = register("hyper-log", VfsOptions::default())?;
+    let conn = connect(&inst, "log.db")?;
+    register_log_table(&conn)?;
+    conn.execute_batch(
+        "CREATE TABLE notes(body TEXT);
+        INSERT INTO notes(body) VALUES ('hello');",
+    )?;
+
+    let mut statement = conn.prepare(
+        "SELECT seq, page_no, length, kind, timestamp, signature_ok FROM hyper_log ORDER BY seq",
+    )?;
+    let rows = statement
+        .query_map(rusqlite::NO_PARAMS, |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, Option<i64>>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, i64>(4)?,
+                row.get::<_, bool>(5)?,
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    assert!(!rows.is_empty());
+    for (index, (seq, page_no, length, kind, timestamp, signature_ok)) in rows.iter().enumerate() {
+        assert_eq!(*seq, index as i64);
+        assert_eq!(page_no.is_some(), kind == "page");
+        assert!(*length > 0);
+        assert!(*timestamp > 0);
+        assert!(signature_ok);
+    }
+    assert_eq!(rows[0].1, Some(0));
+
+    // Constraints on `seq` narrow down the blocks that are read.
+    let seqs = |sql: &str| -> rusqlite::Result<Vec<i64>> {
+        conn.prepare(sql)?
+            .query_map(rusqlite::NO_PARAMS, |row| row.get(0))?
+            .collect()
+    };
+    let last = rows.len() as i64 - 1;
+    assert!(last >= 2);
+    assert_eq!(seqs("SELECT seq FROM hyper_log WHERE seq = 1")?, vec![1]);
+    assert_eq!(
+        seqs("SELECT seq FROM hyper_log WHERE seq > 0 AND seq <= 2")?,
+        vec![1, 2]
+    );
+    assert_eq!(
+        seqs("SELECT seq FROM hyper_log WHERE seq >= 0.5 AND seq < 1.5")?,
+        vec![1]
+    );
+    assert!(seqs("SELECT seq FROM hyper_log WHERE seq = 1.5")?.is_empty());
+    assert_eq!(
+        seqs(&format!("SELECT seq FROM hyper_log WHERE seq >= {}", last))?,
+        vec![last]
+    );
+    assert_eq!(
+        seqs("SELECT seq FROM hyper_log WHERE seq < 'text' LIMIT 1")?,
+        vec![0]
+    );
+
+    let other = Connection::open_in_memory()?;
+    register_log_table(&other)?;
+    assert!(other
+        .query_row(
+            "SELECT COUNT(*) FROM hyper_log",
+            rusqlite::NO_PARAMS,
+            |row| { row.get::<_, i64>(0) }
+        )
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn answers_hypercore_functions() -> anyhow::Result<()> {
+    let inst = register("hyper-functions", VfsOptions::default())?;
+    let conn = connect(&inst, "functions.db")?;
+    register_functions(&conn)?;
+    conn.execute_batch("CREATE TABLE notes(body TEXT);")?;
+
+    let (key, version, share, verified): (String, i64, String, bool) = conn.query_row(
+        "SELECT hyper_key(), hyper_version(), hyper_share(), hyper_verify()",
+        rusqlite::NO_PARAMS,
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+    )?;
+    let pragma: String =
+        conn.query_row("PRAGMA hyper_key", rusqlite::NO_PARAMS, |row| row.get(0))?;
+    assert_eq!(key, pragma);
+    assert!(version > 0);
+    assert_eq!(share, format!("hyper://{}", key));
+    assert!(verified);
+
+    conn.execute(
+        "INSERT INTO notes(body) VALUES ('hello')",
+        rusqlite::NO_PARAMS,
+    )?;
+    let later: i64 = conn.query_row("SELECT hyper_version()", rusqlite::NO_PARAMS, |row| {
+        row.get(0)
+    })?;
+    assert!(later > version);
+
+    let other = Connection::open_in_memory()?;
+    register_functions(&other)?;
+    assert!(other
+        .query_row("SELECT hyper_key()", rusqlite::NO_PARAMS, |row| {
+            row.get::<_, String>(0)
+        })
+        .is_err());
+    Ok(())
+}
+
+#[cfg(feature = "disk")]
+fn is_corrupt<T>(result: rusqlite::Result<T>) -> bool {
+    matches!(
+        result,
+        Err(rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error {
+                code: rusqlite::ErrorCode::DatabaseCorrupt,
+                ..
+            },
+            _
+        ))
+    )
+}
+
+/// Changes one of the files making up the Hypercore for `name` behind its back.
+#[cfg(feature = "disk")]
+fn tamper(
+    storage: &Storage,
+    name: &str,
+    file: &str,
+    change: impl FnOnce(&mut Vec<u8>),
+) -> anyhow::Result<()> {
+    let path = storage.directory_of(name).unwrap().join(file);
+    let mut bytes = std::fs::read(&path)?;
+    change(&mut bytes);
+    std::fs::write(path, bytes)?;
+    Ok(())
+}
+
+/// What `xGetLastError` of the VFS behind `inst` reports.
+#[cfg(feature = "disk")]
+fn last_error(inst: &Rc<RefCell<Instance>>) -> String {
+    let name = std::ffi::CString::new(inst.borrow().vfs_name().unwrap()).unwrap();
+    let mut buffer = [0 as std::os::raw::c_char; 512];
+    unsafe {
+        let vfs = sqlite3::sqlite3_vfs_find(name.as_ptr());
+        (*vfs).xGetLastError.unwrap()(vfs, buffer.len() as _, buffer.as_mut_ptr());
+        std::ffi::CStr::from_ptr(buffer.as_ptr())
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+#[cfg(feature = "disk")]
+#[test]
+fn refuses_blocks_that_have_been_tampered_with() -> anyhow::Result<()> {
+    let directory = tempfile::tempdir()?;
+    let options = VfsOptions {
+        storage: Storage::Disk(directory.path().to_path_buf()),
+        ..VfsOptions::default()
+    };
+    {
+        let conn = connect(&register("hyper-untampered", options.clone())?, "notes.db")?;
+        conn.execute_batch("CREATE TABLE notes(body TEXT); INSERT INTO notes VALUES ('hello');")?;
+    }
+    tamper(&options.storage, "notes.db", "data", |bytes| {
+        *bytes.last_mut().unwrap() ^= 0xff
+    })?;
+
+    let inst = register("hyper-tampered-data", options)?;
+    let read = connect(&inst, "notes.db").and_then(|conn| {
+        conn.query_row("SELECT body FROM notes", rusqlite::NO_PARAMS, |row| {
+            row.get::<_, String>(0)
+        })
+    });
+    assert!(is_corrupt(read));
+    assert!(last_error(&inst).contains("doesn't match the signature"));
+    Ok(())
+}
+
+#[cfg(feature = "disk")]
+#[test]
+fn refuses_to_open_a_tampered_tree() -> anyhow::Result<()> {
+    let directory = tempfile::tempdir()?;
+    let options = VfsOptions {
+        storage: Storage::Disk(directory.path().to_path_buf()),
+        ..VfsOptions::default()
+    };
+    {
+        let conn = connect(&register("hyper-untouched", options.clone())?, "notes.db")?;
+        conn.execute_batch("CREATE TABLE notes(body TEXT); INSERT INTO notes VALUES ('hello');")?;
+    }
+    // Every node of the tree is a 32 byte hash followed by the length it covers.
+    tamper(&options.storage, "notes.db", "tree", |bytes| {
+        bytes.chunks_mut(40).for_each(|node| node[0] ^= 0xff)
+    })?;
+
+    let inst = register("hyper-tampered-tree", options)?;
+    assert!(is_corrupt(connect(&inst, "notes.db")));
+    assert!(last_error(&inst).contains("doesn't match its signature"));
+    Ok(())
+}
+
+fn replica_of(
+    name: &str,
+    peer: Arc<dyn Peer>,
+    timeout: Option<std::time::Duration>,
+) -> anyhow::Result<Rc<RefCell<Instance>>> {
+    register(
+        name,
+        VfsOptions {
+            peers: vec![peer],
+            replication: Replication::default(),
+            timeouts: Timeouts {
+                peer: timeout,
+                ..Timeouts::default()
+            },
+            ..VfsOptions::default()
+        },
+    )
+}
+
+fn connect_read_only(inst: &Rc<RefCell<Instance>>, path: &str) -> rusqlite::Result<Connection> {
+    Connection::open_with_flags_and_vfs(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY,
+        &inst.borrow().vfs_name().unwrap(),
+    )
+}
+
+#[test]
+fn reads_pages_from_peers_on_demand() -> anyhow::Result<()> {
+    let hyper_vfs = Rc::new(RefCell::new(Vfs::connect(VfsOptions::default())?));
+    let origin = Instance::new(
+        "hyper-origin",
+        Rc::clone(&hyper_vfs) as Rc<RefCell<dyn System>>,
+    )?;
+    Instance::register(Rc::clone(&origin), false)?;
+    let writer = connect(&origin, "origin.db")?;
+    writer.execute_batch("CREATE TABLE notes(id INTEGER PRIMARY KEY, body TEXT);")?;
+    // Every transaction rewrites a page or two, spreading the latest pages all over the feed.
+    for start in (0..2000).step_by(40) {
+        writer.execute(
+            "WITH RECURSIVE ids(id) AS (SELECT ?1 UNION ALL SELECT id + 1 FROM ids WHERE id < ?2)
+            INSERT INTO notes(id, body) SELECT id, printf('note %d', id) FROM ids",
+            [start, start + 39],
+        )?;
+    }
+    let key: String =
+        writer.query_row("PRAGMA hyper_key", rusqlite::NO_PARAMS, |row| row.get(0))?;
+    let version: i64 = writer
+        .query_row("PRAGMA hyper_version", rusqlite::NO_PARAMS, |row| {
+            row.get::<_, String>(0)
+        })?
+        .parse()?;
+
+    let peer = hyper_vfs.borrow().peer("origin.db")?;
+    let replica = replica_of("hyper-replica", peer, None)?;
+    let path = format!("hyper://{}", key);
+    assert!(connect(&replica, &path).is_err());
+    let reader = connect_read_only(&replica, &path)?;
+    register_log_table(&reader)?;
+
+    let body: String = reader.query_row(
+        "SELECT body FROM notes WHERE id = 1234",
+        rusqlite::NO_PARAMS,
+        |row| row.get(0),
+    )?;
+    assert_eq!(body, "note 1234");
+    let (blocks, stored): (i64, i64) = reader.query_row(
+        "SELECT COUNT(*), COUNT(length) FROM hyper_log",
+        rusqlite::NO_PARAMS,
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    assert_eq!(blocks, version);
+    // A handful of index blocks, and the pages on the way down to the row.
+    assert!(stored <= 10, "fetched {} of {} blocks", stored, blocks);
+    let pragma: String =
+        reader.query_row("PRAGMA hyper_peers", rusqlite::NO_PARAMS, |row| row.get(0))?;
+    assert_eq!(pragma, "1");
+
+    writer.execute(
+        "INSERT INTO notes(id, body) VALUES (5000, 'late')",
+        rusqlite::NO_PARAMS,
+    )?;
+    let late: String = reader.query_row(
+        "SELECT body FROM notes WHERE id = 5000",
+        rusqlite::NO_PARAMS,
+        |row| row.get(0),
+    )?;
+    assert_eq!(late, "late");
+    Ok(())
+}
+
+#[test]
+fn finds_replicated_pages_through_the_index() -> anyhow::Result<()> {
+    let hyper_vfs = Rc::new(RefCell::new(Vfs::connect(VfsOptions::default())?));
+    let origin = Instance::new(
+        "hyper-indexed-origin",
+        Rc::clone(&hyper_vfs) as Rc<RefCell<dyn System>>,
+    )?;
+    Instance::register(Rc::clone(&origin), false)?;
+    let writer = connect(&origin, "indexed-origin.db")?;
+    register_log_table(&writer)?;
+    writer.execute_batch(
+        "CREATE TABLE notes(id INTEGER PRIMARY KEY, body TEXT);
+        WITH RECURSIVE ids(id) AS (SELECT 1 UNION ALL SELECT id + 1 FROM ids WHERE id < 300)
+        INSERT INTO notes(id, body) SELECT id, 'draft' FROM ids;",
+    )?;
+    // Every page is written over and over, leaving most of the feed superseded.
+    for round in 0..30 {
+        writer.execute(
+            "UPDATE notes SET body = printf('note %d, take %d', id, ?)",
+            [round],
+        )?;
+    }
+    let key: String =
+        writer.query_row("PRAGMA hyper_key", rusqlite::NO_PARAMS, |row| row.get(0))?;
+    let latest = writer
+        .prepare("SELECT page_no, MAX(seq) FROM hyper_log WHERE kind = 'page' GROUP BY page_no")?
+        .query_map(rusqlite::NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<HashMap<i64, i64>>>()?;
+
+    let peer = hyper_vfs.borrow().peer("indexed-origin.db")?;
+    let replica = replica_of("hyper-indexed-replica", peer, None)?;
+    let reader = connect_read_only(&replica, &format!("hyper://{}", key))?;
+    register_log_table(&reader)?;
+    let (count, takes): (i64, i64) = reader.query_row(
+        "SELECT COUNT(*), SUM(body LIKE '%take 29') FROM notes",
+        rusqlite::NO_PARAMS,
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    assert_eq!((count, takes), (300, 300));
+
+    // Nothing was scanned: every page came straight from its latest block.
+    let stored = reader
+        .prepare("SELECT seq, kind, page_no FROM hyper_log WHERE length IS NOT NULL")?
+        .query_map(rusqlite::NO_PARAMS, |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<i64>>(2)?,
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let mut indexes = 0;
+    for (seq, kind, page_no) in stored {
+        match (kind.as_str(), page_no) {
+            ("page", Some(page_no)) => assert_eq!(latest.get(&page_no), Some(&seq)),
+            ("index", None) => indexes += 1,
+            other => panic!("Fetched block {} ({:?}) for no reason", seq, other),
+        }
+    }
+    assert!(indexes <= 6, "fetched {} index blocks", indexes);
+    Ok(())
+}
+
+/// Knows how long the feed is, but never hands out any of its blocks.
+#[derive(Debug)]
+struct Unresponsive(Arc<dyn Peer>);
+
+impl Peer for Unresponsive {
+    fn length(&self, key: [u8; 32]) -> BoxFuture<'static, anyhow::Result<Option<u64>>> {
+        self.0.length(key)
+    }
+
+    fn proof(
+        &self,
+        key: [u8; 32],
+        block: Option<RequestBlock>,
+        upgrade: Option<RequestUpgrade>,
+    ) -> BoxFuture<'static, anyhow::Result<Option<Proof>>> {
+        match block {
+            Some(_) => future::pending().boxed(),
+            None => self.0.proof(key, block, upgrade),
+        }
+    }
+}
+
+#[test]
+fn fails_reads_when_peers_time_out() -> anyhow::Result<()> {
+    let hyper_vfs = Rc::new(RefCell::new(Vfs::connect(VfsOptions::default())?));
+    let origin = Instance::new(
+        "hyper-slow-origin",
+        Rc::clone(&hyper_vfs) as Rc<RefCell<dyn System>>,
+    )?;
+    Instance::register(Rc::clone(&origin), false)?;
+    let writer = connect(&origin, "slow.db")?;
+    writer.execute_batch("CREATE TABLE notes(body TEXT);")?;
+    let key: String =
+        writer.query_row("PRAGMA hyper_key", rusqlite::NO_PARAMS, |row| row.get(0))?;
+
+    let peer = Unresponsive(hyper_vfs.borrow().peer("slow.db")?);
+    let replica = replica_of(
+        "hyper-slow-replica",
+        Arc::new(peer),
+        Some(std::time::Duration::from_millis(100)),
+    )?;
+    let result = connect_read_only(&replica, &format!("hyper://{}", key)).and_then(|reader| {
+        reader.query_row("SELECT COUNT(*) FROM notes", rusqlite::NO_PARAMS, |row| {
+            row.get::<_, i64>(0)
+        })
+    });
+    assert!(matches!(
+        result,
+        Err(rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error {
+                code: rusqlite::ErrorCode::SystemIOFailure,
+                ..
+            },
+            _
+        ))
+    ));
+    Ok(())
+}
+
+#[cfg(feature = "disk")]
+#[test]
+fn rebuilds_a_damaged_page_index() -> anyhow::Result<()> {
+    let directory = tempfile::tempdir()?;
+    let options = VfsOptions {
+        storage: Storage::Disk(directory.path().to_path_buf()),
+        ..VfsOptions::default()
+    };
+    {
+        let conn = connect(&register("hyper-indexed", options.clone())?, "indexed.db")?;
+        conn.execute_batch(
+            "CREATE TABLE notes(id INTEGER PRIMARY KEY, body TEXT);
+            WITH RECURSIVE ids(id) AS (SELECT 1 UNION ALL SELECT id + 1 FROM ids WHERE id < 500)
+            INSERT INTO notes(id, body) SELECT id, printf('note %d', id) FROM ids;
+            UPDATE notes SET body = upper(body) WHERE id % 7 = 0;",
+        )?;
+    }
+    let index = options
+        .storage
+        .directory_of("indexed.db")
+        .unwrap()
+        .join("pages.idx");
+    let original = std::fs::read(&index)?;
+    assert!(original.len() > 32);
+
+    let damages: [fn(&mut Vec<u8>); 3] = [
+        // A record cut short, as if the process died writing it.
+        |bytes| bytes.truncate(bytes.len() - 4),
+        // Every page pointed at the same block.
+        |bytes| {
+            for record in bytes[32..].chunks_exact_mut(9) {
+                if record[0] == BlockKind::Page as u8 {
+                    record[1..].copy_from_slice(&1u64.to_le_bytes());
+                }
+            }
+        },
+        // The index of some other feed.
+        |bytes| bytes[0] ^= 0xff,
+    ];
+    for (round, damage) in damages.iter().enumerate() {
+        tamper(&options.storage, "indexed.db", "pages.idx", *damage)?;
+        let inst = register(&format!("hyper-reindexed-{}", round), options.clone())?;
+        let conn = connect(&inst, "indexed.db")?;
+        let (count, shouted): (i64, i64) = conn.query_row(
+            "SELECT COUNT(*), SUM(body = upper(body)) FROM notes",
+            rusqlite::NO_PARAMS,
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        assert_eq!((count, shouted), (500, 71));
+        assert_eq!(std::fs::read(&index)?, original);
+    }
+    Ok(())
+}
+
+#[test]
+fn caches_hot_pages_between_transactions() -> anyhow::Result<()> {
+    let cache = crate::vfs::Cache::new(256);
+    let stats = cache.stats();
+    let hyper_vfs = Rc::new(RefCell::new(Vfs::connect(VfsOptions::default())?));
+    let inst = Instance::builder("hyper-cached", hyper_vfs)
+        .layer(cache)
+        .register(false)?;
+    let reader = connect(&inst, "cached.db")?;
+    let writer = connect(&inst, "cached.db")?;
+    writer.execute_batch(
+        "CREATE TABLE notes(body TEXT);
+        INSERT INTO notes(body) VALUES ('hello');",
+    )?;
+    let count = || -> rusqlite::Result<i64> {
+        reader.query_row("SELECT COUNT(*) FROM notes", rusqlite::NO_PARAMS, |row| {
+            row.get(0)
+        })
+    };
+
+    for _ in 0..5 {
+        assert_eq!(count()?, 1);
+    }
+    assert!(stats.get().hits > 0);
+
+    writer.execute(
+        "INSERT INTO notes(body) VALUES ('world')",
+        rusqlite::NO_PARAMS,
+    )?;
+    assert_eq!(count()?, 2);
+
+    let cache = crate::vfs::Cache::new(0);
+    let stats = cache.stats();
+    let uncached = Rc::new(RefCell::new(Vfs::connect(VfsOptions::default())?));
+    let inst = Instance::builder("hyper-uncached", uncached)
+        .layer(cache)
+        .register(false)?;
+    let conn = connect(&inst, "uncached.db")?;
+    conn.execute_batch("CREATE TABLE notes(body TEXT);")?;
+    conn.query_row("SELECT COUNT(*) FROM notes", rusqlite::NO_PARAMS, |row| {
+        row.get::<_, i64>(0)
+    })?;
+    assert_eq!(stats.get().hits, 0);
+    assert!(stats.get().misses > 0);
+    Ok(())
+}
+
+#[test]
+fn reaches_the_hypercore_through_layers() -> anyhow::Result<()> {
+    let hyper_vfs = Rc::new(RefCell::new(Vfs::connect(VfsOptions::default())?));
+    let inst = Instance::builder("hyper-layered", hyper_vfs)
+        .layer(crate::vfs::Cache::new(16))
+        .register(false)?;
+    let conn = connect(&inst, "layered.db")?;
+    register_log_table(&conn)?;
+    conn.execute_batch("CREATE TABLE notes(body TEXT);")?;
+
+    let blocks: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM hyper_log",
+        rusqlite::NO_PARAMS,
+        |row| row.get(0),
+    )?;
+    let version: String = conn.query_row("PRAGMA hyper_version", rusqlite::NO_PARAMS, |row| {
+        row.get(0)
+    })?;
+    assert!(blocks > 0);
+    assert_eq!(blocks.to_string(), version);
+    Ok(())
+}
+
+#[test]
+fn recovers_batches_cut_short_by_a_crash() -> anyhow::Result<()> {
+    let hyper_vfs = Rc::new(RefCell::new(Vfs::connect(VfsOptions::default())?));
+    let harness = crate::vfs::CrashHarness::new("hyper-crashes", hyper_vfs, "crashes.db")?;
+    harness.connect()?.execute_batch(
+        r#"
+        CREATE TABLE accounts(id INTEGER PRIMARY KEY, balance INTEGER);
+        INSERT INTO accounts(id, balance) VALUES (1, 500), (2, 500);
+        "#,
+    )?;
+    let transfer = r#"
+        BEGIN;
+        UPDATE accounts SET balance = balance - 10 WHERE id = 1;
+        UPDATE accounts SET balance = balance + 10 WHERE id = 2;
+        COMMIT;
+        "#;
+
+    let crashes = harness.run(transfer, |conn| {
+        let (total, moved): (i64, i64) = conn.query_row(
+            "SELECT SUM(balance), 500 - MIN(balance) FROM accounts",
+            rusqlite::NO_PARAMS,
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        anyhow::ensure!(total == 1000, "The accounts add up to {}", total);
+        anyhow::ensure!(moved % 10 == 0, "{} was moved, not whole transfers", moved);
+        Ok(())
+    })?;
+    assert!(crashes > 0);
+    Ok(())
+}
+
+#[test]
+fn times_the_reads_of_a_query() -> anyhow::Result<()> {
+    let hyper_vfs = Rc::new(RefCell::new(Vfs::connect(VfsOptions::default())?));
+    let inst = Instance::builder("hyper-instrumented", hyper_vfs)
+        .instrument()
+        .register(false)?;
+    let conn = connect(&inst, "instrumented.db")?;
+    conn.execute_batch(
+        r#"
+        CREATE TABLE notes(body TEXT);
+        INSERT INTO notes(body) VALUES ('hello'), ('world');
+        "#,
+    )?;
+    let before = inst.borrow().stats().unwrap().files["instrumented.db"].reads;
+    conn.query_row("SELECT COUNT(*) FROM notes", rusqlite::NO_PARAMS, |row| {
+        row.get::<_, i64>(0)
+    })?;
+
+    let reads = inst.borrow().stats().unwrap().files["instrumented.db"].reads;
+    assert!(reads.calls > before.calls);
+    assert!(reads.bytes > before.bytes);
+    assert!(reads.latency.quantile(0.5) <= reads.latency.max());
+    assert!(reads.latency.total() >= before.latency.total());
+    Ok(())
+}
+
+#[test]
+fn waits_for_a_quorum_of_replicas_on_full_syncs() -> anyhow::Result<()> {
+    let hyper_vfs = Rc::new(RefCell::new(Vfs::connect(VfsOptions {
+        durability: Durability::Replicated { quorum: 2 },
+        ..VfsOptions::default()
+    })?));
+    let origin = Instance::new(
+        "hyper-durable",
+        Rc::clone(&hyper_vfs) as Rc<RefCell<dyn System>>,
+    )?;
+    Instance::register(Rc::clone(&origin), false)?;
+    let writer = connect(&origin, "durable.db")?;
+    writer.execute_batch("CREATE TABLE notes(body TEXT);")?;
+    let key: String =
+        writer.query_row("PRAGMA hyper_key", rusqlite::NO_PARAMS, |row| row.get(0))?;
+    let path = format!("hyper://{}", key);
+
+    let peer = hyper_vfs.borrow().peer("durable.db")?;
+    let replicas = (0..2)
+        .map(|_| {
+            Vfs::connect(VfsOptions {
+                peers: vec![Arc::clone(&peer)],
+                ..VfsOptions::default()
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    hyper_vfs
+        .borrow()
+        .replicate_to("durable.db", replicas[0].replica(&path)?)?;
+
+    // A single replica is short of the quorum full syncs wait for, which normal ones don't...
+    writer.execute_batch(
+        "PRAGMA synchronous = NORMAL;
+        INSERT INTO notes(body) VALUES ('flushed');
+        PRAGMA synchronous = FULL;",
+    )?;
+    let unacknowledged = writer.execute(
+        "INSERT INTO notes(body) VALUES ('unacknowledged')",
+        rusqlite::NO_PARAMS,
+    );
+    match unacknowledged {
+        Err(rusqlite::Error::SqliteFailure(error, _)) => {
+            assert_eq!(error.code, rusqlite::ErrorCode::SystemIOFailure)
+        }
+        other => panic!("Expected the sync to fail, got {:?}", other),
+    }
+
+    // ...while a second one makes it.
+    hyper_vfs
+        .borrow()
+        .replicate_to("durable.db", replicas[1].replica(&path)?)?;
+    writer.execute(
+        "INSERT INTO notes(body) VALUES ('acknowledged')",
+        rusqlite::NO_PARAMS,
+    )?;
+    let version: String = writer.query_row("PRAGMA hyper_version", rusqlite::NO_PARAMS, |row| {
+        row.get(0)
+    })?;
+
+    let replica = Rc::new(RefCell::new(replicas.into_iter().nth(1).unwrap()));
+    let inst = Instance::new("hyper-durable-replica", replica as Rc<RefCell<dyn System>>)?;
+    Instance::register(Rc::clone(&inst), false)?;
+    let reader = connect_read_only(&inst, &path)?;
+    let stored: String = reader.query_row("PRAGMA hyper_version", rusqlite::NO_PARAMS, |row| {
+        row.get(0)
+    })?;
+    let acknowledged: i64 = reader.query_row(
+        "SELECT COUNT(*) FROM notes WHERE body = 'acknowledged'",
+        rusqlite::NO_PARAMS,
+        |row| row.get(0),
+    )?;
+    assert_eq!(stored, version);
+    assert_eq!(acknowledged, 1);
+    Ok(())
+}
+
+#[cfg(feature = "session")]
+const NOTES: &str = "CREATE TABLE notes(id INTEGER PRIMARY KEY, body TEXT);";
+
+#[cfg(feature = "session")]
+fn kept_as_changesets(name: &str, peers: Vec<Arc<dyn Peer>>) -> anyhow::Result<Rc<RefCell<Vfs>>> {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let hyper_vfs = Rc::new(RefCell::new(Vfs::connect(VfsOptions {
+        peers,
+        mode: StorageMode::Changesets {
+            schema: NOTES.to_string(),
+        },
+        ..VfsOptions::default()
+    })?));
+    let inst = Instance::new(name, Rc::clone(&hyper_vfs) as Rc<RefCell<dyn System>>)?;
+    Instance::register(inst, false)?;
+    Ok(hyper_vfs)
+}
+
+#[cfg(feature = "session")]
+fn column(conn: &Connection, sql: &str) -> rusqlite::Result<Vec<String>> {
+    conn.prepare(sql)?
+        .query_map(rusqlite::NO_PARAMS, |row| row.get(0))?
+        .collect()
+}
+
+#[cfg(feature = "session")]
+#[test]
+fn keeps_committed_transactions_as_changesets() -> anyhow::Result<()> {
+    let hyper_vfs = kept_as_changesets("hyper-changesets", Vec::default())?;
+    let conn = Connection::open_with_flags_and_vfs(
+        "notes.db",
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        "hyper-changesets",
+    )?;
+    let recorder = hyper_vfs.borrow().recorder(&conn, "notes.db")?;
+    let record = |sql: &str| recorder.record(&conn, |conn| Ok(conn.execute_batch(sql)?));
+
+    record("INSERT INTO notes VALUES (1, 'draft'), (2, 'kept');")?;
+    record("UPDATE notes SET body = 'final' WHERE id = 1;")?;
+    assert_eq!(
+        column(&conn, "SELECT body FROM notes ORDER BY id")?,
+        ["final", "kept"]
+    );
+    register_log_table(&conn)?;
+    assert_eq!(
+        column(&conn, "SELECT kind FROM hyper_log")?,
+        ["changeset", "changeset"]
+    );
+
+    // Nothing else can write to it, since it wouldn't make it into the feed, and neither can
+    // schema changes, which changesets don't carry.
+    assert!(conn
+        .execute("INSERT INTO notes VALUES (3, 'lost')", rusqlite::NO_PARAMS)
+        .is_err());
+    assert!(record("ALTER TABLE notes ADD COLUMN tag TEXT;").is_err());
+    assert_eq!(
+        column(&conn, "SELECT body FROM notes ORDER BY id")?,
+        ["final", "kept"]
+    );
+    assert_eq!(
+        column(&conn, "SELECT kind FROM hyper_log")?,
+        ["changeset", "changeset"]
+    );
+
+    let copy = Connection::open_in_memory()?;
+    copy.execute_batch(NOTES)?;
+    hyper_vfs.borrow().replay_into("notes.db", &copy)?;
+    assert_eq!(
+        column(&copy, "SELECT body FROM notes ORDER BY id")?,
+        ["final", "kept"]
+    );
+    Ok(())
+}
+
+#[cfg(feature = "session")]
+#[test]
+fn builds_databases_kept_as_changesets_from_peers() -> anyhow::Result<()> {
+    let origin = kept_as_changesets("hyper-changeset-origin", Vec::default())?;
+    let writer = Connection::open_with_flags_and_vfs(
+        "shared.db",
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
+        "hyper-changeset-origin",
+    )?;
+    let recorder = origin.borrow().recorder(&writer, "shared.db")?;
+    let record = |sql: &str| recorder.record(&writer, |conn| Ok(conn.execute_batch(sql)?));
+    record("INSERT INTO notes VALUES (1, 'hello');")?;
+    let key: String =
+        writer.query_row("PRAGMA hyper_key", rusqlite::NO_PARAMS, |row| row.get(0))?;
+
+    let peer = origin.borrow().peer("shared.db")?;
+    kept_as_changesets("hyper-changeset-replica", vec![peer])?;
+    let reader = Connection::open_with_flags_and_vfs(
+        format!("hyper://{}", key),
+        OpenFlags::SQLITE_OPEN_READ_ONLY,
+        "hyper-changeset-replica",
+    )?;
+    assert_eq!(column(&reader, "SELECT body FROM notes")?, ["hello"]);
+
+    // Changesets appended later are applied once the reader starts reading again.
+    record("INSERT INTO notes VALUES (2, 'world');")?;
+    assert_eq!(
+        column(&reader, "SELECT body FROM notes ORDER BY id")?,
+        ["hello", "world"]
+    );
+    Ok(())
+}
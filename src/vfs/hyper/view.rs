@@ -0,0 +1,282 @@
+// Databases kept in `StorageMode::Changesets`, whose Hypercores hold the changeset of every
+// transaction committed to them instead of their pages. SQLite reads and writes a copy of the
+// database in memory, built from the schema and the changesets in the feed; a `Recorder` appends
+// the changeset of each transaction once it's committed to the copy. Changesets appended
+// elsewhere (by a peer, or another process sharing the storage) are applied to the copy whenever
+// a connection starts reading it.
+use super::super::{sqlite3, File, MemoryFile};
+use super::{Block, BlockKind, FileState, HyperFile};
+use crate::hyper::changeset::{self, Entry};
+use rusqlite::Connection;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet};
+use std::os::raw;
+use std::rc::Rc;
+
+/// Where SQLite keeps the number of times a database has changed in its header.
+const CHANGE_COUNTER: usize = 24;
+/// Where SQLite keeps the change counter the version number in its header was written at.
+const VERSION_VALID_FOR: usize = 92;
+
+/// The copy of a database kept as changesets that SQLite works on.
+pub(super) struct View {
+    schema: String,
+    data: Rc<RefCell<Vec<u8>>>,
+    contents: MemoryFile,
+    /// How many blocks of the feed have been applied to the copy, once it's been built.
+    applied: Cell<Option<u64>>,
+    /// Blocks past `applied` whose changesets were recorded from the copy, and so are in it.
+    recorded: RefCell<BTreeSet<u64>>,
+}
+
+impl View {
+    pub(super) fn new(schema: &str) -> Self {
+        let data = Rc::default();
+        Self {
+            schema: schema.to_string(),
+            contents: MemoryFile::shared(Rc::clone(&data)),
+            data,
+            applied: Cell::default(),
+            recorded: RefCell::default(),
+        }
+    }
+
+    pub(super) fn contents(&self) -> &dyn File {
+        &self.contents
+    }
+
+    /// Notes that the changeset at `seq` was recorded from the copy.
+    fn recorded(&self, seq: u64) {
+        match self.applied.get() {
+            Some(applied) if applied == seq => self.applied.set(Some(seq + 1)),
+            _ => drop(self.recorded.borrow_mut().insert(seq)),
+        }
+    }
+}
+
+/// Writes to a database kept in `StorageMode::Changesets` through a connection to it; see
+/// `Vfs::recorder`.
+pub struct Recorder {
+    path: String,
+    state: Rc<FileState>,
+}
+
+impl Recorder {
+    pub(super) fn new(path: &str, state: Rc<FileState>) -> Self {
+        Self {
+            path: path.to_string(),
+            state,
+        }
+    }
+
+    /// Runs `change` against `conn` in a transaction and appends what it changed to the feed as a
+    /// changeset once it's committed; the transaction is undone if the append fails. `conn` can't
+    /// be in a transaction already, and `change` can't change the schema.
+    ///
+    /// What's appended is synced as `PRAGMA synchronous` says; a sync that fails is reported
+    /// after the transaction has committed, like it is for databases kept as pages.
+    pub fn record<T>(
+        &self,
+        conn: &Connection,
+        change: impl FnOnce(&Connection) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let recording = |on: bool| {
+            HyperFile::with_handle(conn, &self.path, &self.state, |file| {
+                file.recording.set(on);
+                Ok(())
+            })
+        };
+
+        recording(true)?;
+        let recorded = changeset::record(
+            conn,
+            |conn| {
+                let before = schema_version(conn)?;
+                let value = change(conn)?;
+                if schema_version(conn)? != before {
+                    return Err(anyhow::anyhow!(
+                        "The schema of {:?} can't be changed; changesets don't carry it",
+                        self.path
+                    ));
+                }
+                Ok(value)
+            },
+            |changeset| {
+                HyperFile::with_handle(conn, &self.path, &self.state, |file| {
+                    file.append_changeset(changeset)
+                })
+            },
+        );
+        recording(false)?;
+
+        let value = recorded?;
+        HyperFile::with_handle(conn, &self.path, &self.state, |file| {
+            file.sync(sqlite3::SQLITE_SYNC_FULL)
+        })?;
+        Ok(value)
+    }
+}
+
+fn schema_version(conn: &Connection) -> rusqlite::Result<i64> {
+    conn.query_row("PRAGMA schema_version", rusqlite::NO_PARAMS, |row| {
+        row.get(0)
+    })
+}
+
+impl HyperFile {
+    fn view(&self) -> anyhow::Result<&View> {
+        self.state
+            .view
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("{:?} doesn't keep changesets", self.name))
+    }
+
+    /// Fails writes to the copy that don't come from a `Recorder`, whose changes would never
+    /// make it into the feed.
+    pub(super) fn check_recording(&self) -> anyhow::Result<()> {
+        if self.recording.get() {
+            return Ok(());
+        }
+        Err(
+            anyhow::Error::new(sqlite3::Error::new(sqlite3::SQLITE_READONLY)).context(format!(
+                "{:?} keeps changesets, so it can only be written through a `Recorder`",
+                self.name
+            )),
+        )
+    }
+
+    /// Builds the copy of the database, or brings it up to date with the changesets appended to
+    /// the feed since.
+    pub(super) fn catch_up(&self) -> anyhow::Result<()> {
+        let view = self.view()?;
+        let length = self.state.feed.borrow().len();
+        if view.applied.get() == Some(length) {
+            return Ok(());
+        }
+
+        let conn = Connection::open_in_memory()?;
+        let start = match view.applied.get() {
+            Some(applied) => {
+                deserialize(&conn, &view.data.borrow())?;
+                applied
+            }
+            None => {
+                conn.execute_batch(&view.schema)?;
+                0
+            }
+        };
+        let missing: Vec<_> = (start..length)
+            .filter(|seq| !view.recorded.borrow().contains(seq))
+            .collect();
+        self.replay(&conn, missing)?;
+
+        let mut data = serialize(&conn)?;
+        let previous = counter(&view.data.borrow());
+        // SQLite only drops the pages it has cached when the change counter moves.
+        let changed = (previous.max(counter(&data)) + 1).to_be_bytes();
+        if data.len() >= VERSION_VALID_FOR + 4 {
+            data[CHANGE_COUNTER..CHANGE_COUNTER + 4].copy_from_slice(&changed);
+            data[VERSION_VALID_FOR..VERSION_VALID_FOR + 4].copy_from_slice(&changed);
+        }
+        *view.data.borrow_mut() = data;
+        view.applied.set(Some(length));
+        view.recorded.borrow_mut().retain(|seq| *seq >= length);
+        Ok(())
+    }
+
+    /// Applies the changesets among the blocks at `seqs` to `conn`, all or nothing.
+    pub(super) fn replay(
+        &self,
+        conn: &Connection,
+        seqs: impl IntoIterator<Item = u64>,
+    ) -> anyhow::Result<()> {
+        let changesets = seqs.into_iter().filter_map(|seq| {
+            let changeset = self.block(seq).and_then(|mut block| {
+                if block.kind != BlockKind::Changeset {
+                    return Ok(None);
+                }
+                self.open_block(&mut block)?;
+                Ok(Some(Entry::decode(&block.payload)?.changeset))
+            });
+            changeset
+                .map_err(|error| error.context(format!("Could not read changeset {}", seq)))
+                .transpose()
+        });
+        changeset::replay(conn, changesets)
+    }
+
+    /// Appends the changeset of a transaction that was committed to the copy.
+    pub(super) fn append_changeset(&self, changeset: Vec<u8>) -> anyhow::Result<()> {
+        let entry = Entry {
+            clock: BTreeMap::default(),
+            changeset,
+        };
+        let seq = self.state.feed.borrow().len();
+        self.append(Block::changeset(entry.encode()))?;
+        self.view()?.recorded(seq);
+        Ok(())
+    }
+}
+
+/// The change counter in the header of the database in `data`.
+fn counter(data: &[u8]) -> u32 {
+    data.get(CHANGE_COUNTER..CHANGE_COUNTER + 4)
+        .map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .unwrap_or_default()
+}
+
+/// The main database of `conn`, as it would be in a file.
+fn serialize(conn: &Connection) -> anyhow::Result<Vec<u8>> {
+    let mut size: sqlite3::sqlite3_int64 = 0;
+    // SAFETY: `conn` is open, and what SQLite hands back is copied out before it's freed.
+    unsafe {
+        let data = sqlite3::sqlite3_serialize(
+            conn.handle(),
+            b"main\0".as_ptr() as *const raw::c_char,
+            &mut size,
+            0,
+        );
+        if data.is_null() {
+            // An empty database has nothing to serialize.
+            return match size {
+                0 => Ok(Vec::new()),
+                _ => Err(anyhow::Error::new(sqlite3::Error::new(
+                    sqlite3::SQLITE_NOMEM,
+                ))),
+            };
+        }
+        let copy = std::slice::from_raw_parts(data, size as usize).to_vec();
+        sqlite3::sqlite3_free(data as *mut raw::c_void);
+        Ok(copy)
+    }
+}
+
+/// Replaces the main database of `conn`, which lives in memory, with `data`.
+fn deserialize(conn: &Connection, data: &[u8]) -> anyhow::Result<()> {
+    // A database in memory starts out empty anyway.
+    if data.is_empty() {
+        return Ok(());
+    }
+    // SAFETY: `conn` is open, and SQLite takes over the memory it's handed.
+    unsafe {
+        let buffer = sqlite3::sqlite3_malloc64(data.len() as u64) as *mut u8;
+        if buffer.is_null() {
+            return Err(anyhow::Error::new(sqlite3::Error::new(
+                sqlite3::SQLITE_NOMEM,
+            )));
+        }
+        std::ptr::copy_nonoverlapping(data.as_ptr(), buffer, data.len());
+        let code = sqlite3::sqlite3_deserialize(
+            conn.handle(),
+            b"main\0".as_ptr() as *const raw::c_char,
+            buffer,
+            data.len() as _,
+            data.len() as _,
+            (sqlite3::SQLITE_DESERIALIZE_FREEONCLOSE | sqlite3::SQLITE_DESERIALIZE_RESIZEABLE) as _,
+        );
+        match code {
+            sqlite3::SQLITE_OK => Ok(()),
+            code => Err(anyhow::Error::new(sqlite3::Error::new(code))),
+        }
+    }
+}
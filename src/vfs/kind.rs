@@ -0,0 +1,233 @@
+// What SQLite opens a file for, which it only says through the `SQLITE_OPEN_*` flags it passes to
+// `xOpen`, and where the files it only needs while they're open go.
+use super::{sqlite3, File, FileControl, LockFlag, System};
+use std::any::Any;
+use std::cell::RefCell;
+use std::os::raw;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The kinds of files SQLite opens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    MainDb,
+    MainJournal,
+    TempDb,
+    TempJournal,
+    /// A database that's dropped once closed, like the ones behind `VACUUM INTO` or sorters.
+    TransientDb,
+    /// A statement journal, for rolling back a single statement.
+    Subjournal,
+    /// The journal tying together a transaction over several attached databases.
+    SuperJournal,
+    Wal,
+}
+
+impl FileKind {
+    /// Picks the kind out of the flags SQLite opens a file with, if it's said at all.
+    pub fn of(open_flags: raw::c_int) -> Option<Self> {
+        let kinds = [
+            (sqlite3::SQLITE_OPEN_MAIN_DB, Self::MainDb),
+            (sqlite3::SQLITE_OPEN_MAIN_JOURNAL, Self::MainJournal),
+            (sqlite3::SQLITE_OPEN_TEMP_DB, Self::TempDb),
+            (sqlite3::SQLITE_OPEN_TEMP_JOURNAL, Self::TempJournal),
+            (sqlite3::SQLITE_OPEN_TRANSIENT_DB, Self::TransientDb),
+            (sqlite3::SQLITE_OPEN_SUBJOURNAL, Self::Subjournal),
+            (sqlite3::SQLITE_OPEN_SUPER_JOURNAL, Self::SuperJournal),
+            (sqlite3::SQLITE_OPEN_WAL, Self::Wal),
+        ];
+        kinds
+            .iter()
+            .find(|(flag, _)| open_flags & flag != 0)
+            .map(|(_, kind)| *kind)
+    }
+
+    /// Whether the file is only of use to the connection that opened it, and only while it's
+    /// open.
+    pub fn is_temporary(self) -> bool {
+        matches!(
+            self,
+            Self::TempDb | Self::TempJournal | Self::TransientDb | Self::Subjournal
+        )
+    }
+}
+
+/// Where an `Instance` puts the temporary files SQLite opens (see `FileKind::is_temporary`),
+/// along with any it opens without a name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TempFiles {
+    /// In memory, without the filesystem hearing of them.
+    #[default]
+    Memory,
+    /// With the parent VFS of the `Instance` (see `Instance::set_parent`), which is usually the
+    /// operating system's.
+    Os,
+    /// With the filesystem, like any other file; the ones without a name get one.
+    System,
+}
+
+/// A name for a temporary file SQLite didn't name, unique within the process.
+pub(crate) fn temporary_name() -> String {
+    static OPENED: AtomicU64 = AtomicU64::new(0);
+    format!(
+        "sqlite-temp-{}-{}",
+        std::process::id(),
+        OPENED.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// A file held in memory, which goes away with the last handle on its contents.
+#[derive(Default)]
+pub struct MemoryFile {
+    data: Rc<RefCell<Vec<u8>>>,
+}
+
+impl MemoryFile {
+    /// A file over `data`, which other handles can share.
+    pub fn shared(data: Rc<RefCell<Vec<u8>>>) -> Self {
+        Self { data }
+    }
+}
+
+impl File for MemoryFile {
+    fn close(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn read(&self, amount: raw::c_int, offset: sqlite3::sqlite3_int64) -> anyhow::Result<Vec<u8>> {
+        let data = self.data.borrow();
+        let start = (offset as usize).min(data.len());
+        let end = (start + amount as usize).min(data.len());
+        Ok(data[start..end].to_vec())
+    }
+
+    fn write(
+        &self,
+        data: Vec<u8>,
+        amount: raw::c_int,
+        offset: sqlite3::sqlite3_int64,
+    ) -> anyhow::Result<raw::c_int> {
+        let mut contents = self.data.borrow_mut();
+        let (start, amount) = (offset as usize, (amount as usize).min(data.len()));
+        if contents.len() < start + amount {
+            contents.resize(start + amount, 0);
+        }
+        contents[start..start + amount].copy_from_slice(&data[..amount]);
+        Ok(amount as raw::c_int)
+    }
+
+    fn truncate(&self, length: sqlite3::sqlite3_int64) -> anyhow::Result<()> {
+        self.data.borrow_mut().truncate(length as usize);
+        Ok(())
+    }
+
+    fn sync(&self, _flags: raw::c_int) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn size(&self) -> anyhow::Result<sqlite3::sqlite3_int64> {
+        Ok(self.data.borrow().len() as _)
+    }
+
+    // Only the connection that opened a temporary file uses it, and SQLite doesn't lock the
+    // journals that are shared.
+    fn lock(&self, _flag: LockFlag) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn unlock(&self, _flag: LockFlag) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn check_reserved_lock(&self) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+
+    fn file_control(&self, _control: FileControl) -> anyhow::Result<Option<String>> {
+        Err(FileControl::not_found())
+    }
+
+    fn sector_size(&self) -> raw::c_int {
+        512
+    }
+
+    fn device_characteristics(&self) -> Vec<raw::c_int> {
+        // Nothing's left half-written in memory.
+        vec![
+            sqlite3::SQLITE_IOCAP_ATOMIC,
+            sqlite3::SQLITE_IOCAP_SAFE_APPEND,
+            sqlite3::SQLITE_IOCAP_SEQUENTIAL,
+            sqlite3::SQLITE_IOCAP_POWERSAFE_OVERWRITE,
+        ]
+    }
+}
+
+/// A file opened with `SQLITE_OPEN_DELETEONCLOSE`, which the filesystem deletes once it's closed.
+pub(crate) struct DeleteOnClose {
+    pub(crate) file: Rc<RefCell<dyn File>>,
+    pub(crate) system: Rc<RefCell<dyn System>>,
+    pub(crate) path: String,
+}
+
+impl File for DeleteOnClose {
+    fn close(&self) -> anyhow::Result<()> {
+        self.file.close()?;
+        self.system
+            .borrow_mut()
+            .delete(&self.path, false)
+            .map_err(|code| anyhow::anyhow!("Could not delete {:?}: {:?}", self.path, code))
+    }
+
+    fn read(&self, amount: raw::c_int, offset: sqlite3::sqlite3_int64) -> anyhow::Result<Vec<u8>> {
+        self.file.read(amount, offset)
+    }
+
+    fn write(
+        &self,
+        data: Vec<u8>,
+        amount: raw::c_int,
+        offset: sqlite3::sqlite3_int64,
+    ) -> anyhow::Result<raw::c_int> {
+        self.file.write(data, amount, offset)
+    }
+
+    fn truncate(&self, length: sqlite3::sqlite3_int64) -> anyhow::Result<()> {
+        self.file.truncate(length)
+    }
+
+    fn sync(&self, flags: raw::c_int) -> anyhow::Result<()> {
+        self.file.sync(flags)
+    }
+
+    fn size(&self) -> anyhow::Result<sqlite3::sqlite3_int64> {
+        self.file.size()
+    }
+
+    fn lock(&self, flag: LockFlag) -> anyhow::Result<()> {
+        self.file.lock(flag)
+    }
+
+    fn unlock(&self, flag: LockFlag) -> anyhow::Result<()> {
+        self.file.unlock(flag)
+    }
+
+    fn check_reserved_lock(&self) -> anyhow::Result<bool> {
+        self.file.check_reserved_lock()
+    }
+
+    fn file_control(&self, control: FileControl) -> anyhow::Result<Option<String>> {
+        self.file.file_control(control)
+    }
+
+    fn sector_size(&self) -> raw::c_int {
+        self.file.sector_size()
+    }
+
+    fn device_characteristics(&self) -> Vec<raw::c_int> {
+        self.file.device_characteristics()
+    }
+
+    fn as_any(&self) -> Option<&dyn Any> {
+        self.file.as_any()
+    }
+}
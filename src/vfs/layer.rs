@@ -0,0 +1,166 @@
+// Behavior that can sit on top of any filesystem, like caching or fault injection, stacked with
+// `Instance::builder` instead of being built into each `System`.
+use super::{
+    sqlite3, AccessFlag, File, FileKind, Instance, Instrument, System, TempFiles, VfsStats,
+    WrappedFile,
+};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::os::raw;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// Something to wrap around a `System` and the files it opens.
+///
+/// Most layers only need to implement `file`; the ones that also change what the filesystem
+/// itself does (like deleting files) override `wrap`.
+pub trait Layer: 'static {
+    /// Wraps `file`, just opened at `path` by the filesystem below.
+    fn file(
+        &self,
+        path: &str,
+        file: Rc<RefCell<dyn File>>,
+    ) -> anyhow::Result<Rc<RefCell<dyn File>>>;
+
+    /// Wraps `system`, returning the filesystem to hand to SQLite (or to the next layer).
+    fn wrap(self, system: Rc<RefCell<dyn System>>) -> Rc<RefCell<dyn System>>
+    where
+        Self: Sized,
+    {
+        Rc::new(RefCell::new(Layered {
+            layer: self,
+            system,
+        }))
+    }
+}
+
+/// A filesystem whose files are all wrapped by `layer`.
+struct Layered<L: Layer> {
+    layer: L,
+    system: Rc<RefCell<dyn System>>,
+}
+
+impl<L: Layer> System for Layered<L> {
+    fn open(
+        &self,
+        path: &str,
+        kind: FileKind,
+        open_flags: &rusqlite::OpenFlags,
+        parameters: &HashMap<String, String>,
+    ) -> Result<Box<WrappedFile>, sqlite3::ErrorCode> {
+        let file = self
+            .system
+            .borrow()
+            .open(path, kind, open_flags, parameters)?;
+        let handle = match file.file() {
+            Some(handle) => handle,
+            None => return Ok(file),
+        };
+        match self.layer.file(path, Rc::clone(&handle)) {
+            Ok(layered) => Ok(Box::new(WrappedFile::wrap(layered))),
+            Err(error) => {
+                log::error!("Could not wrap {:?}: {:#}", path, error);
+                let _ = handle.borrow().close();
+                Err(sqlite3::ErrorCode::CannotOpen)
+            }
+        }
+    }
+
+    fn delete(&mut self, path: &str, sync_to_system: bool) -> Result<(), sqlite3::ErrorCode> {
+        self.system.borrow_mut().delete(path, sync_to_system)
+    }
+
+    fn access(&self, path: &str, access_flags: &[AccessFlag]) -> Result<(), sqlite3::ErrorCode> {
+        self.system.borrow().access(path, access_flags)
+    }
+
+    fn full_pathname(&self, path: &str) -> Result<String, sqlite3::ErrorCode> {
+        self.system.borrow().full_pathname(path)
+    }
+
+    fn randomness(&self, buffer: &mut [u8]) -> Option<usize> {
+        self.system.borrow().randomness(buffer)
+    }
+
+    fn sleep(&self, duration: Duration) -> Option<Duration> {
+        self.system.borrow().sleep(duration)
+    }
+
+    fn current_time(&self) -> Option<f64> {
+        self.system.borrow().current_time()
+    }
+}
+
+/// Puts together an `Instance` from a filesystem and the layers stacked on it; each layer wraps
+/// the ones added before it.
+pub struct VfsBuilder {
+    name: String,
+    system: Rc<RefCell<dyn System>>,
+    max_pathname: Option<raw::c_int>,
+    parent: Option<String>,
+    temp_files: TempFiles,
+    stats: Option<Rc<RefCell<VfsStats>>>,
+}
+
+impl VfsBuilder {
+    pub fn new(name: impl ToString, system: Rc<RefCell<dyn System>>) -> Self {
+        Self {
+            name: name.to_string(),
+            system,
+            max_pathname: None,
+            parent: None,
+            temp_files: TempFiles::default(),
+            stats: None,
+        }
+    }
+
+    pub fn layer(mut self, layer: impl Layer) -> Self {
+        self.system = layer.wrap(self.system);
+        self
+    }
+
+    /// Adds an `Instrument` layer, whose stats are kept by the `Instance` (see `Instance::stats`).
+    pub fn instrument(mut self) -> Self {
+        let instrument = Instrument::new();
+        self.stats = Some(instrument.stats());
+        self.layer(instrument)
+    }
+
+    /// See `Instance::set_max_pathname`.
+    pub fn max_pathname(mut self, max_pathname: raw::c_int) -> Self {
+        self.max_pathname = Some(max_pathname);
+        self
+    }
+
+    /// See `Instance::set_parent`.
+    pub fn parent(mut self, vfs_name: impl ToString) -> Self {
+        self.parent = Some(vfs_name.to_string());
+        self
+    }
+
+    /// See `Instance::set_temp_files`.
+    pub fn temp_files(mut self, temp_files: TempFiles) -> Self {
+        self.temp_files = temp_files;
+        self
+    }
+
+    pub fn build(self) -> anyhow::Result<Rc<RefCell<Instance>>> {
+        let instance = Instance::new(self.name, self.system)?;
+        instance.borrow_mut().stats = self.stats;
+        instance.borrow_mut().set_temp_files(self.temp_files)?;
+        if let Some(parent) = &self.parent {
+            instance.borrow_mut().set_parent(parent)?;
+        }
+        if let Some(max_pathname) = self.max_pathname {
+            instance.borrow_mut().set_max_pathname(max_pathname)?;
+        }
+        Ok(instance)
+    }
+
+    /// Builds the `Instance` and registers it with SQLite right away.
+    pub fn register(self, make_default: bool) -> anyhow::Result<Rc<RefCell<Instance>>> {
+        let instance = self.build()?;
+        Instance::register(Rc::clone(&instance), make_default)?;
+        Ok(instance)
+    }
+}
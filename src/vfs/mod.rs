@@ -2,17 +2,37 @@
 use rusqlite::ffi as sqlite3;
 use std::cell::RefCell;
 use std::ffi::CString;
-use std::mem::{self, MaybeUninit};
+use std::mem;
 use std::os::raw;
 use std::ptr::NonNull;
 use std::rc::Rc;
 
+pub(crate) mod api;
+pub mod backup;
+mod cache;
+pub(crate) mod control;
+mod faulty;
 mod file;
+#[cfg(feature = "hypercore")]
+pub mod hyper;
+mod kind;
+mod layer;
+mod stats;
 mod system;
 
+pub use cache::{Cache, CacheStats, CachedFile};
+pub use control::FileControl;
+pub use faulty::{CrashHarness, FaultyFilesystem};
 pub use file::VirtualFile as File;
+pub use file::WrappedFile;
+pub use kind::{FileKind, MemoryFile, TempFiles};
+pub use layer::{Layer, VfsBuilder};
+pub use stats::{CallStats, FileStats, Histogram, Instrument, VfsStats};
 pub use system::VirtualFilesystem as System;
 
+/// The default value of `mxPathname` advertised to SQLite for an `Instance`.
+pub const DEFAULT_MAX_PATHNAME: raw::c_int = 1024;
+
 /// Represents the access level of a file.
 // FIXME: Turn into a bitwise flag.
 pub enum AccessFlag {
@@ -25,6 +45,7 @@ pub enum AccessFlag {
 }
 
 // FIXME: Turn into a bitwise flag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum LockFlag {
     None = sqlite3::SQLITE_LOCK_NONE as _,
     Shared = sqlite3::SQLITE_LOCK_SHARED as _,
@@ -39,6 +60,12 @@ pub struct Instance {
     ptr: sqlite3::sqlite3_vfs,
     fs: Rc<RefCell<dyn System>>,
     vfs_name: CString,
+    max_pathname: raw::c_int,
+    stats: Option<Rc<RefCell<VfsStats>>>,
+    temp_files: TempFiles,
+    /// The VFS handed what the filesystem leaves alone; SQLite's default one when this one was
+    /// registered, unless `set_parent` picked another.
+    parent: *mut sqlite3::sqlite3_vfs,
 }
 
 impl Instance {
@@ -46,25 +73,100 @@ impl Instance {
         vfs_name: impl ToString,
         filesystem: Rc<RefCell<dyn System>>,
     ) -> anyhow::Result<Rc<RefCell<Self>>> {
-        let vfs: sqlite3::sqlite3_vfs = unsafe { MaybeUninit::uninit().assume_init() };
+        let vfs: sqlite3::sqlite3_vfs = unsafe { mem::zeroed() };
         Ok(Rc::new(RefCell::new(Self {
             ptr: vfs,
             fs: Rc::clone(&filesystem),
             vfs_name: CString::new(vfs_name.to_string().into_bytes())?,
+            max_pathname: DEFAULT_MAX_PATHNAME,
+            stats: None,
+            temp_files: TempFiles::default(),
+            parent: std::ptr::null_mut(),
         })))
     }
 
+    /// Starts putting together an `Instance` with layers on top of `filesystem`.
+    pub fn builder(vfs_name: impl ToString, filesystem: Rc<RefCell<dyn System>>) -> VfsBuilder {
+        VfsBuilder::new(vfs_name, filesystem)
+    }
+
+    /// The longest path (in bytes, excluding the NUL terminator) that SQLite will ask this VFS to
+    /// resolve.
+    pub fn max_pathname(&self) -> raw::c_int {
+        self.max_pathname
+    }
+
+    /// Sets the value of `mxPathname` for this VFS; it has to happen before registration since
+    /// SQLite sizes its path buffers from it.
+    pub fn set_max_pathname(&mut self, max_pathname: raw::c_int) -> anyhow::Result<()> {
+        if self.registered() {
+            Err(anyhow::anyhow!(
+                "Cannot resize the path names of a registered VFS"
+            ))
+        } else if max_pathname <= 0 {
+            Err(anyhow::anyhow!(
+                "The maximum path name length has to be positive"
+            ))
+        } else {
+            self.max_pathname = max_pathname;
+            Ok(())
+        }
+    }
+
+    /// Where temporary files go; in memory by default.
+    pub fn temp_files(&self) -> TempFiles {
+        self.temp_files
+    }
+
+    /// Sets where temporary files go. It has to happen before registration, since SQLite sizes
+    /// its files for the VFS that might open them.
+    pub fn set_temp_files(&mut self, temp_files: TempFiles) -> anyhow::Result<()> {
+        if self.registered() {
+            Err(anyhow::anyhow!(
+                "Cannot move the temporary files of a registered VFS"
+            ))
+        } else {
+            self.temp_files = temp_files;
+            Ok(())
+        }
+    }
+
+    /// Sets the VFS, registered with SQLite as `vfs_name`, that loads extensions, makes random
+    /// bytes, sleeps and tells the time unless the filesystem does (and opens temporary files
+    /// under `TempFiles::Os`). It has to happen before registration.
+    pub fn set_parent(&mut self, vfs_name: &str) -> anyhow::Result<()> {
+        let name = CString::new(vfs_name)?;
+        let parent = unsafe { api::vfs_find(name.as_ptr()) };
+        if self.registered() {
+            Err(anyhow::anyhow!(
+                "Cannot change the parent of a registered VFS"
+            ))
+        } else if parent.is_null() {
+            Err(anyhow::anyhow!(
+                "There's no VFS registered as {:?}",
+                vfs_name
+            ))
+        } else if name == self.vfs_name {
+            Err(anyhow::anyhow!("A VFS cannot be its own parent"))
+        } else {
+            self.parent = parent;
+            Ok(())
+        }
+    }
+
     /// The name of the VFS.
     pub fn vfs_name(&self) -> Option<String> {
         CString::into_string(self.vfs_name.clone()).ok()
     }
 
     pub fn filesystem(&self) -> Rc<RefCell<dyn System>> {
-        if self.fs.as_ptr().is_null() {
-            panic!("Somehow, we lost the pointer.");
-        } else {
-            Rc::clone(&self.fs)
-        }
+        Rc::clone(&self.fs)
+    }
+
+    /// What's been asked of each file so far, if the VFS was built with
+    /// `VfsBuilder::instrument`.
+    pub fn stats(&self) -> Option<VfsStats> {
+        self.stats.as_ref().map(|stats| stats.borrow().clone())
     }
 
     fn into_raw(instance_rc: Rc<RefCell<Self>>) -> *mut raw::c_void {
@@ -75,7 +177,12 @@ impl Instance {
         if !instance_rc.borrow().registered() {
             {
                 let mut instance_mut = instance_rc.borrow_mut();
-                system::bind(&mut instance_mut.ptr);
+                let max_pathname = instance_mut.max_pathname;
+                if instance_mut.parent.is_null() {
+                    instance_mut.parent = unsafe { api::vfs_find(std::ptr::null()) };
+                }
+                let parent = instance_mut.parent;
+                system::bind(&mut instance_mut.ptr, max_pathname, parent);
                 instance_mut.ptr.zName = instance_mut.vfs_name.as_ptr() as _;
                 instance_mut.ptr.pAppData = Self::into_raw(Rc::clone(&instance_rc));
             }
@@ -87,7 +194,7 @@ impl Instance {
             // FIXME: Look into leaning on rusqlite to handle error reporting from SQLite.
 
             let register_result = unsafe {
-                sqlite3::sqlite3_vfs_register(
+                api::vfs_register(
                     &mut instance_rc.borrow_mut().ptr,
                     make_default as raw::c_int,
                 )
@@ -114,7 +221,7 @@ impl Instance {
     }
 
     pub fn unregister(instance: &mut Self) -> anyhow::Result<()> {
-        let unregister_result = unsafe { sqlite3::sqlite3_vfs_unregister(&mut instance.ptr) };
+        let unregister_result = unsafe { api::vfs_unregister(&mut instance.ptr) };
 
         if unregister_result == sqlite3::SQLITE_OK as _ {
             log::info!(
@@ -134,7 +241,7 @@ impl Instance {
 
     /// Checks to see if the `VirtualFilesystem` held by this instance has been registered.
     pub fn registered(&self) -> bool {
-        NonNull::new(unsafe { sqlite3::sqlite3_vfs_find(self.vfs_name.as_ptr() as _) }).is_some()
+        NonNull::new(unsafe { api::vfs_find(self.vfs_name.as_ptr() as _) }).is_some()
     }
 }
 
@@ -143,7 +250,53 @@ impl Drop for Instance {
         if self.registered() {
             assert!(Instance::unregister(self).is_ok())
         }
-        mem::drop(self.ptr);
+    }
+}
+
+thread_local! {
+    /// Why the last VFS method that failed on this thread did, for `xGetLastError`.
+    static LAST_ERROR: RefCell<Option<(raw::c_int, String)>> = const { RefCell::new(None) };
+}
+
+/// Remembers why a VFS method is about to fail with `code`.
+pub(crate) fn set_last_error(code: raw::c_int, message: String) {
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some((code, message)));
+}
+
+/// The result code and message of the last VFS method that failed on this thread.
+pub(crate) fn last_error() -> Option<(raw::c_int, String)> {
+    LAST_ERROR.with(|last_error| last_error.borrow().clone())
+}
+
+/// Converts an `ErrorCode` into the primary result code SQLite expects back from a VFS method.
+pub(crate) fn result_code(code: sqlite3::ErrorCode) -> raw::c_int {
+    use sqlite3::ErrorCode;
+
+    match code {
+        ErrorCode::InternalMalfunction => sqlite3::SQLITE_INTERNAL,
+        ErrorCode::PermissionDenied => sqlite3::SQLITE_PERM,
+        ErrorCode::OperationAborted => sqlite3::SQLITE_ABORT,
+        ErrorCode::DatabaseBusy => sqlite3::SQLITE_BUSY,
+        ErrorCode::DatabaseLocked => sqlite3::SQLITE_LOCKED,
+        ErrorCode::OutOfMemory => sqlite3::SQLITE_NOMEM,
+        ErrorCode::ReadOnly => sqlite3::SQLITE_READONLY,
+        ErrorCode::OperationInterrupted => sqlite3::SQLITE_INTERRUPT,
+        ErrorCode::SystemIOFailure => sqlite3::SQLITE_IOERR,
+        ErrorCode::DatabaseCorrupt => sqlite3::SQLITE_CORRUPT,
+        ErrorCode::NotFound => sqlite3::SQLITE_NOTFOUND,
+        ErrorCode::DiskFull => sqlite3::SQLITE_FULL,
+        ErrorCode::CannotOpen => sqlite3::SQLITE_CANTOPEN,
+        ErrorCode::FileLockingProtocolFailed => sqlite3::SQLITE_PROTOCOL,
+        ErrorCode::SchemaChanged => sqlite3::SQLITE_SCHEMA,
+        ErrorCode::TooBig => sqlite3::SQLITE_TOOBIG,
+        ErrorCode::ConstraintViolation => sqlite3::SQLITE_CONSTRAINT,
+        ErrorCode::TypeMismatch => sqlite3::SQLITE_MISMATCH,
+        ErrorCode::APIMisuse => sqlite3::SQLITE_MISUSE,
+        ErrorCode::NoLargeFileSupport => sqlite3::SQLITE_NOLFS,
+        ErrorCode::AuthorizationForStatementDenied => sqlite3::SQLITE_AUTH,
+        ErrorCode::ParameterOutOfRange => sqlite3::SQLITE_RANGE,
+        ErrorCode::NotADatabase => sqlite3::SQLITE_NOTADB,
+        _ => sqlite3::SQLITE_ERROR,
     }
 }
 
@@ -0,0 +1,331 @@
+// Counts and timings of what SQLite asks of each file, for finding out where a slow query spends
+// its time (say, fetching pages of a Hypercore from peers, or waiting on locks). With the
+// `tracing` feature, each call is also a span.
+use super::{sqlite3, File, FileControl, Layer, LockFlag};
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::os::raw;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Calls are sorted by whether they took under 1µs, 2µs, 4µs and so on up to about 8 seconds,
+/// with the last bucket holding every slower one.
+const BUCKETS: usize = 25;
+
+/// How long calls took, in buckets of powers of two microseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Histogram {
+    counts: [u64; BUCKETS],
+    total: Duration,
+    max: Duration,
+}
+
+impl Histogram {
+    pub(super) fn record(&mut self, elapsed: Duration) {
+        let micros = elapsed.as_micros() as u64;
+        let bucket = (64 - micros.leading_zeros() as usize).min(BUCKETS - 1);
+        self.counts[bucket] += 1;
+        self.total += elapsed;
+        self.max = self.max.max(elapsed);
+    }
+
+    fn merge(&mut self, other: &Self) {
+        for (count, other) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *count += other;
+        }
+        self.total += other.total;
+        self.max = self.max.max(other.max);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn total(&self) -> Duration {
+        self.total
+    }
+
+    pub fn mean(&self) -> Duration {
+        match self.count() {
+            0 => Duration::default(),
+            count => Duration::from_nanos((self.total.as_nanos() / count as u128) as u64),
+        }
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// How long it took for up to `quantile` (between 0 and 1) of the calls to finish, rounded up
+    /// to a bucket (and capped by the slowest call).
+    pub fn quantile(&self, quantile: f64) -> Duration {
+        let wanted = (self.count() as f64 * quantile.clamp(0.0, 1.0)).ceil() as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= wanted.max(1) {
+                return match bound(bucket) {
+                    Some(bound) => bound.min(self.max),
+                    None => self.max,
+                };
+            }
+        }
+        self.max
+    }
+
+    /// How many calls finished under each bound, in order; the last bucket has no bound, and
+    /// holds the calls slower than every other.
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .map(|(bucket, count)| (bound(bucket), *count))
+    }
+}
+
+/// What the calls in `bucket` took less than, unless it's the last one.
+fn bound(bucket: usize) -> Option<Duration> {
+    match bucket {
+        bucket if bucket < BUCKETS - 1 => Some(Duration::from_micros(1 << bucket)),
+        _ => None,
+    }
+}
+
+/// One kind of call made to a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CallStats {
+    pub calls: u64,
+    pub failures: u64,
+    /// Bytes read or written.
+    pub bytes: u64,
+    pub latency: Histogram,
+}
+
+impl CallStats {
+    fn merge(&mut self, other: &Self) {
+        self.calls += other.calls;
+        self.failures += other.failures;
+        self.bytes += other.bytes;
+        self.latency.merge(&other.latency);
+    }
+}
+
+/// What was asked of one file, through every handle opened on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FileStats {
+    pub reads: CallStats,
+    pub writes: CallStats,
+    pub syncs: CallStats,
+    /// Bytes are the length the file was cut to.
+    pub truncates: CallStats,
+    pub locks: CallStats,
+    pub unlocks: CallStats,
+    /// Locks turned down with `SQLITE_BUSY`; each retry SQLite's busy handler makes counts again.
+    pub busy: u64,
+}
+
+impl FileStats {
+    fn merge(&mut self, other: &Self) {
+        self.reads.merge(&other.reads);
+        self.writes.merge(&other.writes);
+        self.syncs.merge(&other.syncs);
+        self.truncates.merge(&other.truncates);
+        self.locks.merge(&other.locks);
+        self.unlocks.merge(&other.unlocks);
+        self.busy += other.busy;
+    }
+}
+
+/// What was asked of every file opened through an `Instrument` layer, by path.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct VfsStats {
+    pub files: BTreeMap<String, FileStats>,
+}
+
+impl VfsStats {
+    /// The stats of every file added up.
+    pub fn total(&self) -> FileStats {
+        let mut total = FileStats::default();
+        self.files.values().for_each(|file| total.merge(file));
+        total
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Call {
+    Read,
+    Write,
+    Sync,
+    Truncate,
+    Lock,
+    Unlock,
+}
+
+impl Call {
+    fn of(self, stats: &mut FileStats) -> &mut CallStats {
+        match self {
+            Self::Read => &mut stats.reads,
+            Self::Write => &mut stats.writes,
+            Self::Sync => &mut stats.syncs,
+            Self::Truncate => &mut stats.truncates,
+            Self::Lock => &mut stats.locks,
+            Self::Unlock => &mut stats.unlocks,
+        }
+    }
+}
+
+fn is_busy(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<sqlite3::Error>()
+        .is_some_and(|error| error.extended_code & 0xff == sqlite3::SQLITE_BUSY)
+}
+
+struct InstrumentedFile {
+    file: Rc<RefCell<dyn File>>,
+    path: String,
+    stats: Rc<RefCell<VfsStats>>,
+}
+
+impl InstrumentedFile {
+    fn measure<T>(
+        &self,
+        call: Call,
+        bytes: impl FnOnce(&T) -> u64,
+        make: impl FnOnce() -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!("vfs", call = ?call, file = %self.path).entered();
+        let start = Instant::now();
+        let result = make();
+        let elapsed = start.elapsed();
+
+        let mut stats = self.stats.borrow_mut();
+        let file = stats.files.entry(self.path.clone()).or_default();
+        let busy = matches!(&result, Err(error) if is_busy(error));
+        file.busy += busy as u64;
+        let call_stats = call.of(file);
+        call_stats.calls += 1;
+        call_stats.latency.record(elapsed);
+        match &result {
+            Ok(value) => call_stats.bytes += bytes(value),
+            Err(_) => call_stats.failures += 1,
+        }
+        #[cfg(feature = "tracing")]
+        tracing::trace!(
+            micros = elapsed.as_micros() as u64,
+            ok = result.is_ok(),
+            busy,
+        );
+        result
+    }
+}
+
+impl File for InstrumentedFile {
+    fn close(&self) -> anyhow::Result<()> {
+        self.file.close()
+    }
+
+    fn read(&self, amount: raw::c_int, offset: sqlite3::sqlite3_int64) -> anyhow::Result<Vec<u8>> {
+        self.measure(
+            Call::Read,
+            |data: &Vec<u8>| data.len() as u64,
+            || self.file.read(amount, offset),
+        )
+    }
+
+    fn write(
+        &self,
+        data: Vec<u8>,
+        amount: raw::c_int,
+        offset: sqlite3::sqlite3_int64,
+    ) -> anyhow::Result<raw::c_int> {
+        self.measure(
+            Call::Write,
+            |written: &raw::c_int| *written as u64,
+            || self.file.write(data, amount, offset),
+        )
+    }
+
+    fn truncate(&self, length: sqlite3::sqlite3_int64) -> anyhow::Result<()> {
+        self.measure(
+            Call::Truncate,
+            |_| length as u64,
+            || self.file.truncate(length),
+        )
+    }
+
+    fn sync(&self, flags: raw::c_int) -> anyhow::Result<()> {
+        self.measure(Call::Sync, |_| 0, || self.file.sync(flags))
+    }
+
+    fn size(&self) -> anyhow::Result<sqlite3::sqlite3_int64> {
+        self.file.size()
+    }
+
+    fn lock(&self, flag: LockFlag) -> anyhow::Result<()> {
+        self.measure(Call::Lock, |_| 0, || self.file.lock(flag))
+    }
+
+    fn unlock(&self, flag: LockFlag) -> anyhow::Result<()> {
+        self.measure(Call::Unlock, |_| 0, || self.file.unlock(flag))
+    }
+
+    fn check_reserved_lock(&self) -> anyhow::Result<bool> {
+        self.file.check_reserved_lock()
+    }
+
+    fn file_control(&self, control: FileControl) -> anyhow::Result<Option<String>> {
+        self.file.file_control(control)
+    }
+
+    fn sector_size(&self) -> raw::c_int {
+        self.file.sector_size()
+    }
+
+    fn device_characteristics(&self) -> Vec<raw::c_int> {
+        self.file.device_characteristics()
+    }
+
+    fn as_any(&self) -> Option<&dyn Any> {
+        self.file.as_any()
+    }
+}
+
+/// A `Layer` recording what's asked of every file into one `VfsStats`. It sees the calls that
+/// reach the layers added before it.
+pub struct Instrument {
+    stats: Rc<RefCell<VfsStats>>,
+}
+
+impl Instrument {
+    pub fn new() -> Self {
+        Self {
+            stats: Rc::default(),
+        }
+    }
+
+    pub fn stats(&self) -> Rc<RefCell<VfsStats>> {
+        Rc::clone(&self.stats)
+    }
+}
+
+impl Default for Instrument {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Layer for Instrument {
+    fn file(
+        &self,
+        path: &str,
+        file: Rc<RefCell<dyn File>>,
+    ) -> anyhow::Result<Rc<RefCell<dyn File>>> {
+        Ok(Rc::new(RefCell::new(InstrumentedFile {
+            file,
+            path: path.to_string(),
+            stats: Rc::clone(&self.stats),
+        })))
+    }
+}
@@ -1,57 +1,93 @@
-use super::{file::WrappedFile, sqlite3, AccessFlag, Instance};
-use std::{mem, os::raw};
+use super::{
+    api, file::WrappedFile, kind, last_error, result_code, sqlite3, AccessFlag, FileKind, Instance,
+    TempFiles,
+};
+use std::{collections::HashMap, mem, os::raw, time::Duration};
 
 pub trait VirtualFilesystem {
-    /// Called when SQLite is attempting to open a file on the system.
+    /// Called when SQLite is attempting to open a file on the system, for what `kind` says.
+    /// `parameters` holds the URI query parameters (like `key=`) that were provided when opening
+    /// the database. Temporary files only get here if the `Instance` hands them over (see
+    /// `TempFiles::System`).
     fn open(
         &self,
         path: &str,
+        kind: FileKind,
         open_flags: &rusqlite::OpenFlags,
+        parameters: &HashMap<String, String>,
     ) -> Result<Box<WrappedFile>, sqlite3::ErrorCode>;
 
     /// Called when SQLite is attempting to delete a file on the system.
     fn delete(&mut self, path: &str, sync_to_system: bool) -> Result<(), sqlite3::ErrorCode>;
 
     /// Called when SQLite is attempting to determine access information about a file on the
-    /// system.
+    /// system. Returning `ErrorCode::NotFound` or `ErrorCode::PermissionDenied` tells SQLite that
+    /// the access isn't possible; any other error is reported as an I/O failure.
     fn access(&self, path: &str, access_flags: &[AccessFlag]) -> Result<(), sqlite3::ErrorCode>;
 
     /// Called to obtain the full path name of the provided string from the filesystem.
     fn full_pathname(&self, path: &str) -> Result<String, sqlite3::ErrorCode>;
+
+    /// Fills `buffer` with random bytes, returning how many it filled, or `None` to leave it to
+    /// the parent VFS (see `Instance::set_parent`), as the methods below do by default.
+    fn randomness(&self, _buffer: &mut [u8]) -> Option<usize> {
+        None
+    }
+
+    /// Sleeps for at least `duration`, returning how long it did.
+    fn sleep(&self, _duration: Duration) -> Option<Duration> {
+        None
+    }
+
+    /// The current time, as a Julian day number (with fractions of days).
+    fn current_time(&self) -> Option<f64> {
+        None
+    }
 }
 
 mod funcs {
     use std::cell::RefCell;
+    use std::collections::HashMap;
     use std::ffi::{c_void, CStr};
-    use std::mem::zeroed;
-    use std::os::raw::{c_char, c_double, c_int, c_schar};
+    use std::os::raw::{c_char, c_double, c_int};
     use std::rc::Rc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use std::{ptr, slice, thread};
 
     use rusqlite::OpenFlags;
 
     use super::{
-        sqlite3::{sqlite3_file, sqlite3_vfs, SQLITE_IOERR_CLOSE, SQLITE_OK},
-        Instance,
+        api,
+        kind::{temporary_name, DeleteOnClose, MemoryFile},
+        last_error, result_code,
+        sqlite3::{
+            sqlite3_file, sqlite3_vfs, ErrorCode, SQLITE_ACCESS_EXISTS, SQLITE_ACCESS_READ,
+            SQLITE_ACCESS_READWRITE, SQLITE_CANTOPEN, SQLITE_IOERR_ACCESS, SQLITE_IOERR_DELETE,
+            SQLITE_IOERR_DELETE_NOENT, SQLITE_OK, SQLITE_OPEN_DELETEONCLOSE, SQLITE_OPEN_MAIN_DB,
+            SQLITE_OPEN_MAIN_JOURNAL, SQLITE_OPEN_WAL,
+        },
+        AccessFlag, FileKind, Instance, TempFiles, WrappedFile,
     };
 
-    unsafe fn extract_instance(vfs_ptr: *mut sqlite3_vfs) -> Option<Box<Rc<RefCell<Instance>>>> {
+    /// The kinds of files whose names SQLite builds with URI parameters attached.
+    const NAMED_WITH_PARAMETERS: c_int =
+        SQLITE_OPEN_MAIN_DB | SQLITE_OPEN_MAIN_JOURNAL | SQLITE_OPEN_WAL;
+
+    unsafe fn extract_instance<'a>(vfs_ptr: *mut sqlite3_vfs) -> Option<&'a Rc<RefCell<Instance>>> {
         let app_data = (*vfs_ptr).pAppData;
 
         if app_data.is_null() {
             log::error!("Couldn't find any reference to the Instance in this VFS pointer.");
             None
         } else {
-            let b = Box::from_raw(app_data as *mut Rc<RefCell<Instance>>);
-            assert!(!b.as_ptr().is_null());
-            Some(b)
+            Some(&*(app_data as *const Rc<RefCell<Instance>>))
         }
     }
 
-    // FIXME: Actually use and correct the path using `null_terminator`.
     pub unsafe extern "C" fn resolve_full_path_name(
         ptr: *mut sqlite3_vfs,
         path_name: *const c_char,
-        _null_terminator: c_int,
+        output_size: c_int,
         resolved_path_name: *mut c_char,
     ) -> c_int {
         let _ = env_logger::builder().is_test(true).try_init();
@@ -64,27 +100,82 @@ mod funcs {
         );
 
         let vfs_inst = extract_instance(ptr).expect("Could not find the instance.");
+        let path_name_utf8 = match path_name_str.to_str() {
+            Ok(path) => path,
+            Err(_) => {
+                log::error!("The path {:?} is not valid UTF-8.", path_name_str);
+                return SQLITE_CANTOPEN;
+            }
+        };
 
-        match vfs_inst.borrow().filesystem().borrow().full_pathname(
-            path_name_str
-                .to_str()
-                .expect("Invalid pointer for the string representing the path of the file name."),
-        ) {
-            Ok(resolved_path) => {
-                log::trace!(
-                    "Resolved {:?} as the full path of {:?} from the {:?} VFS.",
-                    resolved_path,
+        let resolved_path = match vfs_inst
+            .borrow()
+            .filesystem()
+            .borrow()
+            .full_pathname(path_name_utf8)
+        {
+            Ok(resolved_path) => resolved_path,
+            Err(code) => {
+                log::error!(
+                    "Could not resolve the full path of {:?}; error code {:?}",
                     path_name_str,
-                    vfs_name
+                    code
                 );
-                let resolved_path_name_str = CStr::from_ptr(resolved_path.as_ptr() as _);
-                *resolved_path_name = resolved_path_name_str.as_ptr() as _;
+                return result_code(code);
             }
-            Err(_) => {
-                *resolved_path_name = path_name as _;
+        };
+
+        // SQLite hands us a buffer of `nOut` bytes (usually `mxPathname + 1`); the resolved path
+        // and its NUL terminator have to fit in both.
+        let limit = (output_size as usize).min((*ptr).mxPathname as usize + 1);
+        let resolved_bytes = resolved_path.as_bytes();
+
+        if resolved_bytes.contains(&0) || resolved_bytes.len() >= limit {
+            log::error!(
+                "The resolved path {:?} of {:?} does not fit within {} bytes.",
+                resolved_path,
+                path_name_str,
+                limit
+            );
+            return SQLITE_CANTOPEN;
+        }
+
+        log::trace!(
+            "Resolved {:?} as the full path of {:?} from the {:?} VFS.",
+            resolved_path,
+            path_name_str,
+            vfs_name
+        );
+        ptr::copy_nonoverlapping(
+            resolved_bytes.as_ptr() as *const c_char,
+            resolved_path_name,
+            resolved_bytes.len(),
+        );
+        *resolved_path_name.add(resolved_bytes.len()) = 0;
+        SQLITE_OK
+    }
+
+    /// Collects the URI parameters SQLite attached to a file name. This is only sound for the
+    /// main database and the journals derived from it, so callers have to check the open flags.
+    unsafe fn uri_parameters(path_name: *const c_char) -> HashMap<String, String> {
+        let mut parameters = HashMap::default();
+        let mut index = 0;
+
+        loop {
+            let key = api::uri_key(path_name, index);
+            if key.is_null() {
+                break parameters;
+            }
+
+            let value = api::uri_parameter(path_name, key);
+            if !value.is_null() {
+                parameters.insert(
+                    CStr::from_ptr(key).to_string_lossy().into_owned(),
+                    CStr::from_ptr(value).to_string_lossy().into_owned(),
+                );
             }
+            index += 1;
         }
-        SQLITE_OK as _
     }
 
     pub unsafe extern "C" fn open_file(
@@ -96,137 +187,333 @@ mod funcs {
     ) -> c_int {
         let _ = env_logger::builder().is_test(true).try_init();
         let vfs_name = CStr::from_ptr((*ptr).zName);
-        let path_name_str = CStr::from_ptr(path_name);
         let open_flags = OpenFlags::from_bits_truncate(open_flags_bits);
+        // Only files opened through the `sqlite3_vfs` directly, rather than by the pager, come
+        // without a kind.
+        let kind = FileKind::of(open_flags_bits).unwrap_or(FileKind::MainDb);
+        let vfs_inst = extract_instance(ptr).expect("Could not find the instance.");
+        let (filesystem, temp_files, parent) = {
+            let instance = vfs_inst.borrow();
+            (instance.filesystem(), instance.temp_files, instance.parent)
+        };
+
+        // SQLite leaves temporary files unnamed when it doesn't care where they go.
+        if path_name.is_null() || kind.is_temporary() {
+            log::trace!(
+                "Opening a {:?} file via the {:?} VFS as {:?}.",
+                kind,
+                vfs_name,
+                temp_files
+            );
+            match (temp_files, parent.as_ref().and_then(|parent| parent.xOpen)) {
+                (TempFiles::Os, Some(parent_open)) => {
+                    return parent_open(parent, path_name, file_ptr, open_flags_bits, output_flags)
+                }
+                (TempFiles::System, _) => {}
+                _ => {
+                    WrappedFile::wrap(Rc::new(RefCell::new(MemoryFile::default())))
+                        .write_into(file_ptr);
+                    if !output_flags.is_null() {
+                        *output_flags = open_flags_bits;
+                    }
+                    return SQLITE_OK;
+                }
+            }
+        }
+
+        let path = if path_name.is_null() {
+            temporary_name()
+        } else {
+            let path_name_str = CStr::from_ptr(path_name);
+            match path_name_str.to_str() {
+                Ok(path) => path.to_string(),
+                Err(_) => {
+                    log::error!("The path {:?} is not valid UTF-8.", path_name_str);
+                    (*file_ptr).pMethods = ptr::null();
+                    return SQLITE_CANTOPEN;
+                }
+            }
+        };
+        let parameters = if !path_name.is_null() && open_flags_bits & NAMED_WITH_PARAMETERS != 0 {
+            uri_parameters(path_name)
+        } else {
+            HashMap::default()
+        };
 
         log::trace!(
-            "Attempting to open a file at {:?} via the {:?} VFS with the flags {:?}.",
-            path_name_str,
+            "Attempting to open a {:?} file at {:?} via the {:?} VFS with the flags {:?}.",
+            kind,
+            path,
             vfs_name,
             open_flags
         );
 
-        let vfs_inst = extract_instance(ptr).expect("Could not find the instance.");
-
-        let result = match vfs_inst.borrow().filesystem().borrow().open(
-            path_name_str
-                .to_str()
-                .expect("Failed to craft string from pointer."),
-            &open_flags,
-        ) {
+        let opened = filesystem
+            .borrow()
+            .open(&path, kind, &open_flags, &parameters);
+        match opened {
             Ok(file) => {
                 log::trace!(
                     "The file {:?} was opened with {:?} as flags.",
-                    path_name_str,
+                    path,
                     open_flags
                 );
-                *file_ptr = file.ptr.unwrap();
-                SQLITE_OK as _
+                match file.file() {
+                    Some(handle) if open_flags_bits & SQLITE_OPEN_DELETEONCLOSE != 0 => {
+                        WrappedFile::wrap(Rc::new(RefCell::new(DeleteOnClose {
+                            file: handle,
+                            system: filesystem,
+                            path,
+                        })))
+                        .write_into(file_ptr)
+                    }
+                    _ => file.write_into(file_ptr),
+                }
+                if !output_flags.is_null() {
+                    *output_flags = open_flags_bits;
+                }
+                SQLITE_OK
             }
             Err(code) => {
-                log::error!(
-                    "Could not open the file {:?}; error code {:?}",
-                    path_name_str,
-                    code
-                );
-                code as _
+                log::error!("Could not open the file {:?}; error code {:?}", path, code);
+                (*file_ptr).pMethods = ptr::null();
+                result_code(code)
             }
-        };
-        result
+        }
     }
+
     pub unsafe extern "C" fn delete_file(
-        _ptr: *mut sqlite3_vfs,
-        _path_name: *const c_char,
-        _immediate: c_int,
+        ptr: *mut sqlite3_vfs,
+        path_name: *const c_char,
+        sync_to_system: c_int,
     ) -> c_int {
         let _ = env_logger::builder().is_test(true).try_init();
-        log::trace!("Attempting to delete a file.");
-        SQLITE_IOERR_CLOSE as _
+        let path_name_str = CStr::from_ptr(path_name);
+        log::trace!("Attempting to delete the file {:?}.", path_name_str);
+
+        let vfs_inst = extract_instance(ptr).expect("Could not find the instance.");
+        let path_name_utf8 = match path_name_str.to_str() {
+            Ok(path) => path,
+            Err(_) => return SQLITE_IOERR_DELETE,
+        };
+
+        match vfs_inst
+            .borrow()
+            .filesystem()
+            .borrow_mut()
+            .delete(path_name_utf8, sync_to_system != 0)
+        {
+            Ok(()) => SQLITE_OK,
+            Err(ErrorCode::NotFound) => SQLITE_IOERR_DELETE_NOENT,
+            Err(code) => {
+                log::error!(
+                    "Could not delete the file {:?}; error code {:?}",
+                    path_name_str,
+                    code
+                );
+                SQLITE_IOERR_DELETE
+            }
+        }
     }
+
     pub unsafe extern "C" fn get_file_access(
-        _ptr: *mut sqlite3_vfs,
-        _path_name: *const c_char,
-        _flags: c_int,
-        _resolved_access_flags: *mut c_int,
+        ptr: *mut sqlite3_vfs,
+        path_name: *const c_char,
+        flags: c_int,
+        resolved_access_flags: *mut c_int,
     ) -> c_int {
         let _ = env_logger::builder().is_test(true).try_init();
-        log::trace!("Attempting to get file access info.");
-        SQLITE_IOERR_CLOSE as _
+        let path_name_str = CStr::from_ptr(path_name);
+        log::trace!(
+            "Attempting to get file access info for {:?} with {}.",
+            path_name_str,
+            flags
+        );
+
+        let access_flag = match flags {
+            SQLITE_ACCESS_EXISTS => AccessFlag::Exists,
+            SQLITE_ACCESS_READ => AccessFlag::ReadOnly,
+            SQLITE_ACCESS_READWRITE => AccessFlag::ReadWrite,
+            _ => return SQLITE_IOERR_ACCESS,
+        };
+        let vfs_inst = extract_instance(ptr).expect("Could not find the instance.");
+        let path_name_utf8 = match path_name_str.to_str() {
+            Ok(path) => path,
+            Err(_) => return SQLITE_IOERR_ACCESS,
+        };
+
+        match vfs_inst
+            .borrow()
+            .filesystem()
+            .borrow()
+            .access(path_name_utf8, &[access_flag])
+        {
+            Ok(()) => {
+                *resolved_access_flags = 1;
+                SQLITE_OK
+            }
+            Err(ErrorCode::NotFound) | Err(ErrorCode::PermissionDenied) => {
+                *resolved_access_flags = 0;
+                SQLITE_OK
+            }
+            Err(code) => {
+                log::error!(
+                    "Could not determine access to {:?}; error code {:?}",
+                    path_name_str,
+                    code
+                );
+                SQLITE_IOERR_ACCESS
+            }
+        }
+    }
+
+    /// The VFS `ptr` forwards what its filesystem leaves alone to, if there's one.
+    unsafe fn parent_of(ptr: *mut sqlite3_vfs) -> Option<*mut sqlite3_vfs> {
+        extract_instance(ptr)
+            .map(|instance| instance.borrow().parent)
+            .filter(|parent| !parent.is_null())
     }
 
+    // Only the parent knows how to load libraries on this platform.
     pub unsafe extern "C" fn dl_open(
-        _vfs: *mut sqlite3_vfs,
+        ptr: *mut sqlite3_vfs,
         file_name: *const c_char,
     ) -> *mut c_void {
-        log::trace!("Opening up the dylib at {:?}.", file_name);
-        zeroed()
+        log::trace!("Opening up the dylib at {:?}.", CStr::from_ptr(file_name));
+        match parent_of(ptr).and_then(|parent| Some((parent, (*parent).xDlOpen?))) {
+            Some((parent, dl_open)) => dl_open(parent, file_name),
+            None => ptr::null_mut(),
+        }
     }
 
     pub unsafe extern "C" fn dl_error(
-        _vfs: *mut sqlite3_vfs,
-        _error_code: c_int,
-        _error_message: *mut c_char,
+        ptr: *mut sqlite3_vfs,
+        buffer_size: c_int,
+        buffer: *mut c_char,
     ) {
-        log::trace!("Ran into an error with the dylib.");
+        match parent_of(ptr).and_then(|parent| Some((parent, (*parent).xDlError?))) {
+            Some((parent, dl_error)) => dl_error(parent, buffer_size, buffer),
+            None if buffer_size > 0 => {
+                let message = b"Loading extensions isn't supported";
+                let length = message.len().min(buffer_size as usize - 1);
+                ptr::copy_nonoverlapping(message.as_ptr() as *const c_char, buffer, length);
+                *buffer.add(length) = 0;
+            }
+            None => {}
+        }
     }
 
-    pub unsafe extern "C" fn dl_close(_vfs: *mut sqlite3_vfs, _arg: *mut c_void) {
+    pub unsafe extern "C" fn dl_close(ptr: *mut sqlite3_vfs, handle: *mut c_void) {
         log::trace!("Closing out the dylib.");
+        if let Some((parent, dl_close)) =
+            parent_of(ptr).and_then(|parent| Some((parent, (*parent).xDlClose?)))
+        {
+            dl_close(parent, handle)
+        }
     }
+
     pub unsafe extern "C" fn dl_sym(
-        _vfs: *mut sqlite3_vfs,
-        _arg: *mut c_void,
+        ptr: *mut sqlite3_vfs,
+        handle: *mut c_void,
         symbol_name: *const c_char,
     ) -> Option<unsafe extern "C" fn(*mut sqlite3_vfs, *mut c_void, *const c_char)> {
-        log::trace!("Resolving the symbol from the dylib of {:?}", symbol_name);
-        None
+        log::trace!(
+            "Resolving the symbol from the dylib of {:?}",
+            CStr::from_ptr(symbol_name)
+        );
+        let (parent, dl_sym) =
+            parent_of(ptr).and_then(|parent| Some((parent, (*parent).xDlSym?)))?;
+        dl_sym(parent, handle, symbol_name)
     }
+
     pub unsafe extern "C" fn current_time(
-        _vfs: *mut sqlite3_vfs,
-        _resulting_timestamp: *mut c_double,
+        ptr: *mut sqlite3_vfs,
+        resulting_timestamp: *mut c_double,
     ) -> c_int {
-        log::trace!("Getting the current time right now");
-        SQLITE_OK as _
+        let vfs_inst = extract_instance(ptr).expect("Could not find the instance.");
+        if let Some(now) = vfs_inst.borrow().filesystem().borrow().current_time() {
+            *resulting_timestamp = now;
+            return SQLITE_OK;
+        }
+        match parent_of(ptr).and_then(|parent| Some((parent, (*parent).xCurrentTime?))) {
+            Some((parent, current_time)) => current_time(parent, resulting_timestamp),
+            None => {
+                // Julian days start at noon, so the Unix epoch falls halfway through day 2440587.
+                let since_epoch = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                *resulting_timestamp = 2_440_587.5 + since_epoch.as_secs_f64() / 86_400.0;
+                SQLITE_OK
+            }
+        }
     }
+
+    /// Copies why the last VFS method on this thread failed into `buffer` (cut short to fit its
+    /// `buffer_size` bytes) and returns the result code it failed with, or 0 if none has.
     pub unsafe extern "C" fn get_last_error(
         _vfs: *mut sqlite3_vfs,
-        error_code: c_int,
-        error_something: *mut c_schar,
+        buffer_size: c_int,
+        buffer: *mut c_char,
     ) -> c_int {
-        let error_str = if !error_something.is_null() {
-            CStr::from_ptr(error_something)
-                .to_str()
-                .unwrap_or("Unexpected error.")
-                .to_string()
-        } else {
-            "Unexpected error - no error message provided.".to_string()
+        let (code, message) = match last_error() {
+            Some(last_error) => last_error,
+            None => return 0,
         };
-        log::trace!("The last error found was {:?}: {:?}", error_code, error_str);
-        SQLITE_OK as _
+
+        if !buffer.is_null() && buffer_size > 0 {
+            let length = message.len().min(buffer_size as usize - 1);
+            ptr::copy_nonoverlapping(message.as_ptr() as *const c_char, buffer, length);
+            *buffer.add(length) = 0;
+        }
+        code
     }
+
     pub unsafe extern "C" fn randomness(
-        _vfs: *mut sqlite3_vfs,
+        ptr: *mut sqlite3_vfs,
         size_of_random_bytes: c_int,
-        message: *mut c_char,
+        buffer: *mut c_char,
     ) -> c_int {
-        log::trace!(
-            "Make something random for {:?} of {} bytes",
-            message,
-            size_of_random_bytes
-        );
-        SQLITE_OK as i32
+        log::trace!("Making {} random bytes.", size_of_random_bytes);
+        let vfs_inst = extract_instance(ptr).expect("Could not find the instance.");
+        let bytes =
+            slice::from_raw_parts_mut(buffer as *mut u8, size_of_random_bytes.max(0) as usize);
+        if let Some(filled) = vfs_inst.borrow().filesystem().borrow().randomness(bytes) {
+            return filled as c_int;
+        }
+        match parent_of(ptr).and_then(|parent| Some((parent, (*parent).xRandomness?))) {
+            Some((parent, randomness)) => randomness(parent, size_of_random_bytes, buffer),
+            None => 0,
+        }
     }
 
-    pub unsafe extern "C" fn sleep(_vfs: *mut sqlite3_vfs, microseconds: c_int) -> c_int {
-        log::trace!("They wanna sleep for {:?} microseconds", microseconds);
-        SQLITE_OK as i32
+    pub unsafe extern "C" fn sleep(ptr: *mut sqlite3_vfs, microseconds: c_int) -> c_int {
+        log::trace!("Sleeping for {} microseconds.", microseconds);
+        let vfs_inst = extract_instance(ptr).expect("Could not find the instance.");
+        let duration = Duration::from_micros(microseconds.max(0) as u64);
+        if let Some(slept) = vfs_inst.borrow().filesystem().borrow().sleep(duration) {
+            return slept.as_micros() as c_int;
+        }
+        match parent_of(ptr).and_then(|parent| Some((parent, (*parent).xSleep?))) {
+            Some((parent, sleep)) => sleep(parent, microseconds),
+            None => {
+                thread::sleep(duration);
+                microseconds
+            }
+        }
     }
 }
 
-pub fn bind(vfs: &mut sqlite3::sqlite3_vfs) {
-    let file_ptr_size = mem::size_of::<Box<dyn super::File>>() as raw::c_int;
+/// Fills in `vfs`, whose files can also be opened by `parent` (see `TempFiles::Os`), so they're
+/// given room for either.
+pub fn bind(
+    vfs: &mut sqlite3::sqlite3_vfs,
+    max_pathname: raw::c_int,
+    parent: *mut sqlite3::sqlite3_vfs,
+) {
+    let parent_file_size = unsafe { parent.as_ref() }.map_or(0, |parent| parent.szOsFile);
+    let file_ptr_size = (mem::size_of::<WrappedFile>() as raw::c_int).max(parent_file_size);
     vfs.iVersion = 1;
-    vfs.mxPathname = 1024;
+    vfs.mxPathname = max_pathname;
     vfs.pNext = std::ptr::null_mut();
     vfs.szOsFile = file_ptr_size;
     vfs.xOpen = Some(funcs::open_file);
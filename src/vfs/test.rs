@@ -1,7 +1,13 @@
 use super::*;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::ffi::CStr;
 use std::ops::Deref;
 
-struct MockFile {}
+#[derive(Default)]
+struct MockFile {
+    data: Rc<RefCell<Vec<u8>>>,
+}
 
 impl File for MockFile {
     fn close(&self) -> anyhow::Result<()> {
@@ -9,7 +15,10 @@ impl File for MockFile {
     }
 
     fn read(&self, amount: raw::c_int, offset: sqlite3::sqlite3_int64) -> anyhow::Result<Vec<u8>> {
-        todo!()
+        let data = self.data.borrow();
+        let start = (offset as usize).min(data.len());
+        let end = (start + amount as usize).min(data.len());
+        Ok(data[start..end].to_vec())
     }
 
     fn write(
@@ -18,54 +27,78 @@ impl File for MockFile {
         amount: raw::c_int,
         offset: sqlite3::sqlite3_int64,
     ) -> anyhow::Result<raw::c_int> {
-        todo!()
+        let mut contents = self.data.borrow_mut();
+        let end = offset as usize + amount as usize;
+        if contents.len() < end {
+            contents.resize(end, 0);
+        }
+        contents[offset as usize..end].copy_from_slice(&data);
+        Ok(amount)
     }
 
     fn truncate(&self, length: sqlite3::sqlite3_int64) -> anyhow::Result<()> {
-        todo!()
+        self.data.borrow_mut().truncate(length as usize);
+        Ok(())
     }
 
-    fn sync(&self, flags: raw::c_int) -> anyhow::Result<()> {
-        todo!()
+    fn sync(&self, _flags: raw::c_int) -> anyhow::Result<()> {
+        Ok(())
     }
 
     fn size(&self) -> anyhow::Result<sqlite3::sqlite3_int64> {
-        todo!()
+        Ok(self.data.borrow().len() as _)
     }
 
-    fn lock(&self, flag: LockFlag) -> anyhow::Result<()> {
-        todo!()
+    fn lock(&self, _flag: LockFlag) -> anyhow::Result<()> {
+        Ok(())
     }
 
-    fn unlock(&self, flag: LockFlag) -> anyhow::Result<()> {
-        todo!()
+    fn unlock(&self, _flag: LockFlag) -> anyhow::Result<()> {
+        Ok(())
     }
 
     fn check_reserved_lock(&self) -> anyhow::Result<bool> {
-        todo!()
+        Ok(false)
     }
 
-    fn file_control(&self, op: raw::c_int, structure: *const raw::c_void) {
-        unimplemented!()
+    fn file_control(&self, control: FileControl) -> anyhow::Result<Option<String>> {
+        match control {
+            FileControl::Pragma { name, value } if name == "mock_echo" => match value {
+                Some(value) => Ok(Some(value)),
+                None => Err(anyhow::anyhow!("Nothing to echo")),
+            },
+            _ => Err(FileControl::not_found()),
+        }
     }
 
     fn sector_size(&self) -> raw::c_int {
-        todo!()
+        512
     }
 
     fn device_characteristics(&self) -> Vec<raw::c_int> {
-        todo!()
+        vec![]
     }
 }
 
-struct MockFilesystem {}
+#[derive(Default)]
+struct MockFilesystem {
+    files: RefCell<HashMap<String, Rc<RefCell<Vec<u8>>>>>,
+    opened: RefCell<Vec<(String, FileKind)>>,
+    /// What the clock says, if not the parent VFS's.
+    now: Option<f64>,
+}
 
 impl System for MockFilesystem {
-    fn access(&self, _path: &str, _access_flags: &[AccessFlag]) -> Result<(), sqlite3::ErrorCode> {
-        todo!()
+    fn access(&self, path: &str, _access_flags: &[AccessFlag]) -> Result<(), sqlite3::ErrorCode> {
+        if self.files.borrow().contains_key(path) {
+            Ok(())
+        } else {
+            Err(sqlite3::ErrorCode::NotFound)
+        }
     }
 
-    fn delete(&mut self, _path: &str, _sync_to_system: bool) -> Result<(), sqlite3::ErrorCode> {
+    fn delete(&mut self, path: &str, _sync_to_system: bool) -> Result<(), sqlite3::ErrorCode> {
+        self.files.borrow_mut().remove(path);
         Ok(())
     }
 
@@ -73,19 +106,27 @@ impl System for MockFilesystem {
         Ok(path.to_string())
     }
 
+    fn current_time(&self) -> Option<f64> {
+        self.now
+    }
+
     fn open(
         &self,
         path: &str,
+        kind: FileKind,
         _open_flags: &rusqlite::OpenFlags,
+        _parameters: &HashMap<String, String>,
     ) -> Result<Box<file::WrappedFile>, sqlite3::ErrorCode> {
         log::trace!(
             "Attempting to look up the file {:?} in the mock system.",
             path
         );
+        self.opened.borrow_mut().push((path.to_string(), kind));
 
-        if path == "mock-system.db" {
+        if path.starts_with("mock-system.db") || path.starts_with("sqlite-temp-") {
             log::trace!("Used the expected mock file name.");
-            let file_ptr = Rc::new(RefCell::new(MockFile {}));
+            let data = Rc::clone(self.files.borrow_mut().entry(path.to_string()).or_default());
+            let file_ptr = Rc::new(RefCell::new(MockFile { data }));
             Ok(Box::new(file::WrappedFile::wrap(file_ptr)))
         } else {
             log::trace!("Didn't recognize the name {:?}; failing out.", path);
@@ -97,7 +138,7 @@ impl System for MockFilesystem {
 #[test]
 fn registers_filesystem() -> anyhow::Result<()> {
     let _ = env_logger::builder().is_test(true).try_init();
-    let mock_fs = Rc::new(RefCell::new(MockFilesystem {}));
+    let mock_fs = Rc::new(RefCell::new(MockFilesystem::default()));
     let inst = Instance::new("mock-init", mock_fs)?;
     assert!(Instance::register(Rc::clone(&inst), false).is_ok());
     assert!(inst.deref().borrow().registered());
@@ -107,10 +148,10 @@ fn registers_filesystem() -> anyhow::Result<()> {
 #[test]
 fn open_database_connection() {
     let _ = env_logger::builder().is_test(true).try_init();
-    let mock_fs = Rc::new(RefCell::new(MockFilesystem {}));
+    let mock_fs = Rc::new(RefCell::new(MockFilesystem::default()));
     let inst_result = Instance::new("mock-connect", mock_fs);
 
-    assert!(matches!(inst_result, Ok(_)));
+    assert!(inst_result.is_ok());
 
     let inst = inst_result.unwrap();
 
@@ -124,7 +165,7 @@ fn open_database_connection() {
         &inst.deref().borrow().vfs_name().unwrap(),
     );
 
-    assert!(matches!(conn_result, Ok(_)));
+    assert!(conn_result.is_ok());
 
     let conn = conn_result.unwrap();
     log::info!("Attempting to load schema.");
@@ -143,3 +184,424 @@ fn open_database_connection() {
     log::info!("Disconnecting");
     drop(conn);
 }
+
+fn resolve_full_path_name(inst: &Rc<RefCell<Instance>>, path: &str) -> (raw::c_int, String) {
+    let instance = inst.deref().borrow();
+    let vfs_ptr = unsafe { sqlite3::sqlite3_vfs_find(instance.vfs_name.as_ptr()) };
+    assert!(!vfs_ptr.is_null());
+
+    let output_size = instance.max_pathname() + 1;
+    let path_name = CString::new(path).unwrap();
+    let mut output = vec![1 as raw::c_char; output_size as usize];
+    let result_code = unsafe {
+        ((*vfs_ptr).xFullPathname.unwrap())(
+            vfs_ptr,
+            path_name.as_ptr(),
+            output_size,
+            output.as_mut_ptr(),
+        )
+    };
+
+    let resolved = if result_code == sqlite3::SQLITE_OK {
+        unsafe { CStr::from_ptr(output.as_ptr()) }
+            .to_string_lossy()
+            .into_owned()
+    } else {
+        String::default()
+    };
+
+    (result_code, resolved)
+}
+
+#[test]
+fn resolves_full_path_name_within_bounds() -> anyhow::Result<()> {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let mock_fs = Rc::new(RefCell::new(MockFilesystem::default()));
+    let inst = Instance::new("mock-full-path", mock_fs)?;
+    inst.borrow_mut().set_max_pathname(84)?;
+    Instance::register(Rc::clone(&inst), false)?;
+
+    let hyper_uri = format!("hyper://{}/docs.db", "a".repeat(64));
+    assert_eq!(
+        resolve_full_path_name(&inst, &hyper_uri),
+        (sqlite3::SQLITE_OK, hyper_uri.clone())
+    );
+
+    let (result_code, _) = resolve_full_path_name(&inst, &format!("{}/nested", hyper_uri));
+    assert_eq!(result_code, sqlite3::SQLITE_CANTOPEN);
+
+    assert!(inst.borrow_mut().set_max_pathname(4096).is_err());
+    Ok(())
+}
+
+#[test]
+fn answers_pragmas_through_file_controls() -> anyhow::Result<()> {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let mock_fs = Rc::new(RefCell::new(MockFilesystem::default()));
+    let inst = Instance::new("mock-file-control", mock_fs)?;
+    Instance::register(Rc::clone(&inst), false)?;
+    let conn = rusqlite::Connection::open_with_flags_and_vfs(
+        "mock-system.db",
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE | rusqlite::OpenFlags::SQLITE_OPEN_CREATE,
+        &inst.borrow().vfs_name().unwrap(),
+    )?;
+
+    let echoed: String =
+        conn.query_row("PRAGMA mock_echo = 'hello'", rusqlite::NO_PARAMS, |row| {
+            row.get(0)
+        })?;
+    assert_eq!(echoed, "hello");
+
+    let error = conn
+        .execute_batch("PRAGMA mock_echo")
+        .expect_err("the handler to fail");
+    assert!(error.to_string().contains("Nothing to echo"));
+
+    // Pragmas the file leaves alone are still handled by SQLite.
+    let user_version: i64 =
+        conn.query_row("PRAGMA user_version", rusqlite::NO_PARAMS, |row| row.get(0))?;
+    assert_eq!(user_version, 0);
+    // Only the handler failing counts as an error; leaving a request to SQLite doesn't.
+    assert!(last_error().unwrap().1.contains("Nothing to echo"));
+    Ok(())
+}
+
+#[test]
+fn caches_reads_until_the_file_changes() -> anyhow::Result<()> {
+    let data = Rc::new(RefCell::new(vec![0; 100]));
+    let stats = Rc::new(Cell::new(CacheStats::default()));
+    let file = CachedFile::new(
+        MockFile {
+            data: Rc::clone(&data),
+        },
+        2,
+        Rc::clone(&stats),
+    );
+
+    file.lock(LockFlag::Shared)?;
+    assert_eq!(file.read(10, 0)?, vec![0; 10]);
+    assert_eq!(file.read(10, 0)?, vec![0; 10]);
+    assert_eq!((stats.get().hits, stats.get().misses), (1, 1));
+
+    file.write(vec![1; 2], 2, 8)?;
+    assert_eq!(file.read(10, 0)?[8..], [1, 1]);
+    assert_eq!(stats.get().misses, 2);
+
+    file.read(10, 10)?;
+    file.read(10, 20)?;
+    assert_eq!(stats.get().evictions, 1);
+    file.unlock(LockFlag::None)?;
+
+    // Like another connection committing a transaction, down to the change counter.
+    data.borrow_mut()[20..30].copy_from_slice(&[2; 10]);
+    file.lock(LockFlag::Shared)?;
+    assert_eq!(file.read(10, 20)?, vec![2; 10]);
+    assert_eq!(stats.get().hits, 1);
+    Ok(())
+}
+
+#[test]
+fn stacks_layers_on_any_filesystem() -> anyhow::Result<()> {
+    let below = Cache::new(0);
+    let above = Cache::new(64);
+    let (below_stats, above_stats) = (below.stats(), above.stats());
+    let mock_fs = Rc::new(RefCell::new(MockFilesystem::default()));
+    let inst = Instance::builder("mock-layered", mock_fs)
+        .layer(below)
+        .layer(above)
+        .build()?;
+
+    let opened = inst.borrow().filesystem().borrow().open(
+        "mock-system.db",
+        FileKind::MainDb,
+        &rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE,
+        &HashMap::default(),
+    );
+    let file = opened
+        .map_err(|code| anyhow::anyhow!("Could not open the file: {:?}", code))?
+        .file()
+        .expect("an open file");
+    file.borrow().write(vec![1; 100], 100, 0)?;
+    for _ in 0..3 {
+        assert_eq!(file.borrow().read(10, 0)?, vec![1; 10]);
+    }
+
+    // Only what the layer added last missed made it to the one below.
+    assert_eq!((above_stats.get().hits, above_stats.get().misses), (2, 1));
+    assert_eq!((below_stats.get().hits, below_stats.get().misses), (0, 1));
+    Ok(())
+}
+
+fn failure_code(error: rusqlite::Error) -> Option<rusqlite::ErrorCode> {
+    match error {
+        rusqlite::Error::SqliteFailure(error, _) => Some(error.code),
+        _ => None,
+    }
+}
+
+#[test]
+fn injects_faults_into_any_filesystem() -> anyhow::Result<()> {
+    let mock_fs = Rc::new(RefCell::new(MockFilesystem::default()));
+    let harness = CrashHarness::new("mock-faults", mock_fs, "mock-system.db")?;
+    let conn = harness.connect()?;
+    conn.busy_timeout(std::time::Duration::default())?;
+    conn.execute_batch("CREATE TABLE notes(body TEXT);")?;
+    let insert = "INSERT INTO notes(body) VALUES ('hello')";
+
+    harness.faults().fail_write(1);
+    let failed = conn.execute(insert, rusqlite::NO_PARAMS).unwrap_err();
+    assert_eq!(
+        failure_code(failed),
+        Some(rusqlite::ErrorCode::SystemIOFailure)
+    );
+    harness.faults().fail_sync(1);
+    let failed = conn.execute(insert, rusqlite::NO_PARAMS).unwrap_err();
+    assert_eq!(
+        failure_code(failed),
+        Some(rusqlite::ErrorCode::SystemIOFailure)
+    );
+    harness.faults().busy_locks(1);
+    let failed = conn.execute(insert, rusqlite::NO_PARAMS).unwrap_err();
+    assert_eq!(
+        failure_code(failed),
+        Some(rusqlite::ErrorCode::DatabaseBusy)
+    );
+
+    conn.execute(insert, rusqlite::NO_PARAMS)?;
+    let count: i64 = conn.query_row("SELECT COUNT(*) FROM notes", rusqlite::NO_PARAMS, |row| {
+        row.get(0)
+    })?;
+    assert_eq!(count, 1);
+    harness.verify(|_| Ok(()))
+}
+
+#[test]
+fn loses_writes_whose_sync_failed_in_a_crash() -> anyhow::Result<()> {
+    let faults = FaultyFilesystem::new();
+    let data = Rc::new(RefCell::new(Vec::new()));
+    let file = faults.file(
+        "mock-system.db",
+        Rc::new(RefCell::new(MockFile {
+            data: Rc::clone(&data),
+        })),
+    )?;
+    file.write(vec![1; 4], 4, 0)?;
+    file.sync(0)?;
+
+    file.write(vec![2; 4], 4, 0)?;
+    // SQLite says a sync is coming before making it, which doesn't make anything durable.
+    let _ = file.file_control(FileControl::Sync {
+        super_journal: None,
+    });
+    faults.fail_sync(1);
+    assert!(file.sync(0).is_err());
+    assert_eq!(file.read(4, 0)?, vec![2; 4]);
+
+    faults.crash();
+    assert_eq!(*data.borrow(), vec![1; 4]);
+    Ok(())
+}
+
+#[test]
+fn recovers_from_a_crash_at_every_write() -> anyhow::Result<()> {
+    let mock_fs = Rc::new(RefCell::new(MockFilesystem::default()));
+    let harness = CrashHarness::new("mock-crashes", mock_fs, "mock-system.db")?;
+    harness.connect()?.execute_batch(
+        r#"
+        CREATE TABLE accounts(id INTEGER PRIMARY KEY, balance INTEGER);
+        CREATE TABLE transfers(amount INTEGER, memo BLOB);
+        INSERT INTO accounts(id, balance) VALUES (1, 500), (2, 500);
+        "#,
+    )?;
+    let transfer = r#"
+        BEGIN;
+        UPDATE accounts SET balance = balance - 10 WHERE id = 1;
+        INSERT INTO transfers(amount, memo) VALUES (10, zeroblob(3000));
+        UPDATE accounts SET balance = balance + 10 WHERE id = 2;
+        COMMIT;
+        "#;
+    let balanced = |conn: &rusqlite::Connection| {
+        let total: i64 = conn.query_row(
+            "SELECT SUM(balance) FROM accounts",
+            rusqlite::NO_PARAMS,
+            |row| row.get(0),
+        )?;
+        anyhow::ensure!(total == 1000, "The accounts add up to {}", total);
+        Ok(())
+    };
+
+    assert!(harness.run(transfer, balanced)? > 0);
+    // Writes torn at the first sector are rolled back just the same.
+    harness.faults().tear_writes(Some(1));
+    assert!(harness.run(transfer, balanced)? > 0);
+    Ok(())
+}
+
+#[test]
+fn counts_what_is_asked_of_each_file() -> anyhow::Result<()> {
+    let faults = FaultyFilesystem::new();
+    let mock_fs = Rc::new(RefCell::new(MockFilesystem::default()));
+    let inst = Instance::builder("mock-instrumented", mock_fs)
+        .layer(faults.clone())
+        .instrument()
+        .register(false)?;
+    let conn = rusqlite::Connection::open_with_flags_and_vfs(
+        "mock-system.db",
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE | rusqlite::OpenFlags::SQLITE_OPEN_CREATE,
+        "mock-instrumented",
+    )?;
+    conn.busy_timeout(std::time::Duration::default())?;
+    // Commits truncate the journal instead of deleting it.
+    conn.execute_batch("PRAGMA journal_mode = TRUNCATE; CREATE TABLE notes(body TEXT);")?;
+    faults.busy_locks(1);
+    let busy = conn.execute_batch("INSERT INTO notes(body) VALUES ('hello')");
+    assert_eq!(
+        failure_code(busy.unwrap_err()),
+        Some(rusqlite::ErrorCode::DatabaseBusy)
+    );
+
+    let stats = inst
+        .borrow()
+        .stats()
+        .expect("the instance to be instrumented");
+    let database = stats.files["mock-system.db"];
+    assert!(database.reads.calls > 0);
+    assert_eq!(database.writes.bytes, database.writes.calls * 4096);
+    assert!(database.syncs.calls > 0);
+    assert_eq!((database.locks.failures, database.busy), (1, 1));
+    assert!(database.unlocks.calls > 0);
+    assert!(stats.files["mock-system.db-journal"].truncates.calls > 0);
+    assert!(stats.files.contains_key("mock-system.db-journal"));
+    assert_eq!(
+        stats.total().writes.latency.count(),
+        stats.total().writes.calls
+    );
+    Ok(())
+}
+
+#[test]
+fn keeps_calls_slower_than_every_bound_apart() {
+    let mut histogram = Histogram::default();
+    histogram.record(std::time::Duration::from_micros(3));
+    histogram.record(std::time::Duration::from_secs(60));
+
+    let buckets: Vec<_> = histogram.buckets().collect();
+    assert_eq!(buckets[2], (Some(std::time::Duration::from_micros(4)), 1));
+    assert_eq!(*buckets.last().unwrap(), (None, 1));
+    assert_eq!(histogram.quantile(1.0), std::time::Duration::from_secs(60));
+    assert_eq!(
+        histogram.mean(),
+        std::time::Duration::from_nanos(30_000_001_500)
+    );
+}
+
+#[test]
+fn routes_temporary_files_by_kind() -> anyhow::Result<()> {
+    // A tiny cache makes the temporary database spill to a file.
+    let workload = "PRAGMA temp.cache_size = 2;
+        CREATE TEMP TABLE scratch(n INTEGER, padding BLOB);
+        INSERT INTO scratch VALUES (1, zeroblob(10000)), (2, zeroblob(10000)), (3, NULL);
+        CREATE TABLE kept AS SELECT n FROM scratch ORDER BY random();";
+
+    for (name, temp_files) in [
+        ("mock-temp-memory", TempFiles::Memory),
+        ("mock-temp-os", TempFiles::Os),
+        ("mock-temp-system", TempFiles::System),
+    ] {
+        let mock_fs = Rc::new(RefCell::new(MockFilesystem::default()));
+        let _inst = Instance::builder(name, Rc::clone(&mock_fs) as Rc<RefCell<dyn System>>)
+            .temp_files(temp_files)
+            .register(false)?;
+        let conn = rusqlite::Connection::open_with_flags_and_vfs(
+            "mock-system.db",
+            rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE | rusqlite::OpenFlags::SQLITE_OPEN_CREATE,
+            name,
+        )?;
+        conn.execute_batch(workload)?;
+        let kept: i64 = conn.query_row("SELECT sum(n) FROM kept", rusqlite::NO_PARAMS, |row| {
+            row.get(0)
+        })?;
+        assert_eq!(kept, 6);
+        drop(conn);
+
+        let mock_fs = mock_fs.borrow();
+        let temporary: Vec<_> = mock_fs
+            .opened
+            .borrow()
+            .iter()
+            .filter(|(_, kind)| kind.is_temporary())
+            .cloned()
+            .collect();
+        if temp_files == TempFiles::System {
+            // The temporary database had no name, so it was given one, and dropped once closed.
+            let (path, kind) = &temporary[0];
+            assert_eq!(*kind, FileKind::TempDb);
+            assert!(path.starts_with("sqlite-temp-"));
+            assert!(mock_fs
+                .files
+                .borrow()
+                .keys()
+                .all(|path| path.starts_with("mock-system.db")));
+        } else {
+            assert_eq!(temporary, vec![]);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn forwards_what_the_filesystem_leaves_to_the_parent() -> anyhow::Result<()> {
+    assert!(Instance::builder(
+        "mock-orphaned",
+        Rc::new(RefCell::new(MockFilesystem::default()))
+    )
+    .parent("no-such-vfs")
+    .build()
+    .is_err());
+
+    let mock_fs = Rc::new(RefCell::new(MockFilesystem::default()));
+    let inst = Instance::builder("mock-delegating", mock_fs).register(false)?;
+    let conn = rusqlite::Connection::open_with_flags_and_vfs(
+        "mock-system.db",
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE | rusqlite::OpenFlags::SQLITE_OPEN_CREATE,
+        "mock-delegating",
+    )?;
+    let year: String =
+        conn.query_row("SELECT strftime('%Y', 'now')", rusqlite::NO_PARAMS, |row| {
+            row.get(0)
+        })?;
+    assert!(year.parse::<u32>()? >= 2024);
+
+    let vfs_ptr = unsafe { sqlite3::sqlite3_vfs_find(inst.borrow().vfs_name.as_ptr()) };
+    let mut random = [0 as raw::c_char; 32];
+    let filled = unsafe { ((*vfs_ptr).xRandomness.unwrap())(vfs_ptr, 32, random.as_mut_ptr()) };
+    assert_eq!(filled, 32);
+    assert!(random.iter().any(|byte| *byte != 0));
+
+    let library = CString::new("no-such-library.so")?;
+    let mut message = [0 as raw::c_char; 256];
+    unsafe {
+        assert!(((*vfs_ptr).xDlOpen.unwrap())(vfs_ptr, library.as_ptr()).is_null());
+        ((*vfs_ptr).xDlError.unwrap())(vfs_ptr, 256, message.as_mut_ptr());
+        assert!(!CStr::from_ptr(message.as_ptr()).to_bytes().is_empty());
+    }
+
+    // What the filesystem does answer doesn't reach the parent.
+    let frozen_fs = Rc::new(RefCell::new(MockFilesystem {
+        now: Some(2_451_545.0),
+        ..MockFilesystem::default()
+    }));
+    let _frozen = Instance::builder("mock-frozen", frozen_fs)
+        .parent(inst.borrow().vfs_name().unwrap())
+        .register(false)?;
+    let conn = rusqlite::Connection::open_with_flags_and_vfs(
+        "mock-system.db",
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE | rusqlite::OpenFlags::SQLITE_OPEN_CREATE,
+        "mock-frozen",
+    )?;
+    let now: String = conn.query_row("SELECT datetime('now')", rusqlite::NO_PARAMS, |row| {
+        row.get(0)
+    })?;
+    assert_eq!(now, "2000-01-01 12:00:00");
+    Ok(())
+}
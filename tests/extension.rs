@@ -0,0 +1,158 @@
+// Loads the library, as built, into the system's SQLite (a separate one from the copy bundled
+// into it), the way the `sqlite3` shell would with `.load`.
+#![cfg(all(unix, feature = "extension"))]
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::path::PathBuf;
+use std::ptr;
+
+type Open = unsafe extern "C" fn(*const c_char, *mut *mut c_void, c_int, *const c_char) -> c_int;
+type EnableLoadExtension = unsafe extern "C" fn(*mut c_void, c_int) -> c_int;
+type LoadExtension =
+    unsafe extern "C" fn(*mut c_void, *const c_char, *const c_char, *mut *mut c_char) -> c_int;
+type Callback =
+    unsafe extern "C" fn(*mut c_void, c_int, *mut *mut c_char, *mut *mut c_char) -> c_int;
+type Exec = unsafe extern "C" fn(
+    *mut c_void,
+    *const c_char,
+    Option<Callback>,
+    *mut c_void,
+    *mut *mut c_char,
+) -> c_int;
+type ErrorMessage = unsafe extern "C" fn(*mut c_void) -> *const c_char;
+type Version = unsafe extern "C" fn() -> *const c_char;
+
+const SQLITE_OK: c_int = 0;
+const SQLITE_OPEN_READWRITE: c_int = 0x2;
+const SQLITE_OPEN_CREATE: c_int = 0x4;
+
+/// The SQLite installed on the system, reached through `dlopen` so it's kept apart from the copy
+/// in the library.
+struct SystemSqlite {
+    library: *mut c_void,
+}
+
+impl SystemSqlite {
+    fn open() -> Option<Self> {
+        ["libsqlite3.so.0", "libsqlite3.so", "libsqlite3.dylib"]
+            .iter()
+            .map(|name| CString::new(*name).unwrap())
+            .map(|name| unsafe { libc::dlopen(name.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) })
+            .find(|library| !library.is_null())
+            .map(|library| Self { library })
+    }
+
+    unsafe fn function<F: Copy>(&self, name: &str) -> F {
+        let name = CString::new(name).unwrap();
+        let function = libc::dlsym(self.library, name.as_ptr());
+        assert!(!function.is_null(), "The system's SQLite has no {:?}", name);
+        std::mem::transmute_copy(&function)
+    }
+
+    unsafe fn error(&self, db: *mut c_void) -> String {
+        let message = self.function::<ErrorMessage>("sqlite3_errmsg")(db);
+        CStr::from_ptr(message).to_string_lossy().into_owned()
+    }
+
+    /// Runs `sql` on `db`, returning the first column of every row.
+    unsafe fn query(&self, db: *mut c_void, sql: &str) -> Vec<String> {
+        unsafe extern "C" fn collect(
+            rows: *mut c_void,
+            _columns: c_int,
+            values: *mut *mut c_char,
+            _names: *mut *mut c_char,
+        ) -> c_int {
+            let value = *values;
+            let value = if value.is_null() {
+                String::new()
+            } else {
+                CStr::from_ptr(value).to_string_lossy().into_owned()
+            };
+            (*(rows as *mut Vec<String>)).push(value);
+            0
+        }
+
+        let mut rows = Vec::<String>::new();
+        let sql = CString::new(sql).unwrap();
+        let code = self.function::<Exec>("sqlite3_exec")(
+            db,
+            sql.as_ptr(),
+            Some(collect),
+            &mut rows as *mut _ as *mut c_void,
+            ptr::null_mut(),
+        );
+        assert_eq!(code, SQLITE_OK, "{}", self.error(db));
+        rows
+    }
+}
+
+/// Where Cargo put the library, next to the directory the test was built in.
+fn built_library() -> PathBuf {
+    let name = format!(
+        "{}sqlite_hypercore{}",
+        std::env::consts::DLL_PREFIX,
+        std::env::consts::DLL_SUFFIX
+    );
+    let deps = std::env::current_exe()
+        .unwrap()
+        .parent()
+        .unwrap()
+        .to_path_buf();
+    [deps.join(&name), deps.parent().unwrap().join(&name)]
+        .iter()
+        .find(|path| path.exists())
+        .cloned()
+        .expect("the library to be built")
+}
+
+#[test]
+fn answers_pragmas_in_another_sqlite() {
+    let sqlite = match SystemSqlite::open() {
+        Some(sqlite) => sqlite,
+        None => {
+            eprintln!("There's no SQLite installed on the system to load the extension into");
+            return;
+        }
+    };
+    unsafe {
+        let open = sqlite.function::<Open>("sqlite3_open_v2");
+        let mut shell = ptr::null_mut();
+        let memory = CString::new(":memory:").unwrap();
+        let flags = SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE;
+        assert_eq!(
+            open(memory.as_ptr(), &mut shell, flags, ptr::null()),
+            SQLITE_OK
+        );
+        sqlite.function::<EnableLoadExtension>("sqlite3_enable_load_extension")(shell, 1);
+
+        let library = CString::new(built_library().to_str().unwrap()).unwrap();
+        let mut error = ptr::null_mut();
+        let code = sqlite.function::<LoadExtension>("sqlite3_load_extension")(
+            shell,
+            library.as_ptr(),
+            ptr::null(),
+            &mut error,
+        );
+        if code != SQLITE_OK {
+            panic!("{}", CStr::from_ptr(error).to_string_lossy());
+        }
+
+        let version = sqlite.function::<Version>("sqlite3_libversion")();
+        let version = CStr::from_ptr(version).to_string_lossy().into_owned();
+        eprintln!("Loaded the extension into SQLite {}", version);
+
+        let mut db = ptr::null_mut();
+        let name = CString::new("extension.db").unwrap();
+        let vfs = CString::new("hypercore").unwrap();
+        assert_eq!(open(name.as_ptr(), &mut db, flags, vfs.as_ptr()), SQLITE_OK);
+        sqlite.query(
+            db,
+            "CREATE TABLE notes(body TEXT); INSERT INTO notes(body) VALUES ('hello');",
+        );
+        assert_eq!(sqlite.query(db, "SELECT body FROM notes"), ["hello"]);
+        let blocks: u64 = sqlite.query(db, "PRAGMA hyper_version")[0].parse().unwrap();
+        assert!(blocks > 0);
+        assert_eq!(sqlite.query(db, "PRAGMA hyper_writable"), ["1"]);
+        assert_eq!(sqlite.query(db, "PRAGMA hyper_key")[0].len(), 64);
+    }
+}